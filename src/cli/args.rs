@@ -1,5 +1,12 @@
 use std::path::PathBuf;
 
+use crate::{
+    cli::{DEFAULT_BYTES_PER_INODE, DEFAULT_RESERVED_PERCENT},
+    device::IoBackend,
+    mfsr::DataMode,
+    types::super_block::{ErrorBehavior, DEFAULT_MAX_MOUNT_COUNT},
+};
+
 use clap::command;
 use clap::{Parser, Subcommand};
 
@@ -15,14 +22,123 @@ pub struct Args {
 pub enum Commands {
     Mkfs {
         disk_path: PathBuf,
+        /// create the image, or grow it, to this size, like 512M or 2G
+        #[arg(short, long, value_parser = parse_size)]
+        size: Option<u64>,
         #[arg(default_value = "4096", short, long)]
         block_size: u32,
+        /// blocks reserved for the metadata journal, 0 disables it
+        #[arg(short, long)]
+        journal_blocks: Option<u32>,
+        /// mounts before fsck should be run again, 0 never asks for it
+        #[arg(short, long, default_value_t = DEFAULT_MAX_MOUNT_COUNT)]
+        max_mount_count: u32,
+        /// one inode for every this many bytes of data blocks
+        #[arg(short = 'i', long, default_value_t = DEFAULT_BYTES_PER_INODE, value_parser = clap::value_parser!(u64).range(1..))]
+        bytes_per_inode: u64,
+        /// inodes in the whole filesystem, instead of deriving them from --bytes-per-inode
+        #[arg(short = 'N', long, conflicts_with = "bytes_per_inode", value_parser = clap::value_parser!(u64).range(1..))]
+        inode_count: Option<u64>,
+        #[arg(short = 'L', long, default_value = "")]
+        label: String,
+        /// random unless given
+        #[arg(short = 'U', long, value_parser = parse_uuid)]
+        uuid: Option<[u8; 16]>,
+        /// share of the blocks only root can allocate
+        #[arg(short, long, default_value_t = DEFAULT_RESERVED_PERCENT, value_parser = clap::value_parser!(u8).range(0..=50))]
+        reserved_percent: u8,
     },
     Mount {
         source: PathBuf,
         directory: PathBuf,
+        /// the default mount options of the super block when not set
+        #[arg(value_enum, short, long)]
+        data_mode: Option<DataMode>,
+        /// mount even if the image wasn't unmounted cleanly
+        #[arg(short, long)]
+        force: bool,
+        /// block group whose superblock backup to use instead of the primary copy
+        #[arg(short, long)]
+        superblock: Option<u64>,
+        /// pread works on devices too big to map
+        #[arg(value_enum, default_value = "mmap", short, long)]
+        io: IoBackend,
+        /// open the device with O_DIRECT, needs --io pread
+        #[arg(long)]
+        direct: bool,
+    },
+    /// change super block parameters of an unmounted filesystem
+    Tune {
+        disk_path: PathBuf,
+        #[arg(short = 'L', long)]
+        label: Option<String>,
+        #[arg(short = 'U', long, value_parser = parse_uuid)]
+        uuid: Option<[u8; 16]>,
+        #[arg(short, long, value_parser = clap::value_parser!(u8).range(0..=50))]
+        reserved_percent: Option<u8>,
+        /// data mode used when mount isn't given one
+        #[arg(value_enum, short, long)]
+        data_mode: Option<DataMode>,
+        #[arg(short, long)]
+        max_mount_count: Option<u32>,
+        /// what to do when metadata can't be read or written
+        #[arg(value_enum, short, long)]
+        errors: Option<ErrorBehavior>,
+    },
+    /// grow or shrink the filesystem, and an image along with it
+    Resize {
+        disk_path: PathBuf,
+        /// like 512M or 2G, rounded down to whole block groups
+        #[arg(value_parser = parse_size)]
+        size: u64,
     },
     Debug {
         disk_path: PathBuf,
     },
+    Fsck {
+        disk_path: PathBuf,
+        /// fix what can be fixed instead of only reporting it
+        #[arg(short, long)]
+        repair: bool,
+        #[arg(short, long)]
+        superblock: Option<u64>,
+    },
+}
+
+// bytes, or a number of KiB, MiB, GiB or TiB
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("Unknown size unit {}", unit)),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("Invalid size {}", s))?;
+
+    number
+        .checked_mul(1 << shift)
+        .ok_or(format!("Size {} is too big", s))
+}
+
+// 32 hex digits, dashes anywhere are ignored
+fn parse_uuid(s: &str) -> Result<[u8; 16], String> {
+    let hex: Vec<u8> = s.bytes().filter(|&c| c != b'-').collect();
+
+    if hex.len() != 32 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return Err(format!("Invalid UUID {}", s));
+    }
+
+    let mut uuid = [0; 16];
+
+    for (byte, digits) in uuid.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).unwrap();
+    }
+
+    Ok(uuid)
 }
@@ -1,34 +1,146 @@
 use std::{
-    fs::OpenOptions,
-    io::{BufWriter, Cursor, Read, Write},
-    mem::size_of,
+    fs::{File, OpenOptions},
+    io::Read,
     path::Path,
 };
 
 use anyhow::{anyhow, Result};
 use fuser::MountOption;
-use libparted::Device;
 
 use crate::{
-    mfsr::Mfsr,
-    types::{block_group::BlockGroup, super_block::SuperBlock},
-    utils::get_block_group_size,
+    device::{device_len, physical_sector_size, BlockDevice, FileDevice, IoBackend},
+    mfsr::{DataMode, Mfsr, MountOptions, Tuning},
+    types::{
+        block_group::BlockGroup,
+        journal::JournalRecord,
+        super_block::{
+            SuperBlock, DEFAULT_MAX_MOUNT_COUNT, FEATURE_COMPAT_JOURNAL, LABEL_SIZE,
+            SUPER_BLOCK_SIZE,
+        },
+    },
+    utils::{
+        format_uuid, get_block_group_size, get_data_block_size, max_block_group_count,
+        max_inodes_per_group,
+    },
 };
 
+// smallest journal mkfs picks by itself
+const DEFAULT_JOURNAL_BLOCKS: u64 = 1024;
+pub const DEFAULT_BYTES_PER_INODE: u64 = 16384;
+pub const DEFAULT_RESERVED_PERCENT: u8 = 5;
+
 pub mod args;
 
-pub fn mkfs<P>(path: P, block_size: u32) -> Result<()>
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    pub block_size: u32,
+    // blocks reserved for the metadata journal, 0 disables it and None picks a size
+    pub journal_blocks: Option<u32>,
+    pub max_mount_count: u32,
+    // data bytes per inode, ignored when inode_count is set
+    pub bytes_per_inode: u64,
+    pub inode_count: Option<u64>,
+    pub label: String,
+    // random when not set
+    pub uuid: Option<[u8; 16]>,
+    // share of the blocks only root can allocate
+    pub reserved_percent: u8,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            journal_blocks: None,
+            max_mount_count: DEFAULT_MAX_MOUNT_COUNT,
+            bytes_per_inode: DEFAULT_BYTES_PER_INODE,
+            inode_count: None,
+            label: String::new(),
+            uuid: None,
+            reserved_percent: DEFAULT_RESERVED_PERCENT,
+        }
+    }
+}
+
+pub fn mkfs<P>(path: P, size: Option<u64>, options: &FormatOptions) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let device = Device::new(&path)?;
-    let device_size = device.length() * 512; // libparted will return the size in 512 bytes sectors
+    // only a new image is created, an existing one or a block device is formatted in place
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(size.is_some())
+        .truncate(false)
+        .open(&path)?;
+    let device_size = device_len(&file)?;
+    let sector_size = physical_sector_size(&file)?;
+    let block_size = options.block_size;
+
+    if let Some(sector_size) = sector_size {
+        if sector_size > block_size as u64 {
+            return Err(anyhow!("The specified cluster size must be bigger than the device's block size , block size: {}, cluster size: {}", block_size, sector_size));
+        }
+    }
+
+    let size = match size {
+        Some(size) if size <= device_size => size,
+        // regular files grow sparse, block devices can't grow at all
+        Some(size) if sector_size.is_none() => {
+            file.set_len(size)?;
+            size
+        }
+        Some(size) => {
+            return Err(anyhow!(
+                "The device is only {} bytes, {} were asked for",
+                device_size,
+                size
+            ))
+        }
+        None => device_size,
+    };
+
+    drop(file);
+    let mut device = FileDevice::open(&path, false, false)?;
+    format(&mut device, size, options)?;
+    device.flush()?;
+
+    Ok(())
+}
+
+// Lays out a filesystem over the first `size` bytes of the device
+pub fn format(device: &mut dyn BlockDevice, size: u64, options: &FormatOptions) -> Result<()> {
+    let block_size = options.block_size;
 
-    if device.phys_sector_size() > block_size as u64 {
-        return Err(anyhow!("The specified cluster size must be bigger than the device's block size , block size: {}, cluster size: {}", block_size, device.phys_sector_size()));
+    if !block_size.is_power_of_two() || (block_size as usize) < SUPER_BLOCK_SIZE {
+        return Err(anyhow!(
+            "The block size must be a power of two of at least {} bytes",
+            SUPER_BLOCK_SIZE
+        ));
+    }
+
+    if options.label.len() > LABEL_SIZE {
+        return Err(anyhow!(
+            "The label can't be longer than {} bytes",
+            LABEL_SIZE
+        ));
+    }
+
+    if options.reserved_percent > 50 {
+        return Err(anyhow!("At most 50% of the blocks can be reserved"));
+    }
+
+    let inodes_per_group = inodes_per_group(size, options)?;
+    let block_group_count = size / get_block_group_size(block_size, inodes_per_group);
+
+    if block_group_count > max_block_group_count(block_size, inodes_per_group) {
+        return Err(anyhow!(
+            "The device is too big, at most {} block groups of {} byte blocks can be addressed",
+            max_block_group_count(block_size, inodes_per_group),
+            block_size
+        ));
     }
 
-    let block_group_count = device_size / get_block_group_size(block_size);
     let uid = unsafe { libc::geteuid() };
     let gid = unsafe { libc::getegid() };
     let data_blocks_per_group = block_size as u64 * 8;
@@ -36,11 +148,42 @@ where
         block_size,
         block_group_count,
         data_blocks_per_group,
+        inodes_per_group,
         uid,
         gid,
     );
+    sb.max_mount_count = options.max_mount_count;
+    sb.reserved_blocks = sb.block_count * options.reserved_percent as u64 / 100;
+    sb.label = options.label.clone();
+    sb.uuid = match options.uuid {
+        Some(uuid) => uuid,
+        None => random_uuid()?,
+    };
+
+    // every transaction logs the bitmaps of all groups and the super block, the journal needs
+    // room for that and some actual metadata on top
+    let min_journal_blocks = 2 * block_group_count + 64;
+    let journal_blocks = match options.journal_blocks {
+        Some(blocks) => blocks as u64,
+        None => DEFAULT_JOURNAL_BLOCKS.max(min_journal_blocks),
+    };
+
+    if journal_blocks != 0 && journal_blocks < min_journal_blocks {
+        return Err(anyhow!(
+            "The journal needs at least {} blocks for {} block groups",
+            min_journal_blocks,
+            block_group_count
+        ));
+    }
+
+    // the journal lives at the start of the first group's data blocks
+    if journal_blocks >= data_blocks_per_group {
+        return Err(anyhow!(
+            "The journal can't be bigger than {} blocks",
+            data_blocks_per_group - 1
+        ));
+    }
 
-    let file = OpenOptions::new().write(true).open(&path)?;
     let mut groups: Vec<BlockGroup> = Vec::with_capacity(block_group_count as usize);
     let empty_bitmap = vec![0; block_size as usize];
 
@@ -48,19 +191,195 @@ where
         groups.push(BlockGroup::new(empty_bitmap.clone(), empty_bitmap.clone()));
     }
 
-    let mut buf = BufWriter::new(&file);
-    BlockGroup::serialize_into(&mut buf, &groups, &mut sb)?;
-    buf.flush()?;
+    if journal_blocks != 0 {
+        for block in 0..journal_blocks as usize {
+            groups[0].data_bitmap[block / 8] |= 1 << (block % 8);
+        }
+
+        sb.journal_start = 1;
+        sb.journal_blocks = journal_blocks as u32;
+        sb.feature_compat |= FEATURE_COMPAT_JOURNAL;
+        sb.free_blocks -= journal_blocks;
+
+        let header = JournalRecord::Header { sequence: 1 };
+        let journal_address = block_size as u64 * 3 + sb.inode_table_size();
+        device.write_at(journal_address, &header.encode(block_size))?;
+    }
+
+    BlockGroup::write_all(device, &groups, &sb)?;
 
     Ok(())
 }
 
-pub fn mount<P>(source: P, mount_point: P) -> Result<()>
+// Fewer inodes per group make the groups smaller, so more of them fit. With an inode count to
+// reach the table shrinks until it's the smallest that still gets there
+fn inodes_per_group(size: u64, options: &FormatOptions) -> Result<u64> {
+    let block_size = options.block_size;
+    let max = max_inodes_per_group(block_size);
+    let mut inodes_per_group = match options.inode_count {
+        None => (get_data_block_size(block_size) / options.bytes_per_inode.max(1)).clamp(1, max),
+        Some(_) => max,
+    };
+
+    loop {
+        let groups = size / get_block_group_size(block_size, inodes_per_group);
+
+        if groups == 0 {
+            return Err(anyhow!("The device is too small for a single block group"));
+        }
+
+        let count = match options.inode_count {
+            Some(count) => count.max(1),
+            None => return Ok(inodes_per_group),
+        };
+        let needed = count.div_ceil(groups);
+
+        if needed > max {
+            return Err(anyhow!(
+                "At most {} inodes fit in {} block groups",
+                max * groups,
+                groups
+            ));
+        }
+
+        if needed == inodes_per_group {
+            return Ok(inodes_per_group);
+        }
+
+        inodes_per_group = needed;
+    }
+}
+
+fn random_uuid() -> Result<[u8; 16]> {
+    let mut uuid = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    // version 4, variant 1
+    uuid[6] = uuid[6] & 0x0f | 0x40;
+    uuid[8] = uuid[8] & 0x3f | 0x80;
+
+    Ok(uuid)
+}
+
+pub fn mount<P>(
+    source: P,
+    mount_point: P,
+    data_mode: Option<DataMode>,
+    force: bool,
+    superblock: Option<u64>,
+    io_backend: IoBackend,
+    direct_io: bool,
+) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let fs = Mfsr::new(source)?;
-    fuser::mount2(fs, mount_point, &[MountOption::AllowOther])?;
+    let options = MountOptions {
+        data_mode,
+        force,
+        superblock,
+        io_backend,
+        direct_io,
+        ..Default::default()
+    };
+    let fs = Mfsr::new(source, options)?;
+    let mut mount_options = vec![MountOption::AllowOther];
+
+    // the kernel refuses writes by itself, nothing written would reach the image anyway
+    if fs.read_only() {
+        mount_options.push(MountOption::RO);
+    }
+
+    fuser::mount2(fs, mount_point, &mount_options)?;
+
+    Ok(())
+}
+
+pub fn fsck<P>(path: P, repair: bool, superblock: Option<u64>) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    // a plain check works on a private copy of the image, even replaying the journal
+    let options = MountOptions {
+        read_only: !repair,
+        superblock,
+        ..Default::default()
+    };
+    let mut fs = Mfsr::open(path, options)?;
+    let report = fs.fsck(repair)?;
+
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+
+    if report.problems.is_empty() {
+        println!("The filesystem is clean");
+        return Ok(());
+    }
+
+    if !repair {
+        return Err(anyhow!(
+            "Found {} problems, run with --repair to fix them",
+            report.problems.len()
+        ));
+    }
+
+    if !report.remaining.is_empty() {
+        println!("Left unrepaired:");
+
+        for problem in &report.remaining {
+            println!("{}", problem);
+        }
+
+        return Err(anyhow!(
+            "{} problems can't be repaired",
+            report.remaining.len()
+        ));
+    }
+
+    println!("Repaired {} problems", report.problems.len());
+
+    Ok(())
+}
+
+pub fn tune<P>(path: P, tuning: &Tuning) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut fs = Mfsr::open(path, MountOptions::default())?;
+    fs.tune(tuning)
+}
+
+pub fn resize<P>(path: P, size: u64) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let device_size = device_len(&file)?;
+    let is_image = physical_sector_size(&file)?.is_none();
+
+    // images grow sparse before the filesystem does, block devices must already be big enough
+    if size > device_size {
+        if !is_image {
+            return Err(anyhow!(
+                "The device is only {} bytes, {} were asked for",
+                device_size,
+                size
+            ));
+        }
+
+        file.set_len(size)?;
+    }
+
+    drop(file);
+    let mut fs = Mfsr::open(&path, MountOptions::default())?;
+    let block_group_count = fs.resize(size)?;
+    drop(fs);
+
+    // and shrink after it, once nothing is left past the new end
+    if is_image && size < device_size {
+        OpenOptions::new().write(true).open(&path)?.set_len(size)?;
+    }
+
+    println!("The filesystem now has {} block groups", block_group_count);
 
     Ok(())
 }
@@ -69,11 +388,14 @@ pub fn debug_disk<P>(path: P) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let mut disk = OpenOptions::new().read(true).open(path)?;
-    let mut buf = [0; size_of::<SuperBlock>()];
-    disk.read_exact(&mut buf)?;
-    let cursor = Cursor::new(buf);
-    let sb = SuperBlock::deserialize_from(cursor)?; // the first group superblock
+    let disk = FileDevice::open(path, true, false)?;
+    let (sb, group) = SuperBlock::read_with_fallback(&disk)?;
+
+    if group != 0 {
+        println!("The primary superblock is damaged, showing the backup from block group {group}");
+    }
+
+    println!("UUID: {}", format_uuid(&sb.uuid));
     dbg!(sb);
 
     Ok(())
@@ -0,0 +1,522 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, FileTypeExt, OpenOptionsExt},
+    },
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use memmap2::{MmapMut, MmapOptions};
+
+// O_DIRECT transfers have to start and end on this boundary, and so do their buffers
+const DIRECT_IO_ALIGNMENT: u64 = 4096;
+// granularity of the writes an overlay keeps in memory
+const OVERLAY_PAGE_SIZE: u64 = 4096;
+// _IOR(0x12, 114, u64) on the usual architectures, libc doesn't export it
+const BLKGETSIZE64: libc::Ioctl = 0x80081272;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum IoBackend {
+    // the whole device is mapped in memory and written back by the kernel
+    #[default]
+    Mmap,
+    // positional reads and writes, nothing is mapped so the device size doesn't matter
+    Pread,
+}
+
+// Storage the filesystem lives on, addressed in bytes. Nothing written is durable until it's
+// flushed
+pub trait BlockDevice: Debug {
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    fn flush_range(&mut self, offset: u64, len: u64) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+}
+
+pub fn open_device<P>(
+    path: P,
+    backend: IoBackend,
+    read_only: bool,
+    direct: bool,
+) -> Result<Box<dyn BlockDevice>>
+where
+    P: AsRef<Path>,
+{
+    match backend {
+        IoBackend::Mmap if direct => Err(anyhow!("O_DIRECT needs the pread backend")),
+        IoBackend::Mmap => Ok(Box::new(MmapDevice::open(path, read_only)?)),
+        IoBackend::Pread => Ok(Box::new(FileDevice::open(path, read_only, direct)?)),
+    }
+}
+
+fn check_bounds(device: &dyn BlockDevice, offset: u64, len: usize) -> Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= device.len() => Ok(()),
+        _ => Err(anyhow!("Access past the end of the device at {}", offset)),
+    }
+}
+
+#[derive(Debug)]
+pub struct MmapDevice {
+    map: MmapMut,
+}
+
+impl MmapDevice {
+    // a read-only device is mapped privately, what's written to it never reaches the file
+    pub fn open<P>(path: P, read_only: bool) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let len = usize::try_from(device_len(&file)?)?;
+        let mut map_options = MmapOptions::new();
+        map_options.len(len);
+        let map = if read_only {
+            unsafe { map_options.map_copy(&file)? }
+        } else {
+            unsafe { map_options.map_mut(&file)? }
+        };
+
+        Ok(Self { map })
+    }
+}
+
+impl BlockDevice for MmapDevice {
+    fn len(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        check_bounds(self, offset, buf.len())?;
+        buf.copy_from_slice(&self.map[offset as usize..offset as usize + buf.len()]);
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        check_bounds(self, offset, data.len())?;
+        self.map[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn flush_range(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.map.flush_range(offset as usize, len as usize)?;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.map.flush()?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct FileDevice {
+    file: File,
+    len: u64,
+    direct: bool,
+    // the same file without O_DIRECT, for the end of a device that stops short of a whole page
+    buffered: File,
+}
+
+impl FileDevice {
+    // A read-only device refuses writes. With O_DIRECT the page cache is skipped, so the order
+    // writes are issued in is the order they reach the device
+    pub fn open<P>(path: P, read_only: bool, direct: bool) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut options = OpenOptions::new();
+        options.read(true).write(!read_only);
+        let buffered = options.open(&path)?;
+
+        if direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+
+        let file = options.open(path)?;
+
+        Ok(Self {
+            len: device_len(&file)?,
+            file,
+            direct,
+            buffered,
+        })
+    }
+
+    fn read_file(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if !self.direct {
+            return Ok(self.file.read_exact_at(buf, offset)?);
+        }
+
+        let start = offset - offset % DIRECT_IO_ALIGNMENT;
+        let end = (offset + buf.len() as u64).next_multiple_of(DIRECT_IO_ALIGNMENT);
+        let mut aligned = AlignedBuffer::new((end - start) as usize);
+        let mut read = 0;
+
+        // the aligned span may go past the end of an image file, that part reads as zeroes
+        while read < aligned.len() {
+            match self
+                .file
+                .read_at(&mut aligned.as_mut()[read..], start + read as u64)?
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&aligned.as_mut()[skip..skip + buf.len()]);
+
+        Ok(())
+    }
+
+    fn write_file(&self, offset: u64, mut data: &[u8]) -> Result<()> {
+        if !self.direct {
+            return Ok(self.file.write_all_at(data, offset)?);
+        }
+
+        // rounding the last page up would grow the device past its end, so whatever lies after
+        // the last whole page goes through the page cache instead
+        let whole_pages = self.len - self.len % DIRECT_IO_ALIGNMENT;
+
+        if offset + data.len() as u64 > whole_pages {
+            let split = whole_pages.max(offset);
+            let (head, tail) = data.split_at((split - offset) as usize);
+            self.buffered.write_all_at(tail, split)?;
+            data = head;
+        }
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let start = offset - offset % DIRECT_IO_ALIGNMENT;
+        let end = (offset + data.len() as u64).next_multiple_of(DIRECT_IO_ALIGNMENT);
+        let mut aligned = AlignedBuffer::new((end - start) as usize);
+
+        // partial pages keep the rest of their contents
+        if start != offset || end != offset + data.len() as u64 {
+            let len = aligned.len();
+            self.read_file(start, &mut aligned.as_mut()[..len])?;
+        }
+
+        let skip = (offset - start) as usize;
+        aligned.as_mut()[skip..skip + data.len()].copy_from_slice(data);
+        self.file.write_all_at(aligned.as_mut(), start)?;
+
+        Ok(())
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        check_bounds(self, offset, buf.len())?;
+        self.read_file(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        check_bounds(self, offset, data.len())?;
+        self.write_file(offset, data)
+    }
+
+    // fdatasync can't be limited to a range, the whole device is flushed
+    fn flush_range(&mut self, _offset: u64, _len: u64) -> Result<()> {
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+// The whole device in memory, to build or inspect an image without touching any file
+#[derive(Debug, Clone)]
+pub struct MemoryDevice {
+    buf: Vec<u8>,
+}
+
+impl MemoryDevice {
+    pub fn new(len: u64) -> Result<Self> {
+        Ok(Self {
+            buf: vec![0; usize::try_from(len)?],
+        })
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl From<Vec<u8>> for MemoryDevice {
+    fn from(buf: Vec<u8>) -> Self {
+        Self { buf }
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn len(&self) -> u64 {
+        self.buf.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        check_bounds(self, offset, buf.len())?;
+        buf.copy_from_slice(&self.buf[offset as usize..offset as usize + buf.len()]);
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        check_bounds(self, offset, data.len())?;
+        self.buf[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn flush_range(&mut self, _offset: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps everything written in memory, by page, and leaves the device underneath untouched.
+// Reads see the writes, so a read-only filesystem can still replay its journal
+#[derive(Debug)]
+pub struct OverlayDevice {
+    device: Box<dyn BlockDevice>,
+    pages: BTreeMap<u64, Vec<u8>>,
+}
+
+impl OverlayDevice {
+    pub fn new(device: Box<dyn BlockDevice>) -> Self {
+        Self {
+            device,
+            pages: BTreeMap::new(),
+        }
+    }
+}
+
+impl BlockDevice for OverlayDevice {
+    fn len(&self) -> u64 {
+        self.device.len()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.device.read_at(offset, buf)?;
+        let end = offset + buf.len() as u64;
+        let pages = offset / OVERLAY_PAGE_SIZE..end.div_ceil(OVERLAY_PAGE_SIZE);
+
+        for (&page, contents) in self.pages.range(pages) {
+            let page_start = page * OVERLAY_PAGE_SIZE;
+            let from = page_start.max(offset);
+            let to = (page_start + OVERLAY_PAGE_SIZE).min(end);
+            buf[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                &contents[(from - page_start) as usize..(to - page_start) as usize],
+            );
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        check_bounds(self, offset, data.len())?;
+        let end = offset + data.len() as u64;
+
+        for page in offset / OVERLAY_PAGE_SIZE..end.div_ceil(OVERLAY_PAGE_SIZE) {
+            let page_start = page * OVERLAY_PAGE_SIZE;
+
+            if !self.pages.contains_key(&page) {
+                let page_len = OVERLAY_PAGE_SIZE.min(self.len() - page_start) as usize;
+                let mut contents = vec![0; OVERLAY_PAGE_SIZE as usize];
+                self.device.read_at(page_start, &mut contents[..page_len])?;
+                self.pages.insert(page, contents);
+            }
+
+            let from = page_start.max(offset);
+            let to = (page_start + OVERLAY_PAGE_SIZE).min(end);
+            let contents = self.pages.get_mut(&page).unwrap();
+            contents[(from - page_start) as usize..(to - page_start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+        }
+
+        Ok(())
+    }
+
+    fn flush_range(&mut self, _offset: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Heap buffer starting on an O_DIRECT boundary
+struct AlignedBuffer {
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let buf = vec![0; len + DIRECT_IO_ALIGNMENT as usize];
+        let start = buf.as_ptr().align_offset(DIRECT_IO_ALIGNMENT as usize);
+
+        Self { buf, start, len }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + self.len]
+    }
+}
+
+// Regular files know their size from their metadata, block devices have to be asked
+pub fn device_len(file: &File) -> Result<u64> {
+    let metadata = file.metadata()?;
+
+    if !metadata.file_type().is_block_device() {
+        return Ok(metadata.len());
+    }
+
+    let mut len: u64 = 0;
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut len) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(len)
+}
+
+// Smallest write a block device does without reading the rest of its sector first, nothing for
+// regular files
+pub fn physical_sector_size(file: &File) -> Result<Option<u64>> {
+    if !file.metadata()?.file_type().is_block_device() {
+        return Ok(None);
+    }
+
+    let mut size: libc::c_uint = 0;
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKPBSZGET, &mut size) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(Some(size as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mfsr::{tests::memory_device, Mfsr, MountOptions};
+
+    #[test]
+    fn memory_device_reads_back_writes() {
+        let mut device = MemoryDevice::new(8192).unwrap();
+        device.write_at(4000, &[7; 200]).unwrap();
+
+        let mut buf = [0; 300];
+        device.read_at(3950, &mut buf).unwrap();
+        assert_eq!(buf[..50], [0; 50]);
+        assert_eq!(buf[50..250], [7; 200]);
+        assert_eq!(buf[250..], [0; 50]);
+
+        assert!(device.write_at(8000, &[0; 200]).is_err());
+        assert!(device.read_at(u64::MAX, &mut buf).is_err());
+    }
+
+    #[test]
+    fn overlay_leaves_the_device_alone() {
+        let mut overlay = OverlayDevice::new(Box::new(MemoryDevice::from(vec![1; 10000])));
+        // across a page boundary and into the short last page
+        overlay.write_at(4090, &[2; 20]).unwrap();
+        overlay.write_at(9990, &[3; 10]).unwrap();
+
+        let mut buf = vec![0; 10000];
+        overlay.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf[4089], 1);
+        assert_eq!(buf[4090..4110], [2; 20]);
+        assert_eq!(buf[4110], 1);
+        assert_eq!(buf[9989], 1);
+        assert_eq!(buf[9990..], [3; 10]);
+
+        overlay.device.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, vec![1; 10000]);
+    }
+
+    #[test]
+    fn direct_writes_stay_inside_the_image() {
+        let path = std::env::temp_dir().join(format!("mfsr-direct-{}", std::process::id()));
+        std::fs::write(&path, vec![1; 10000]).unwrap();
+
+        let mut device = FileDevice::open(&path, false, true).unwrap();
+        // across a page boundary, across the last whole page and within the short last page
+        device.write_at(4090, &[2; 20]).unwrap();
+        device.write_at(8000, &[3; 300]).unwrap();
+        device.write_at(9990, &[4; 10]).unwrap();
+        device.flush().unwrap();
+
+        let mut buf = vec![0; 10000];
+        device.read_at(0, &mut buf).unwrap();
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(image.len(), 10000);
+        assert_eq!(image, buf);
+        assert_eq!(buf[4089], 1);
+        assert_eq!(buf[4090..4110], [2; 20]);
+        assert_eq!(buf[4110], 1);
+        assert_eq!(buf[8000..8300], [3; 300]);
+        assert_eq!(buf[8300], 1);
+        assert_eq!(buf[9989], 1);
+        assert_eq!(buf[9990..], [4; 10]);
+    }
+
+    #[test]
+    fn formatted_memory_device_mounts() {
+        let device = memory_device(2, 2);
+        let mut fs = Mfsr::from_device(Box::new(device), MountOptions::default()).unwrap();
+        fs.close().unwrap();
+
+        let device = fs.into_device();
+        let mut image = vec![0; device.len() as usize];
+        device.read_at(0, &mut image).unwrap();
+        let options = MountOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut fs = Mfsr::from_device(Box::new(MemoryDevice::from(image)), options).unwrap();
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+}
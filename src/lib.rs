@@ -0,0 +1,5 @@
+pub mod cli;
+pub mod device;
+pub mod mfsr;
+pub mod types;
+pub mod utils;
@@ -1,14 +1,10 @@
-mod cli;
-mod mfsr;
-mod types;
-mod utils;
-
 use anyhow::Result;
 use clap::Parser;
-use cli::{
+use mfsr::cli::{
     args::{Args, Commands},
-    debug_disk, mkfs, mount,
+    debug_disk, fsck, mkfs, mount, resize, tune, FormatOptions,
 };
+use mfsr::mfsr::Tuning;
 
 fn main() -> Result<()> {
     let args = Args::parse();
@@ -16,9 +12,62 @@ fn main() -> Result<()> {
     match args.command {
         Commands::Mkfs {
             disk_path,
+            size,
             block_size,
-        } => mkfs(disk_path, block_size),
+            journal_blocks,
+            max_mount_count,
+            bytes_per_inode,
+            inode_count,
+            label,
+            uuid,
+            reserved_percent,
+        } => {
+            let options = FormatOptions {
+                block_size,
+                journal_blocks,
+                max_mount_count,
+                bytes_per_inode,
+                inode_count,
+                label,
+                uuid,
+                reserved_percent,
+            };
+            mkfs(disk_path, size, &options)
+        }
+        Commands::Tune {
+            disk_path,
+            label,
+            uuid,
+            reserved_percent,
+            data_mode,
+            max_mount_count,
+            errors,
+        } => {
+            let tuning = Tuning {
+                label,
+                uuid,
+                reserved_percent,
+                data_mode,
+                max_mount_count,
+                errors,
+            };
+            tune(disk_path, &tuning)
+        }
+        Commands::Resize { disk_path, size } => resize(disk_path, size),
         Commands::Debug { disk_path } => debug_disk(disk_path),
-        Commands::Mount { source, directory } => mount(source, directory),
+        Commands::Fsck {
+            disk_path,
+            repair,
+            superblock,
+        } => fsck(disk_path, repair, superblock),
+        Commands::Mount {
+            source,
+            directory,
+            data_mode,
+            force,
+            superblock,
+            io,
+            direct,
+        } => mount(source, directory, data_mode, force, superblock, io, direct),
     }
 }
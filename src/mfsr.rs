@@ -1,92 +1,400 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsStr,
-    fs::{File, OpenOptions},
-    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
-    mem::{size_of, size_of_val},
+    fs::File,
+    io::{BufRead, BufReader, Cursor},
+    mem::size_of,
+    os::unix::ffi::OsStrExt,
     path::Path,
     time::{Duration, SystemTime},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
 use fuser::{
-    FileType, Filesystem, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request, TimeOrNow,
-    FUSE_ROOT_ID,
+    FileType, Filesystem, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLseek, ReplyOpen,
+    ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
 };
 use libc::{
-    c_int, EACCES, EEXIST, EFBIG, EINVAL, EIO, ENAMETOOLONG, ENOENT, ENOTEMPTY, EPERM, F_OK,
-    O_ACCMODE, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, RENAME_EXCHANGE, R_OK, S_ISGID, S_ISUID,
-    S_ISVTX, S_IXGRP, S_IXOTH, S_IXUSR, W_OK, X_OK,
+    c_int, E2BIG, EACCES, EEXIST, EFBIG, EINVAL, EIO, EISDIR, EMLINK, ENAMETOOLONG, ENODATA,
+    ENODEV, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENXIO, EOPNOTSUPP, EPERM, ERANGE, EROFS,
+    FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE,
+    F_OK, O_ACCMODE, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, RENAME_EXCHANGE, R_OK, SEEK_DATA,
+    SEEK_HOLE, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFMT, S_IFREG, S_IFSOCK, S_ISGID, S_ISUID,
+    S_ISVTX, S_IXGRP, S_IXOTH, S_IXUSR, W_OK, XATTR_CREATE, XATTR_REPLACE, X_OK,
 };
-use memmap2::{MmapMut, MmapOptions};
 
 use crate::{
+    device::{open_device, BlockDevice, IoBackend, OverlayDevice},
     types::{
-        block_group::BlockGroup, directory_entry::DirectoryEntry, inode::Inode,
-        super_block::SuperBlock,
+        acl::{Acl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR},
+        block_group::{BlockGroup, CACHED_GROUPS},
+        directory_entry::DirectoryEntry,
+        encoding::{Decoder, Encoder},
+        extended_attributes::{
+            ExtendedAttributes, XattrValue, MAX_INLINE_VALUE_SIZE, MAX_XATTR_NAME_LENGTH,
+            SECURITY_PREFIX, TRUSTED_PREFIX, USER_PREFIX,
+        },
+        extent::{Extent, ExtentMap, INLINE_EXTENTS},
+        inode::{Inode, DIRECT_POINTERS, EXTENTS_FLAG, INLINE_DATA_SIZE, INODE_SIZE},
+        journal::JournalRecord,
+        super_block::{
+            ErrorBehavior, FsState, SuperBlock, DEFAULT_MOUNT_WRITEBACK,
+            FEATURE_RO_COMPAT_SHARED_BLOCKS,
+        },
     },
     utils::{
-        bytes_to_pointer, bytes_to_u64, current_timestamp, get_block_group_size,
-        get_inode_table_size, pointer_to_bytes, system_time_to_timestamp, time_or_now_to_timestamp,
+        bytes_to_u64, current_timestamp, max_block_group_count, system_time_to_timestamp,
+        time_or_now_to_timestamp, u64_to_bytes,
     },
 };
 
+mod fsck;
+mod resize;
+mod tune;
+
+pub use tune::Tuning;
+
 const FILE_ATTR_TTL: Duration = Duration::new(0, 0);
 const MAX_NAME_LENGTH: usize = 255;
 const FMODE_EXEC: i32 = 0x20;
 const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
 const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
-// with doubly indirect pointers we can have file sizes up to 4 GiB
-const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+const INDIRECTION_LEVELS: u32 = 3;
+const COPY_CHUNK_SIZE: u64 = 1 << 20;
+// the most an operation logs besides the file blocks it maps: two full directories, a few inodes
+// and their xattr blocks
+const CHANGE_JOURNAL_BLOCKS: usize = 2 * DIRECT_POINTERS + 8;
+// longest a transaction stays open when operations don't commit it themselves
+const COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DataMode {
+    // file data reaches the disk before the metadata pointing at it is committed
+    #[default]
+    Ordered,
+    // file data and metadata are written back in no particular order
+    Writeback,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    // the super block's default when not set
+    pub data_mode: Option<DataMode>,
+    // nothing written to the image ever reaches the device
+    pub read_only: bool,
+    // mount images that weren't unmounted cleanly even when the journal can't vouch for them
+    pub force: bool,
+    // block group whose backup of the super block is used instead of the primary copy
+    pub superblock: Option<u64>,
+    pub io_backend: IoBackend,
+    // bypass the page cache, only with the pread backend
+    pub direct_io: bool,
+}
 
 #[derive(Debug)]
 pub struct Mfsr {
     super_block: SuperBlock,
-    io_map: MmapMut,
-    block_groups: Vec<BlockGroup>,
+    device: Box<dyn BlockDevice>,
+    // bitmaps of the groups loaded so far, by group, see group()
+    block_groups: BTreeMap<usize, BlockGroup>,
+    group_clock: u64,
+    // no group before these has a free data block or inode left
+    first_free_data_group: usize,
+    first_free_inode_group: usize,
     next_fh: u64,
+    // extra references held on data blocks shared between files
+    shared_blocks: BTreeMap<u64, u32>,
+    shared_blocks_dirty: bool,
+    // byte ranges of the device written since the last sync, start to end
+    dirty_ranges: BTreeMap<u64, u64>,
+    options: MountOptions,
+    // metadata blocks changed by the open transaction, by block number on the device. They only
+    // reach their place on disk once the transaction is committed to the journal
+    transaction: BTreeMap<u64, Vec<u8>>,
+    journal_sequence: u64,
+    committing: bool,
+    last_commit: SystemTime,
+    // group of the super block backup in use, the primary copy is rewritten from it on the
+    // next commit
+    backup_group: Option<u64>,
+    // caller of the request being served, only root allocates reserved blocks
+    request_uid: u32,
+    data_mode: DataMode,
 }
 
 impl Mfsr {
-    pub fn new<P>(source: P) -> Result<Self>
+    pub fn new<P>(source: P, options: MountOptions) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let device = open_device(
+            source,
+            options.io_backend,
+            options.read_only,
+            options.direct_io,
+        )?;
+
+        Self::from_device(device, options)
+    }
+
+    // Same as new for an image on any device, the I/O options are ignored
+    pub fn from_device(device: Box<dyn BlockDevice>, options: MountOptions) -> Result<Self> {
+        let mut fs = Self::open_from_device(device, options)?;
+        fs.check_state()?;
+        fs.create_root()?;
+        fs.load_shared_blocks()?;
+        fs.commit()?;
+
+        Ok(fs)
+    }
+
+    // Opens the image and brings it up to date with the journal, without touching anything else
+    pub fn open<P>(source: P, options: MountOptions) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .truncate(false)
-            .open(source)?;
-        let mut buf = [0; size_of::<SuperBlock>()];
-        file.read_exact(&mut buf)?;
-        let cursor = Cursor::new(&buf);
-        let super_block = SuperBlock::deserialize_from(cursor)?;
-        let size = get_block_group_size(super_block.block_size) * super_block.block_group_count;
-        file.rewind()?;
-        let io_map = unsafe { MmapOptions::new().len(size as usize).map_mut(&file)? };
-        let mut cursor = Cursor::new(&io_map);
-        let block_groups = BlockGroup::deserialize_from(
-            &mut cursor,
-            super_block.block_size,
-            super_block.block_group_count as usize,
+        let device = open_device(
+            source,
+            options.io_backend,
+            options.read_only,
+            options.direct_io,
         )?;
 
+        Self::open_from_device(device, options)
+    }
+
+    pub fn open_from_device(
+        mut device: Box<dyn BlockDevice>,
+        mut options: MountOptions,
+    ) -> Result<Self> {
+        let (super_block, group) = match options.superblock {
+            Some(group) => (SuperBlock::read_from_group(device.as_ref(), group)?, group),
+            None => SuperBlock::read_with_fallback(device.as_ref())?,
+        };
+
+        // backups are only refreshed on unmount, the counters in them may be behind
+        if group != 0 && options.superblock.is_none() {
+            eprintln!(
+                "Warning: the primary super block is damaged, using the backup in group {}, run `mfsr fsck`",
+                group
+            );
+        }
+
+        if super_block.unknown_features_incompat() != 0 {
+            return Err(anyhow!(
+                "The filesystem uses unsupported incompatible features {:#x}",
+                super_block.unknown_features_incompat()
+            ));
+        }
+
+        if super_block.unknown_features_ro_compat() != 0 && !options.read_only {
+            eprintln!(
+                "Warning: the filesystem uses unsupported read-only compatible features {:#x}, mounting read-only",
+                super_block.unknown_features_ro_compat()
+            );
+            options.read_only = true;
+        }
+
+        if options.read_only {
+            device = Box::new(OverlayDevice::new(device));
+        }
+
+        if super_block.block_group_count
+            > max_block_group_count(super_block.block_size, super_block.inodes_per_group)
+        {
+            return Err(anyhow!(
+                "The filesystem has more block groups than can be addressed: {}",
+                super_block.block_group_count
+            ));
+        }
+
+        let size = super_block.group_size() * super_block.block_group_count;
+
+        if device.len() < size {
+            return Err(anyhow!(
+                "The device is smaller than the filesystem, {} bytes for {}",
+                device.len(),
+                size
+            ));
+        }
+
         let mut fs = Self {
             super_block,
-            block_groups,
-            io_map,
+            block_groups: BTreeMap::new(),
+            group_clock: 0,
+            first_free_data_group: 0,
+            first_free_inode_group: 0,
+            device,
             next_fh: 1,
+            shared_blocks: BTreeMap::new(),
+            shared_blocks_dirty: false,
+            dirty_ranges: BTreeMap::new(),
+            options,
+            transaction: BTreeMap::new(),
+            journal_sequence: 0,
+            committing: false,
+            last_commit: SystemTime::now(),
+            backup_group: (group != 0).then_some(group),
+            request_uid: 0,
+            data_mode: DataMode::Ordered,
         };
 
-        fs.create_root()?;
+        // finish whatever was committed before a crash, it may touch the super block and bitmaps
+        fs.replay_journal()?;
+
+        // the journal logs the primary copy, so it may have brought a damaged one back
+        match fs.backup_group {
+            None => fs.super_block = SuperBlock::read_from_group(fs.device.as_ref(), 0)?,
+            Some(_) if fs.options.superblock.is_some() => {}
+            Some(_) => {
+                if let Ok(super_block) = SuperBlock::read_from_group(fs.device.as_ref(), 0) {
+                    fs.super_block = super_block;
+                    fs.backup_group = None;
+                }
+            }
+        }
+
+        if let Some(group) = fs.backup_group {
+            eprintln!(
+                "Warning: using the superblock backup from block group {}, the primary copy is rewritten from it on the next write",
+                group
+            );
+        }
+        fs.data_mode = match fs.options.data_mode {
+            Some(data_mode) => data_mode,
+            None if fs.super_block.default_mount_options & DEFAULT_MOUNT_WRITEBACK != 0 => {
+                DataMode::Writeback
+            }
+            None => DataMode::Ordered,
+        };
+
+        if fs.super_block.journal_blocks != 0
+            && fs.transaction_capacity() < fs.commit_reserve() + CHANGE_JOURNAL_BLOCKS
+        {
+            return Err(anyhow!(
+                "Journal too small for {} block groups",
+                fs.super_block.block_group_count
+            ));
+        }
 
         Ok(fs)
     }
 
+    // Refuses an image that wasn't unmounted cleanly unless the journal brought it back in shape
+    // or the mount is forced, and warns about anything fsck should look at
+    fn check_state(&self) -> Result<()> {
+        let super_block = &self.super_block;
+
+        if super_block.state == FsState::Dirty {
+            if super_block.journal_blocks == 0 && !self.options.force {
+                return Err(anyhow!(
+                    "The filesystem was not cleanly unmounted, run `mfsr fsck --repair` or mount with --force"
+                ));
+            }
+
+            eprintln!("Warning: the filesystem was not cleanly unmounted");
+        }
+
+        if super_block.error_count != 0 {
+            eprintln!(
+                "Warning: {} filesystem errors since the last check, run `mfsr fsck`",
+                super_block.error_count
+            );
+        }
+
+        if super_block.needs_check() {
+            eprintln!(
+                "Warning: mounted {} times without a check, run `mfsr fsck`",
+                super_block.mount_count
+            );
+        }
+
+        Ok(())
+    }
+
+    // Changes made without mounting would be overwritten by a mounted filesystem's own copy of
+    // the super block and bitmaps
+    fn check_unmounted(&self) -> Result<()> {
+        if self.read_only() {
+            return Err(anyhow!("The filesystem is read only"));
+        }
+
+        if self.super_block.state == FsState::Dirty {
+            return Err(anyhow!(
+                "The filesystem is mounted or was not cleanly unmounted, run `mfsr fsck --repair` first"
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Counts a failed metadata read or write against the filesystem until the next fsck, then
+    // does what the super block asks for on errors
+    fn track_error<T>(&mut self, result: Result<T>) -> Result<T> {
+        let error = match &result {
+            Ok(_) => return result,
+            Err(e) => e,
+        };
+
+        self.super_block.record_error();
+
+        match self.super_block.errors {
+            ErrorBehavior::Continue => {}
+            ErrorBehavior::RemountRo => {
+                if !self.options.read_only {
+                    eprintln!("Error: {}, no more changes are accepted", error);
+                }
+
+                self.options.read_only = true;
+            }
+            ErrorBehavior::Panic => panic!("Filesystem error: {}", error),
+        }
+
+        result
+    }
+
+    // Every request that changes the filesystem starts here
+    fn begin_change(&mut self, req: &Request<'_>) -> Result<(), c_int> {
+        if self.options.read_only {
+            return Err(EROFS);
+        }
+
+        self.request_uid = req.uid();
+
+        self.reserve_journal(CHANGE_JOURNAL_BLOCKS)
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    // Gives the device back once the filesystem is closed
+    pub fn into_device(self) -> Box<dyn BlockDevice> {
+        self.device
+    }
+
+    // Writes back everything still in memory, including the super block and bitmap backups
+    pub fn close(&mut self) -> Result<()> {
+        self.store_shared_blocks()?;
+        self.commit()?;
+
+        // the commit put the bitmaps in place, only the super block backups are left
+        let super_block = self.super_block.encode();
+
+        for group_id in 0..self.super_block.block_group_count {
+            self.device
+                .write_at(self.super_block.group_size() * group_id, &super_block)?;
+        }
+
+        self.device.flush()?;
+
+        Ok(())
+    }
+
     pub fn check_access(
-        &self,
-        inode_uid: u32,
-        inode_gid: u32,
-        file_mode: u16,
+        &mut self,
+        inode: &Inode,
         uid: u32,
         gid: u32,
         mut access_mask: i32,
@@ -95,7 +403,7 @@ impl Mfsr {
         if access_mask == F_OK {
             return true;
         }
-        let file_mode = i32::from(file_mode);
+        let file_mode = i32::from(inode.mode as u16);
 
         // root is allowed to read & write anything
         if uid == 0 {
@@ -107,9 +415,14 @@ impl Mfsr {
             return access_mask == 0;
         }
 
-        if uid == inode_uid {
+        // an access ACL replaces the owner/group/other classes
+        if let Some(acl) = self.get_acl(inode, ACL_ACCESS_XATTR) {
+            return acl.permits(inode.uid, inode.gid, uid, gid, access_mask);
+        }
+
+        if uid == inode.uid {
             access_mask -= access_mask & (file_mode >> 6);
-        } else if gid == inode_gid {
+        } else if gid == inode.gid {
             access_mask -= access_mask & (file_mode >> 3);
         } else {
             access_mask -= access_mask & file_mode;
@@ -144,15 +457,67 @@ impl Mfsr {
             let mut inode = Inode::new(FUSE_ROOT_ID, FileType::Directory, 0o777, 0, 0, 0);
             inode.hard_links = 2;
             let mut dentry = DirectoryEntry::new(FUSE_ROOT_ID);
-            self.write_dentry(&mut inode, &mut dentry)?;
+            self.write_dentry(&mut inode, &mut dentry)
+                .map_err(|code| anyhow!("Failed to write the root directory: {}", code))?;
             self.write_inode(&mut inode)?;
             Ok(())
         }
     }
 
-    fn inode_exists(&self, inode_id: u64) -> bool {
+    // The bitmaps of a group, read when they're first needed. Past CACHED_GROUPS loaded groups
+    // the least recently used clean one is dropped, dirty ones stay until they're logged
+    fn group(&mut self, group_id: usize) -> &mut BlockGroup {
+        self.group_clock += 1;
+
+        if !self.block_groups.contains_key(&group_id) {
+            let group = self.load_group(group_id);
+
+            if self.block_groups.len() >= CACHED_GROUPS {
+                let oldest = self
+                    .block_groups
+                    .iter()
+                    .filter(|(_, group)| !group.dirty)
+                    .min_by_key(|(_, group)| group.last_used)
+                    .map(|(&id, _)| id);
+
+                if let Some(id) = oldest {
+                    self.block_groups.remove(&id);
+                }
+            }
+
+            self.block_groups.insert(group_id, group);
+        }
+
+        let group = self.block_groups.get_mut(&group_id).unwrap();
+        group.last_used = self.group_clock;
+
+        group
+    }
+
+    // Goes through the transaction, the bitmaps logged by the last commit may not be in place yet
+    fn load_group(&mut self, group_id: usize) -> BlockGroup {
+        let block_size = self.super_block.block_size as usize;
+        let offset = self.super_block.group_size() * group_id as u64 + block_size as u64;
+        let mut data_bitmap = vec![0; block_size * 2];
+        let read = self.read_bytes(offset, &mut data_bitmap);
+
+        // a group that can't be read looks full, so nothing new lands in it
+        if self.track_error(read).is_err() {
+            data_bitmap.fill(0xff);
+        }
+
+        let inode_bitmap = data_bitmap.split_off(block_size);
+
+        BlockGroup::new(data_bitmap, inode_bitmap)
+    }
+
+    fn inode_exists(&mut self, inode_id: u64) -> bool {
+        if !self.valid_inode_id(inode_id) {
+            return false;
+        }
+
         let (group_id, bitmap_byte_index, bitmap_bit_index) = self.inode_bitmap_offset(inode_id);
-        let group = &self.block_groups[group_id];
+        let group = self.group(group_id);
 
         if bitmap_byte_index >= group.inode_bitmap.len() {
             return false;
@@ -170,80 +535,106 @@ impl Mfsr {
         }
 
         let offset = self.inode_table_offset(inode_id);
-        let mmap = self.io_map.as_mut();
-        let mut cursor = Cursor::new(mmap);
-        cursor.seek(std::io::SeekFrom::Start(offset)).unwrap();
+        let mut buf = vec![0; INODE_SIZE];
+        let inode = self
+            .read_bytes(offset, &mut buf)
+            .and_then(|_| Inode::decode(&buf));
 
-        Some(Inode::deserialize_from(&mut cursor).unwrap())
+        self.track_error(inode).ok()
     }
 
     fn write_inode(&mut self, inode: &mut Inode) -> anyhow::Result<()> {
         let (group_id, bitmap_byte_index, bitmap_bit_index) = self.inode_bitmap_offset(inode.id);
-        let group = &mut self.block_groups[group_id];
+        let group = self.group(group_id);
         let creation = group.inode_bitmap[bitmap_byte_index] & 1 << bitmap_bit_index == 0;
         group.inode_bitmap[bitmap_byte_index] |= 1 << bitmap_bit_index;
+        group.dirty |= creation;
 
-        let offset = self.inode_table_offset(inode.id);
-        let mmap = self.io_map.as_mut();
-        let mut cursor = Cursor::new(mmap);
-        cursor.seek(std::io::SeekFrom::Start(offset))?;
-        inode.serialize_into(&mut cursor)?;
-
+        // counted along with the bit, even if the write below fails
         if creation {
             self.super_block.free_inodes -= 1;
         }
 
+        let offset = self.inode_table_offset(inode.id);
+        self.write_bytes(offset, &inode.encode())?;
+
         Ok(())
     }
 
-    fn delete_inode(&mut self, inode_id: u64) {
-        let inode = self.get_inode(inode_id).unwrap();
-        let (group_id, bitmap_byte_index, bitmap_bit_index) = self.inode_bitmap_offset(inode_id);
-        let group = &mut self.block_groups[group_id];
-        group.inode_bitmap[bitmap_byte_index] &= !(1 << bitmap_bit_index);
-        self.super_block.free_inodes += 1;
+    fn delete_inode(&mut self, inode_id: u64) -> Result<(), c_int> {
+        let mut inode = self.get_inode(inode_id).ok_or(EIO)?;
 
-        for pointer in inode.direct_pointers {
-            if pointer == 0 {
-                continue;
-            }
+        self.free_inode(&mut inode)
+    }
 
-            let (group_id, bitmap_byte_index, bitmap_bit_index) =
-                self.data_block_bitmap_offset(pointer);
-            let group = &mut self.block_groups[group_id];
-            group.data_bitmap[bitmap_byte_index] &= !(1 << bitmap_bit_index);
-        }
+    // Frees the inode's slot and everything it holds. A new inode may not have been written yet,
+    // so its slot is only released if the bitmap has it
+    fn free_inode(&mut self, inode: &mut Inode) -> Result<(), c_int> {
+        let xattrs = self.get_xattrs(inode).map_err(|_| EIO)?;
+        let (group_id, bitmap_byte_index, bitmap_bit_index) = self.inode_bitmap_offset(inode.id);
+        let group = self.group(group_id);
 
-        if inode.indirect_pointer != 0 {
-            let pointers = self.read_indirect_pointer(inode.indirect_pointer).unwrap();
+        if group.inode_bitmap[bitmap_byte_index] & 1 << bitmap_bit_index != 0 {
+            group.inode_bitmap[bitmap_byte_index] &= !(1 << bitmap_bit_index);
+            group.dirty = true;
+            self.first_free_inode_group = self.first_free_inode_group.min(group_id);
+            self.super_block.free_inodes += 1;
+        }
 
-            for pointer in pointers {
-                if pointer != 0 {
-                    let (group_index, byte_index, bit_index) = self.inode_bitmap_offset(inode_id);
-                    self.block_groups[group_index].data_bitmap[byte_index] &= !(1 << bit_index);
+        if inode.xattr_pointer != 0 {
+            for value in xattrs.entries.values() {
+                if let XattrValue::Block { block_id, .. } = value {
+                    self.free_data_block(*block_id);
                 }
             }
+
+            self.free_data_block(inode.xattr_pointer);
+        }
+
+        // fast symlinks keep their target in the pointers, there's nothing to free
+        if inode.is_fast_symlink() {
+            return Ok(());
+        }
+
+        self.truncate_blocks(inode, 0).map_err(|_| EIO)
+    }
+
+    // Undoes a create that failed before any entry pointed at the new inode, returning the
+    // failure's code, or EIO if not everything could be freed
+    fn discard_new_inode(&mut self, inode: &mut Inode, code: c_int) -> c_int {
+        match self.free_inode(inode) {
+            Ok(()) => code,
+            Err(_) => EIO,
         }
     }
 
+    // Inode ids start at 1 and every group holds the next inodes_per_group of them
     fn inode_bitmap_offset(&self, inode_id: u64) -> (usize, usize, usize) {
-        let group_offset = inode_id / self.super_block.block_size as u64;
-        let byte_offset = ((inode_id - 1) - group_offset * self.super_block.block_size as u64) / 8;
-        let bit_offset = (inode_id - 1) % 8;
+        let inodes_per_group = self.super_block.inodes_per_group;
+        let group_offset = (inode_id - 1) / inodes_per_group;
+        let index = (inode_id - 1) % inodes_per_group;
 
         (
             group_offset as usize,
-            byte_offset as usize,
-            bit_offset as usize,
+            (index / 8) as usize,
+            (index % 8) as usize,
         )
     }
 
+    // Whether the id has a slot in the inode table and a bit in the inode bitmap
+    fn valid_inode_id(&self, inode_id: u64) -> bool {
+        inode_id != 0
+            && (inode_id - 1) / self.super_block.inodes_per_group
+                < self.super_block.block_group_count
+    }
+
     fn inode_table_offset(&self, inode_id: u64) -> u64 {
-        let group_id = inode_id / self.super_block.block_size as u64;
+        let inodes_per_group = self.super_block.inodes_per_group;
+        let group_id = (inode_id - 1) / inodes_per_group;
         let block_size = self.super_block.block_size;
-        get_block_group_size(block_size) * group_id
+        self.super_block.group_size() * group_id
             + block_size as u64 * 3
-            + (inode_id - 1) * size_of::<Inode>() as u64
+            + (inode_id - 1) % inodes_per_group * INODE_SIZE as u64
     }
 
     fn lookup_inode(&mut self, parent_id: u64, name: &OsStr) -> Option<Inode> {
@@ -259,18 +650,28 @@ impl Mfsr {
         }
     }
 
-    fn next_inode_id(&self) -> u64 {
-        for (group_id, group) in self.block_groups.iter().enumerate() {
+    fn next_inode_id(&mut self) -> u64 {
+        let inodes_per_group = self.super_block.inodes_per_group;
+
+        for group_id in self.first_free_inode_group..self.super_block.block_group_count as usize {
+            let group = self.group(group_id);
+
             for (byte_index, byte) in group.inode_bitmap.iter().enumerate() {
                 for bit_index in 0..8 {
+                    let index = byte_index as u64 * 8 + bit_index as u64;
+
+                    // the bitmap may have more bits than the group has inodes
+                    if index >= inodes_per_group {
+                        break;
+                    }
+
                     if byte >> bit_index & 1 == 0 {
-                        return (group_id as u64 * self.super_block.block_size as u64)
-                            + (byte_index as u64 * 8)
-                            + bit_index as u64
-                            + 1;
+                        return group_id as u64 * inodes_per_group + index + 1;
                     }
                 }
             }
+
+            self.first_free_inode_group = group_id + 1;
         }
 
         0
@@ -293,29 +694,40 @@ impl Mfsr {
     }
 
     #[inline(always)]
-    fn data_block_id_to_address(&self, block_id: u32) -> u32 {
-        let cluster_size = self.super_block.block_size;
-        let group_id = block_id / self.super_block.block_size;
-        let offset = (block_id - 1) - group_id * cluster_size;
+    fn data_block_id_to_address(&self, block_id: u64) -> u64 {
+        let cluster_size = self.super_block.block_size as u64;
+        let data_blocks_per_group = self.super_block.data_blocks_per_group;
+        let group_id = (block_id - 1) / data_blocks_per_group;
+        let offset = (block_id - 1) % data_blocks_per_group;
 
-        group_id * get_block_group_size(self.super_block.block_size) as u32
+        group_id * self.super_block.group_size()
             + cluster_size * 3 // super block + data bitmap + inode bitmap
-            + get_inode_table_size(cluster_size) as u32
+            + self.super_block.inode_table_size()
             + offset * cluster_size
     }
 
-    fn next_free_data_block(&self) -> u32 {
-        for (group_id, group) in self.block_groups.iter().enumerate() {
+    fn next_free_data_block(&mut self) -> u64 {
+        let data_blocks_per_group = self.super_block.data_blocks_per_group;
+
+        for group_id in self.first_free_data_group..self.super_block.block_group_count as usize {
+            let group = self.group(group_id);
+
             for (byte_index, byte) in group.data_bitmap.iter().enumerate() {
                 for bit_index in 0..8 {
+                    let index = byte_index as u64 * 8 + bit_index as u64;
+
+                    // the bitmap may have more bits than the group has data blocks
+                    if index >= data_blocks_per_group {
+                        break;
+                    }
+
                     if byte >> bit_index & 1 == 0 {
-                        return (group_id as u32 * self.super_block.block_size)
-                            + (byte_index as u32 * 8)
-                            + bit_index as u32
-                            + 1;
+                        return group_id as u64 * data_blocks_per_group + index + 1;
                     }
                 }
             }
+
+            self.first_free_data_group = group_id + 1;
         }
 
         0
@@ -356,14 +768,27 @@ impl Mfsr {
         uid: u32,
         gid: u32,
     ) -> std::result::Result<(), c_int> {
-        if size > MAX_FILE_SIZE {
+        if size > self.max_file_size(inode) {
             return Err(EFBIG);
         }
 
-        if !self.check_access(inode.uid, inode.gid, inode.mode as u16, uid, gid, W_OK) {
+        if !self.check_access(inode, uid, gid, W_OK) {
             return Err(EACCES);
         }
 
+        // only the blocks along the new end are logged, and the last one may be copied on write
+        let journal_blocks = self.mapping_journal_blocks(inode, 1, false)?;
+        self.reserve_journal(journal_blocks)?;
+
+        if size < inode.size {
+            let block_size = self.super_block.block_size as u64;
+            self.truncate_blocks(inode, size.div_ceil(block_size))
+                .map_err(|_| EIO)?;
+
+            // the tail of a partial last block must read as zeroes if the file grows again
+            self.zero_range(inode, size, size.next_multiple_of(block_size))?;
+        }
+
         inode.size = size;
         inode.last_metadata_changed = current_timestamp();
         inode.last_modified = current_timestamp();
@@ -382,9 +807,10 @@ impl Mfsr {
         }
     }
 
-    fn data_block_bitmap_offset(&self, block_id: u32) -> (usize, usize, usize) {
-        let group_offset = block_id / self.super_block.block_size;
-        let byte_offset = ((block_id - 1) - group_offset * self.super_block.block_size) / 8;
+    fn data_block_bitmap_offset(&self, block_id: u64) -> (usize, usize, usize) {
+        let data_blocks_per_group = self.super_block.data_blocks_per_group;
+        let group_offset = (block_id - 1) / data_blocks_per_group;
+        let byte_offset = ((block_id - 1) % data_blocks_per_group) / 8;
         let bit_offset = (block_id - 1) % 8;
 
         (
@@ -394,185 +820,2519 @@ impl Mfsr {
         )
     }
 
+    fn free_data_block(&mut self, block_id: u64) {
+        // shared blocks only lose a reference
+        if let Some(refs) = self.shared_blocks.get_mut(&block_id) {
+            *refs -= 1;
+            self.shared_blocks_dirty = true;
+
+            if *refs == 0 {
+                self.shared_blocks.remove(&block_id);
+            }
+
+            return;
+        }
+
+        let (group_index, byte_index, bit_index) = self.data_block_bitmap_offset(block_id);
+        let group = self.group(group_index);
+        group.data_bitmap[byte_index] &= !(1 << bit_index);
+        group.dirty = true;
+        self.first_free_data_group = self.first_free_data_group.min(group_index);
+        self.super_block.free_blocks += 1;
+    }
+
     #[inline(always)]
-    fn write_data(&mut self, block_id: u32, data: &[u8]) -> Result<usize> {
-        let address = self.data_block_id_to_address(block_id);
-        let mut cursor = Cursor::new(self.io_map.as_mut());
-        cursor.seek(SeekFrom::Start(address as u64))?;
-        cursor.write_all(data)?;
+    fn write_data(&mut self, block_id: u64, data: &[u8]) -> Result<usize> {
+        self.write_data_at(block_id, 0, data)
+    }
+
+    // Metadata writes go through the journal
+    fn write_data_at(&mut self, block_id: u64, offset: usize, data: &[u8]) -> Result<usize> {
+        let address = self.data_block_id_to_address(block_id) + offset as u64;
+        self.write_bytes(address, data)?;
         let (group_index, byte_index, bit_index) = self.data_block_bitmap_offset(block_id);
-        self.block_groups[group_index].data_bitmap[byte_index] |= 1 << bit_index;
+        let group = self.group(group_index);
+        group.dirty |= group.data_bitmap[byte_index] & (1 << bit_index) == 0;
+        group.data_bitmap[byte_index] |= 1 << bit_index;
 
         Ok(data.len())
     }
 
     #[inline(always)]
-    fn read_data(&mut self, block_id: u32, buf: &mut [u8]) -> Result<()> {
-        if block_id == 0 {
-            println!();
-        }
-        let address = self.data_block_id_to_address(block_id);
-        let mut cursor = Cursor::new(self.io_map.as_ref());
-        cursor.seek(SeekFrom::Start(address as u64))?;
-        cursor.read_exact(buf)?;
+    fn read_data(&mut self, block_id: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_data_at(block_id, 0, buf)
+    }
 
-        Ok(())
+    // File contents skip the journal, the data mode decides how they're ordered with it
+    fn write_file_data_at(&mut self, block_id: u64, offset: usize, data: &[u8]) -> Result<()> {
+        let address = self.data_block_id_to_address(block_id) + offset as u64;
+        self.write_bytes_direct(address, data)
     }
 
-    fn read_indirect_pointer(&mut self, indirect_pointer: u32) -> Result<Vec<u32>> {
-        let mut buf = vec![0; self.super_block.block_size as usize];
-        self.read_data(indirect_pointer, &mut buf)?;
+    fn read_data_at(&mut self, block_id: u64, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let address = self.data_block_id_to_address(block_id) + offset as u64;
+        self.read_bytes(address, buf)
+    }
+
+    // Free blocks the current request may take, root can dig into the reserved ones
+    fn allocatable_blocks(&self) -> u64 {
+        if self.request_uid == 0 {
+            return self.super_block.free_blocks;
+        }
 
-        Ok(buf
-            .chunks_exact(size_of_val(&indirect_pointer))
-            .map(bytes_to_pointer)
-            .collect())
+        self.super_block
+            .free_blocks
+            .saturating_sub(self.super_block.reserved_blocks)
     }
 
-    fn write_indirect_pointer(
-        &mut self,
-        inode: &mut Inode,
-        offset: usize,
-        data: &[u8],
-    ) -> Result<usize, c_int> {
-        if inode.indirect_pointer == 0 {
-            let pointers = vec![0; self.super_block.block_size as usize];
-            let indirect_block_id = self.next_free_data_block();
+    fn allocate_data_block(&mut self) -> Result<u64, c_int> {
+        let block_id = self.next_free_data_block();
 
-            if self.write_data(indirect_block_id, &pointers).is_err() {
-                return Err(EIO);
-            }
+        if block_id == 0 || self.allocatable_blocks() == 0 {
+            return Err(ENOSPC);
+        }
 
-            inode.indirect_pointer = indirect_block_id;
+        // new blocks start zeroed, so partial writes and fresh pointer tables read back clean
+        let zeroes = vec![0; self.super_block.block_size as usize];
+        self.write_file_data_at(block_id, 0, &zeroes)
+            .map_err(|_| EIO)?;
+        let (group_index, byte_index, bit_index) = self.data_block_bitmap_offset(block_id);
+        let group = self.group(group_index);
+        group.data_bitmap[byte_index] |= 1 << bit_index;
+        group.dirty = true;
+        self.super_block.free_blocks -= 1;
 
-            if self.write_inode(inode).is_err() {
-                return Err(EIO);
-            }
+        Ok(block_id)
+    }
 
-            inode.block_count += 1;
-            self.super_block.block_count += 1;
-            self.super_block.free_blocks -= 1
+    fn is_data_block_used(&mut self, block_id: u64) -> bool {
+        let (group_index, byte_index, bit_index) = self.data_block_bitmap_offset(block_id);
+
+        if group_index as u64 >= self.super_block.block_group_count {
+            return true;
         }
 
-        let mut pointers = self
-            .read_indirect_pointer(inode.indirect_pointer)
-            .map_err(|_| EIO)?;
-        let pointer = pointers[offset];
-        let block_id = if pointer == 0 {
-            inode.block_count += 1;
-            self.super_block.block_count += 1;
-            self.super_block.free_blocks -= 1;
-            self.next_free_data_block()
+        self.group(group_index).data_bitmap[byte_index] & (1 << bit_index) != 0
+    }
+
+    // Allocates up to `max_len` consecutive data blocks, starting at `goal` when it's free so
+    // files grow in place. Runs never cross a block group since groups aren't adjacent on disk
+    fn allocate_contiguous(&mut self, goal: u64, max_len: u64) -> Result<(u64, u64), c_int> {
+        let start = if goal != 0 && !self.is_data_block_used(goal) {
+            goal
         } else {
-            pointer
+            self.next_free_data_block()
         };
 
-        if self.write_data(block_id, data).is_err() {
-            return Err(EIO);
+        let max_len = max_len.min(self.allocatable_blocks());
+
+        if start == 0 || max_len == 0 {
+            return Err(ENOSPC);
         }
 
-        pointers[offset] = block_id;
-        let mut buf = vec![];
+        let data_blocks_per_group = self.super_block.data_blocks_per_group;
+        let group_left = data_blocks_per_group - (start - 1) % data_blocks_per_group;
+        let mut len = 0;
 
-        for pointer in pointers {
-            let slice = pointer_to_bytes(pointer);
-            buf.extend_from_slice(&slice);
+        while len < max_len.min(group_left) && !self.is_data_block_used(start + len) {
+            let (group_index, byte_index, bit_index) = self.data_block_bitmap_offset(start + len);
+            let group = self.group(group_index);
+            group.data_bitmap[byte_index] |= 1 << bit_index;
+            group.dirty = true;
+            len += 1;
         }
 
-        if self
-            .write_data(inode.indirect_pointer, buf.as_slice())
-            .is_err()
-        {
-            return Err(EIO);
-        }
+        let zeroes = vec![0; (len * self.super_block.block_size as u64) as usize];
+        self.write_file_data_at(start, 0, &zeroes)
+            .map_err(|_| EIO)?;
+        self.super_block.free_blocks -= len;
 
-        Ok(data.len())
+        Ok((start, len))
     }
 
-    fn get_dentry(&mut self, parent_inode: &Inode) -> Result<DirectoryEntry> {
-        let address = self.data_block_id_to_address(parent_inode.direct_pointers[0]) as u64;
-        let mut cursor = Cursor::new(self.io_map.as_ref());
-        cursor.seek(SeekFrom::Start(address))?;
-        let mut buf = [0; size_of::<u64>()];
-        cursor.read_exact(&mut buf)?;
-        let len = bytes_to_u64(buf);
-        let mut result_buf = vec![0; len as usize];
+    fn extent_leaves(&mut self, inode: &Inode) -> Result<Vec<u64>> {
+        let mut leaves = vec![];
 
-        for direct_pointer in parent_inode.direct_pointers {
-            if direct_pointer == 0 {
-                continue;
-            }
+        if inode.indirect_pointer == 0 {
+            return Ok(leaves);
+        }
 
-            cursor.read_exact(&mut result_buf)?;
+        for index in 0..self.pointers_per_block() {
+            match self.read_pointer(inode.indirect_pointer, index)? {
+                0 => break,
+                leaf => leaves.push(leaf),
+            }
         }
 
-        let result = DirectoryEntry::deserialize_from(Cursor::new(result_buf)).unwrap();
-        Ok(result)
+        Ok(leaves)
     }
 
-    fn write_dentry(
-        &mut self,
-        parent_inode: &mut Inode,
-        dentry: &mut DirectoryEntry,
-    ) -> Result<()> {
-        let mut buf = vec![];
-        dentry.serialize_into(Cursor::new(&mut buf))?;
+    fn read_extents(&mut self, inode: &Inode) -> Result<ExtentMap> {
+        // small maps live in the inode, bigger ones in leaf blocks listed by an index block
+        if inode.indirect_pointer == 0 {
+            return Ok(ExtentMap::new(inode.inline_extents()));
+        }
 
-        for (i, chunk) in buf.chunks(self.super_block.block_size as usize).enumerate() {
-            let block_id = if parent_inode.direct_pointers[i] == 0 {
-                parent_inode.block_count += 1;
-                self.super_block.block_count += 1;
-                self.next_free_data_block()
-            } else {
-                parent_inode.direct_pointers[i]
-            };
+        let mut extents = vec![];
+        let mut buf = vec![0; self.super_block.block_size as usize];
 
-            parent_inode.direct_pointers[i] = block_id;
-            self.write_data(block_id, chunk)?;
+        for leaf in self.extent_leaves(inode)? {
+            self.read_data(leaf, &mut buf)?;
+            extents.extend(ExtentMap::decode_leaf(&buf));
         }
 
-        Ok(())
+        Ok(ExtentMap::new(extents))
     }
-}
 
-impl Filesystem for Mfsr {
-    fn init(&mut self, req: &Request<'_>, _config: &mut fuser::KernelConfig) -> Result<(), c_int> {
-        self.super_block.update_last_mounted();
-        self.super_block.uid = req.uid();
-        self.super_block.gid = req.gid();
+    fn write_extents(&mut self, inode: &mut Inode, map: &ExtentMap) -> Result<(), c_int> {
+        let mut leaves = self.extent_leaves(inode).map_err(|_| EIO)?;
 
-        Ok(())
-    }
+        if map.extents.len() <= INLINE_EXTENTS {
+            for leaf in leaves {
+                self.free_data_block(leaf);
+                inode.block_count -= 1;
+            }
 
-    fn destroy(&mut self) {
-        let buf = self.io_map.as_mut();
-        let mut cursor = Cursor::new(buf);
-        BlockGroup::serialize_into(&mut cursor, &self.block_groups, &mut self.super_block).unwrap();
-    }
+            if inode.indirect_pointer != 0 {
+                self.free_data_block(inode.indirect_pointer);
+                inode.indirect_pointer = 0;
+                inode.block_count -= 1;
+            }
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if name.len() > MAX_NAME_LENGTH {
-            reply.error(ENAMETOOLONG);
-            return;
+            inode.set_inline_extents(&map.extents);
+            return Ok(());
         }
 
-        match self.lookup_inode(parent, name) {
-            Some(i) => reply.entry(&FILE_ATTR_TTL, &i.to_file_attr(&self.super_block), 0),
-            None => reply.error(ENOENT),
+        let block_size = self.super_block.block_size;
+        let chunks: Vec<&[Extent]> = map
+            .extents
+            .chunks(ExtentMap::leaf_capacity(block_size))
+            .collect();
+
+        if chunks.len() as u64 > self.pointers_per_block() {
+            return Err(EFBIG);
         }
-    }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
-        match self.get_inode(ino) {
-            Some(i) => reply.attr(&FILE_ATTR_TTL, &i.to_file_attr(&self.super_block)),
-            None => reply.error(ENOENT),
+        if inode.indirect_pointer == 0 {
+            inode.indirect_pointer = self.allocate_data_block()?;
+            inode.block_count += 1;
         }
-    }
 
-    fn setattr(
-        &mut self,
-        req: &Request<'_>,
-        ino: u64,
+        while leaves.len() < chunks.len() {
+            leaves.push(self.allocate_data_block()?);
+            inode.block_count += 1;
+        }
+
+        for leaf in leaves.drain(chunks.len()..) {
+            self.free_data_block(leaf);
+            inode.block_count -= 1;
+        }
+
+        let mut index = vec![];
+
+        for (leaf, chunk) in leaves.iter().zip(chunks) {
+            self.write_data(*leaf, &ExtentMap::encode_leaf(chunk, block_size))
+                .map_err(|_| EIO)?;
+            index.extend_from_slice(&u64_to_bytes(*leaf));
+        }
+
+        index.resize(block_size as usize, 0);
+        self.write_data(inode.indirect_pointer, &index)
+            .map_err(|_| EIO)?;
+        inode.direct_pointers = [0; DIRECT_POINTERS];
+
+        Ok(())
+    }
+
+    // Makes sure the file blocks [first, first + count) are backed by data blocks
+    fn map_blocks(&mut self, inode: &mut Inode, first: u64, count: u64) -> Result<(), c_int> {
+        if !inode.uses_extents() {
+            for block_index in first..first + count {
+                self.get_or_allocate_block(inode, block_index)?;
+            }
+
+            return Ok(());
+        }
+
+        if first + count > self.max_file_size(inode) / self.super_block.block_size as u64 {
+            return Err(EFBIG);
+        }
+
+        let mut map = self.read_extents(inode).map_err(|_| EIO)?;
+        let mut block_index = first;
+        let mut result = Ok(());
+
+        while block_index < first + count {
+            let (pointer, run) = map.lookup(block_index);
+            let run = run.min(first + count - block_index);
+
+            if pointer != 0 {
+                block_index += run;
+                continue;
+            }
+
+            // continue right after the data of the previous extent when possible
+            let goal = map
+                .extents
+                .iter()
+                .rev()
+                .find(|e| e.end() <= block_index)
+                .map(|e| e.start + (block_index - e.logical))
+                .unwrap_or(0);
+
+            let (start, len) = match self.allocate_contiguous(goal, run) {
+                Ok(r) => r,
+                Err(code) => {
+                    result = Err(code);
+                    break;
+                }
+            };
+
+            map.insert(Extent::new(block_index, start, len));
+            inode.block_count += len;
+            block_index += len;
+        }
+
+        self.write_extents(inode, &map)?;
+        result
+    }
+
+    // Resolves a file block to its data block and how many of the following blocks are
+    // contiguous on disk, unallocated ranges resolve to 0
+    fn resolve_block_range(&mut self, inode: &Inode, block_index: u64) -> Result<(u64, u64)> {
+        if !inode.uses_extents() {
+            return self.resolve_pointer_range(inode, block_index);
+        }
+
+        let (pointer, run) = self.read_extents(inode)?.lookup(block_index);
+
+        if pointer == 0 {
+            return Ok((0, run));
+        }
+
+        let data_blocks_per_group = self.super_block.data_blocks_per_group;
+        let group_left = data_blocks_per_group - (pointer - 1) % data_blocks_per_group;
+
+        Ok((pointer, run.min(group_left)))
+    }
+
+    // Block mapped version of resolve_block_range. A missing pointer table makes the whole range
+    // it would have mapped a hole, so holes are skipped a table at a time
+    fn resolve_pointer_range(&mut self, inode: &Inode, block_index: u64) -> Result<(u64, u64)> {
+        let (level, path) = match self.block_path(block_index) {
+            Some(p) => p,
+            None => return Ok((0, u64::MAX - block_index)),
+        };
+
+        if level == 0 {
+            return Ok((inode.direct_pointers[path[0] as usize], 1));
+        }
+
+        let pointers = self.pointers_per_block();
+        let mut pointer = inode.indirect_root(level);
+
+        for (depth, index) in path.iter().enumerate() {
+            if pointer == 0 {
+                // blocks left in the subtree the missing table would have mapped
+                let span = pointers.pow(level - depth as u32);
+                let offset = path[depth..]
+                    .iter()
+                    .fold(0, |offset, index| offset * pointers + index);
+
+                return Ok((0, span - offset));
+            }
+
+            pointer = self.read_pointer(pointer, *index)?;
+        }
+
+        Ok((pointer, 1))
+    }
+
+    #[inline(always)]
+    fn pointers_per_block(&self) -> u64 {
+        self.super_block.block_size as u64 / size_of::<u64>() as u64
+    }
+
+    fn max_file_size(&self, inode: &Inode) -> u64 {
+        // extents address file blocks with 64 bits, the limit is the largest offset FUSE takes
+        if inode.uses_extents() {
+            let block_size = self.super_block.block_size as u64;
+            return i64::MAX as u64 / block_size * block_size;
+        }
+
+        let pointers = self.pointers_per_block();
+        let mut blocks = DIRECT_POINTERS as u64;
+
+        for level in 1..=INDIRECTION_LEVELS {
+            blocks = blocks.saturating_add(pointers.saturating_pow(level));
+        }
+
+        blocks.saturating_mul(self.super_block.block_size as u64)
+    }
+
+    fn read_pointer(&mut self, block_id: u64, index: u64) -> Result<u64> {
+        let mut buf = [0; size_of::<u64>()];
+        self.read_data_at(block_id, index as usize * buf.len(), &mut buf)?;
+
+        Ok(bytes_to_u64(buf))
+    }
+
+    fn write_pointer(&mut self, block_id: u64, index: u64, pointer: u64) -> Result<()> {
+        let buf = u64_to_bytes(pointer);
+        self.write_data_at(block_id, index as usize * buf.len(), &buf)?;
+
+        Ok(())
+    }
+
+    // Splits a file block index into the indirection level that maps it and the index at each
+    // level of the pointer tree, starting from the root
+    fn block_path(&self, block_index: u64) -> Option<(u32, Vec<u64>)> {
+        if block_index < DIRECT_POINTERS as u64 {
+            return Some((0, vec![block_index]));
+        }
+
+        let pointers = self.pointers_per_block();
+        let mut index = block_index - DIRECT_POINTERS as u64;
+
+        for level in 1..=INDIRECTION_LEVELS {
+            let span = pointers.pow(level);
+
+            if index < span {
+                let mut path = Vec::with_capacity(level as usize);
+
+                for depth in (0..level).rev() {
+                    path.push(index / pointers.pow(depth) % pointers);
+                }
+
+                return Some((level, path));
+            }
+
+            index -= span;
+        }
+
+        None
+    }
+
+    // Returns the data block backing a file block index, 0 if it was never allocated
+    fn get_block_pointer(&mut self, inode: &Inode, block_index: u64) -> Result<u64> {
+        if inode.uses_extents() {
+            return Ok(self.read_extents(inode)?.lookup(block_index).0);
+        }
+
+        let (level, path) = match self.block_path(block_index) {
+            Some(p) => p,
+            None => return Ok(0),
+        };
+
+        if level == 0 {
+            return Ok(inode.direct_pointers[path[0] as usize]);
+        }
+
+        let mut pointer = inode.indirect_root(level);
+
+        for index in path {
+            if pointer == 0 {
+                break;
+            }
+
+            pointer = self.read_pointer(pointer, index)?;
+        }
+
+        Ok(pointer)
+    }
+
+    // Returns the data block backing a file block index, allocating it and any missing pointer
+    // tables on the way
+    fn get_or_allocate_block(&mut self, inode: &mut Inode, block_index: u64) -> Result<u64, c_int> {
+        if inode.uses_extents() {
+            self.map_blocks(inode, block_index, 1)?;
+            return self.get_block_pointer(inode, block_index).map_err(|_| EIO);
+        }
+
+        let (level, path) = self.block_path(block_index).ok_or(EFBIG)?;
+
+        if level == 0 {
+            let index = path[0] as usize;
+
+            if inode.direct_pointers[index] == 0 {
+                inode.direct_pointers[index] = self.allocate_data_block()?;
+                inode.block_count += 1;
+            }
+
+            return Ok(inode.direct_pointers[index]);
+        }
+
+        if inode.indirect_root(level) == 0 {
+            let block_id = self.allocate_data_block()?;
+            inode.set_indirect_root(level, block_id);
+            inode.block_count += 1;
+        }
+
+        let mut pointer = inode.indirect_root(level);
+
+        for index in path {
+            let mut next = self.read_pointer(pointer, index).map_err(|_| EIO)?;
+
+            if next == 0 {
+                next = self.allocate_data_block()?;
+                inode.block_count += 1;
+                self.write_pointer(pointer, index, next).map_err(|_| EIO)?;
+            }
+
+            pointer = next;
+        }
+
+        Ok(pointer)
+    }
+
+    // Frees every block mapped at or after the given file block index, together with the
+    // pointer tables that end up empty
+    fn truncate_blocks(&mut self, inode: &mut Inode, from: u64) -> Result<()> {
+        if inode.uses_extents() {
+            let mut map = self.read_extents(inode)?;
+
+            for (start, len) in map.remove_range(from, u64::MAX) {
+                for block_id in start..start + len {
+                    self.free_data_block(block_id);
+                }
+
+                inode.block_count -= len;
+            }
+
+            return self
+                .write_extents(inode, &map)
+                .map_err(|code| anyhow!("Failed to write extents: {}", code));
+        }
+
+        for index in from.min(DIRECT_POINTERS as u64) as usize..DIRECT_POINTERS {
+            if inode.direct_pointers[index] != 0 {
+                self.free_data_block(inode.direct_pointers[index]);
+                inode.direct_pointers[index] = 0;
+                inode.block_count -= 1;
+            }
+        }
+
+        let pointers = self.pointers_per_block();
+        let mut level_start = DIRECT_POINTERS as u64;
+
+        for level in 1..=INDIRECTION_LEVELS {
+            let span = pointers.pow(level);
+            let root = inode.indirect_root(level);
+
+            if root != 0 && from < level_start + span {
+                let relative_from = from.saturating_sub(level_start);
+                let (freed, empty) = self.truncate_pointer_table(root, level, relative_from)?;
+                inode.block_count -= freed;
+
+                if empty {
+                    self.free_data_block(root);
+                    inode.set_indirect_root(level, 0);
+                    inode.block_count -= 1;
+                }
+            }
+
+            level_start += span;
+        }
+
+        Ok(())
+    }
+
+    // Frees the blocks a pointer table maps from `from` onwards, returns how many blocks were
+    // freed and whether the table is now empty
+    fn truncate_pointer_table(&mut self, table: u64, level: u32, from: u64) -> Result<(u64, bool)> {
+        let pointers = self.pointers_per_block();
+        let child_span = pointers.pow(level - 1);
+        let first = from / child_span;
+        let mut freed = 0;
+        let mut empty = true;
+
+        for index in first..pointers {
+            let pointer = self.read_pointer(table, index)?;
+
+            if pointer == 0 {
+                continue;
+            }
+
+            let child_from = if index == first { from % child_span } else { 0 };
+
+            if level > 1 {
+                let (child_freed, child_empty) =
+                    self.truncate_pointer_table(pointer, level - 1, child_from)?;
+                freed += child_freed;
+
+                if !child_empty {
+                    empty = false;
+                    continue;
+                }
+            }
+
+            self.free_data_block(pointer);
+            freed += 1;
+
+            // a table truncated from its start is freed whole, clearing it would only log it
+            if from != 0 {
+                self.write_pointer(table, index, 0)?;
+            }
+        }
+
+        for index in 0..first.min(pointers) {
+            if self.read_pointer(table, index)? != 0 {
+                empty = false;
+                break;
+            }
+        }
+
+        Ok((freed, empty))
+    }
+
+    // Points a file block index of a block mapped inode at `block_id`, returns the block it
+    // pointed to before. Missing pointer tables are only allocated when mapping a block
+    fn set_block_pointer(
+        &mut self,
+        inode: &mut Inode,
+        block_index: u64,
+        block_id: u64,
+    ) -> Result<u64, c_int> {
+        let (level, path) = self.block_path(block_index).ok_or(EFBIG)?;
+
+        if level == 0 {
+            let previous = inode.direct_pointers[path[0] as usize];
+            inode.direct_pointers[path[0] as usize] = block_id;
+            return Ok(previous);
+        }
+
+        if inode.indirect_root(level) == 0 {
+            if block_id == 0 {
+                return Ok(0);
+            }
+
+            let table = self.allocate_data_block()?;
+            inode.set_indirect_root(level, table);
+            inode.block_count += 1;
+        }
+
+        let mut table = inode.indirect_root(level);
+        let (last, parents) = path.split_last().unwrap();
+
+        for index in parents {
+            let mut next = self.read_pointer(table, *index).map_err(|_| EIO)?;
+
+            if next == 0 {
+                if block_id == 0 {
+                    return Ok(0);
+                }
+
+                next = self.allocate_data_block()?;
+                inode.block_count += 1;
+                self.write_pointer(table, *index, next).map_err(|_| EIO)?;
+            }
+
+            table = next;
+        }
+
+        let previous = self.read_pointer(table, *last).map_err(|_| EIO)?;
+        self.write_pointer(table, *last, block_id)
+            .map_err(|_| EIO)?;
+
+        Ok(previous)
+    }
+
+    // Frees the data blocks mapped in the file block range [from, to), leaving a hole
+    fn punch_blocks(&mut self, inode: &mut Inode, from: u64, to: u64) -> Result<(), c_int> {
+        if inode.uses_extents() {
+            let mut map = self.read_extents(inode).map_err(|_| EIO)?;
+
+            for (start, len) in map.remove_range(from, to) {
+                for block_id in start..start + len {
+                    self.free_data_block(block_id);
+                }
+
+                inode.block_count -= len;
+            }
+
+            return self.write_extents(inode, &map);
+        }
+
+        let max_blocks = self.max_file_size(inode) / self.super_block.block_size as u64;
+
+        for block_index in from..to.min(max_blocks) {
+            let block_id = self.set_block_pointer(inode, block_index, 0)?;
+
+            if block_id != 0 {
+                self.free_data_block(block_id);
+                inode.block_count -= 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Removes the file blocks [from, to) and moves the blocks after them down to close the gap
+    fn collapse_blocks(&mut self, inode: &mut Inode, from: u64, to: u64) -> Result<(), c_int> {
+        let block_size = self.super_block.block_size as u64;
+        let end = inode.size.div_ceil(block_size);
+
+        if inode.uses_extents() {
+            let mut map = self.read_extents(inode).map_err(|_| EIO)?;
+
+            for (start, len) in map.collapse_range(from, to) {
+                for block_id in start..start + len {
+                    self.free_data_block(block_id);
+                }
+
+                inode.block_count -= len;
+            }
+
+            self.write_extents(inode, &map)?;
+        } else {
+            self.punch_blocks(inode, from, to)?;
+
+            for block_index in to..end {
+                let block_id = self.set_block_pointer(inode, block_index, 0)?;
+
+                if block_id != 0 {
+                    self.set_block_pointer(inode, block_index - (to - from), block_id)?;
+                }
+            }
+        }
+
+        // drops the pointer tables left empty past the new end of the file
+        self.truncate_blocks(inode, end - (to - from))
+            .map_err(|_| EIO)
+    }
+
+    // Writes zeroes over the byte range [offset, end), skipping holes
+    fn zero_range(&mut self, inode: &mut Inode, offset: u64, end: u64) -> Result<(), c_int> {
+        let block_size = self.super_block.block_size as u64;
+        let mut position = offset;
+
+        if offset >= end {
+            return Ok(());
+        }
+
+        let first = offset / block_size;
+        self.unshare_blocks(inode, first, (end - 1) / block_size - first + 1)?;
+
+        while position < end {
+            let block_offset = position % block_size;
+            let (block_id, run) = self
+                .resolve_block_range(inode, position / block_size)
+                .map_err(|_| EIO)?;
+            let len = (run.saturating_mul(block_size) - block_offset).min(end - position);
+
+            if block_id != 0 {
+                let zeroes = vec![0; len as usize];
+                self.write_file_data_at(block_id, block_offset as usize, &zeroes)
+                    .map_err(|_| EIO)?;
+            }
+
+            position += len;
+        }
+
+        Ok(())
+    }
+
+    fn read_file(&mut self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let block_size = self.super_block.block_size as u64;
+        let mut read = 0;
+
+        while read < buf.len() {
+            let position = offset + read as u64;
+            let block_offset = (position % block_size) as usize;
+            let (block_id, run) = self.resolve_block_range(inode, position / block_size)?;
+            let len = (run.saturating_mul(block_size) - block_offset as u64)
+                .min((buf.len() - read) as u64) as usize;
+
+            // unallocated blocks read back as zeroes
+            if block_id != 0 {
+                self.read_data_at(block_id, block_offset, &mut buf[read..read + len])?;
+            }
+
+            read += len;
+        }
+
+        Ok(())
+    }
+
+    fn write_file(&mut self, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let block_size = self.super_block.block_size as u64;
+        let first = offset / block_size;
+        let count = (offset + data.len() as u64 - 1) / block_size - first + 1;
+        self.map_blocks(inode, first, count)?;
+        self.unshare_blocks(inode, first, count)?;
+
+        let mut written = 0;
+
+        while written < data.len() {
+            let position = offset + written as u64;
+            let block_offset = (position % block_size) as usize;
+            let (block_id, run) = self
+                .resolve_block_range(inode, position / block_size)
+                .map_err(|_| EIO)?;
+            let len = ((run * block_size) as usize - block_offset).min(data.len() - written);
+
+            self.write_file_data_at(block_id, block_offset, &data[written..written + len])
+                .map_err(|_| EIO)?;
+            written += len;
+        }
+
+        inode.size = inode.size.max(offset + data.len() as u64);
+
+        Ok(())
+    }
+
+    // Copies bytes between files through a bounded buffer
+    fn copy_file_data(
+        &mut self,
+        source: &mut Inode,
+        offset_in: u64,
+        dest: &mut Inode,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<(), c_int> {
+        let mut buf = vec![0; COPY_CHUNK_SIZE.min(len) as usize];
+        let mut copied = 0;
+
+        while copied < len {
+            let chunk = (len - copied).min(COPY_CHUNK_SIZE) as usize;
+            self.read_file(source, offset_in + copied, &mut buf[..chunk])
+                .map_err(|_| EIO)?;
+            self.write_file(dest, offset_out + copied, &buf[..chunk])?;
+
+            // copying within a file changes the mapping the source was read from
+            if source.id == dest.id {
+                *source = dest.clone();
+            }
+
+            copied += chunk as u64;
+        }
+
+        Ok(())
+    }
+
+    // Points a file block at another data block, returns the block it was mapped to before
+    fn remap_block(
+        &mut self,
+        inode: &mut Inode,
+        block_index: u64,
+        block_id: u64,
+    ) -> Result<u64, c_int> {
+        if !inode.uses_extents() {
+            return self.set_block_pointer(inode, block_index, block_id);
+        }
+
+        let mut map = self.read_extents(inode).map_err(|_| EIO)?;
+        let previous = match map.remove_range(block_index, block_index + 1).first() {
+            Some((start, _)) => *start,
+            None => 0,
+        };
+
+        if block_id != 0 {
+            map.insert(Extent::new(block_index, block_id, 1));
+        }
+
+        self.write_extents(inode, &map)?;
+
+        Ok(previous)
+    }
+
+    // Copy on write: gives the file its own copy of every shared block in the range before it's
+    // modified in place
+    fn unshare_blocks(&mut self, inode: &mut Inode, first: u64, count: u64) -> Result<(), c_int> {
+        if self.shared_blocks.is_empty() {
+            return Ok(());
+        }
+
+        let mut block_index = first;
+        let mut buf = vec![0; self.super_block.block_size as usize];
+
+        while block_index < first + count {
+            let (block_id, run) = self
+                .resolve_block_range(inode, block_index)
+                .map_err(|_| EIO)?;
+            let run = run.min(first + count - block_index);
+
+            if block_id == 0 {
+                block_index += run;
+                continue;
+            }
+
+            let shared: Vec<u64> = self
+                .shared_blocks
+                .range(block_id..block_id + run)
+                .map(|(shared, _)| *shared)
+                .collect();
+
+            for shared in shared {
+                let copy = self.allocate_data_block()?;
+                self.read_data(shared, &mut buf).map_err(|_| EIO)?;
+                self.write_file_data_at(copy, 0, &buf).map_err(|_| EIO)?;
+                self.remap_block(inode, block_index + (shared - block_id), copy)?;
+                // drops this file's reference
+                self.free_data_block(shared);
+            }
+
+            block_index += run;
+        }
+
+        Ok(())
+    }
+
+    // Maps the source blocks [from, from + count) into `dest` starting at file block `to`, the
+    // data blocks end up shared by both files
+    fn share_blocks(
+        &mut self,
+        source: &Inode,
+        from: u64,
+        dest: &mut Inode,
+        to: u64,
+        count: u64,
+    ) -> Result<(), c_int> {
+        let mut runs = vec![];
+        let mut block_index = from;
+
+        while block_index < from + count {
+            let (block_id, run) = self
+                .resolve_block_range(source, block_index)
+                .map_err(|_| EIO)?;
+            let run = run.min(from + count - block_index);
+
+            if block_id != 0 {
+                for shared in block_id..block_id + run {
+                    *self.shared_blocks.entry(shared).or_insert(0) += 1;
+                }
+
+                self.shared_blocks_dirty = true;
+
+                runs.push((to + block_index - from, block_id, run));
+            }
+
+            block_index += run;
+        }
+
+        // the references are taken first so blocks already shared with the range survive this
+        self.punch_blocks(dest, to, to + count)?;
+
+        if dest.uses_extents() {
+            let mut map = self.read_extents(dest).map_err(|_| EIO)?;
+
+            for (logical, block_id, run) in runs {
+                map.insert(Extent::new(logical, block_id, run));
+                dest.block_count += run;
+            }
+
+            return self.write_extents(dest, &map);
+        }
+
+        for (logical, block_id, run) in runs {
+            for offset in 0..run {
+                self.set_block_pointer(dest, logical + offset, block_id + offset)?;
+                dest.block_count += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The extra references of shared blocks are kept in a file without a name, pointed at by
+    // the superblock. It holds the entry count as a u64 followed by each block id as a u64 and
+    // its reference count as a u32, all little-endian
+    fn load_shared_blocks(&mut self) -> Result<()> {
+        if self.super_block.shared_blocks_inode == 0 {
+            return Ok(());
+        }
+
+        let inode = self
+            .get_inode(self.super_block.shared_blocks_inode)
+            .ok_or(anyhow!("Missing shared blocks inode"))?;
+        let mut buf = vec![0; inode.size as usize];
+        self.read_file(&inode, 0, &mut buf)?;
+        let mut d = Decoder::new(&buf);
+        self.shared_blocks.clear();
+
+        for _ in 0..d.u64()? {
+            let block_id = d.u64()?;
+            self.shared_blocks.insert(block_id, d.u32()?);
+        }
+
+        Ok(())
+    }
+
+    // What storing a table of `entries` shared blocks logs: the table, the extent leaves mapping
+    // it, the block indexing them and its inode
+    fn shared_blocks_journal_blocks(&self, entries: usize) -> usize {
+        let block_size = self.super_block.block_size;
+        let blocks = (8 + 12 * entries).div_ceil(block_size as usize);
+
+        blocks + blocks.div_ceil(ExtentMap::leaf_capacity(block_size)) + 2
+    }
+
+    fn store_shared_blocks(&mut self) -> Result<()> {
+        // the table is rewritten whole, in a transaction of its own if need be
+        self.reserve_journal(self.shared_blocks_journal_blocks(self.shared_blocks.len()))
+            .map_err(|code| anyhow!("Failed to make room for the shared blocks: {}", code))?;
+
+        let mut inode = match self.super_block.shared_blocks_inode {
+            0 if self.shared_blocks.is_empty() => return Ok(()),
+            0 => {
+                let inode = Inode::new(
+                    self.next_inode_id(),
+                    FileType::RegularFile,
+                    0o600,
+                    0,
+                    0,
+                    EXTENTS_FLAG,
+                );
+                self.super_block.shared_blocks_inode = inode.id;
+                self.super_block.feature_ro_compat |= FEATURE_RO_COMPAT_SHARED_BLOCKS;
+                inode
+            }
+            id => self
+                .get_inode(id)
+                .ok_or(anyhow!("Missing shared blocks inode"))?,
+        };
+
+        if self.shared_blocks.is_empty() {
+            self.shared_blocks_dirty = false;
+            self.delete_inode(inode.id)
+                .map_err(|code| anyhow!("Failed to delete the shared blocks inode: {}", code))?;
+            self.super_block.shared_blocks_inode = 0;
+            return Ok(());
+        }
+
+        self.shared_blocks_dirty = false;
+        // the table is metadata, so unlike file data it's written through the journal
+        let mut e = Encoder::new();
+        e.u64(self.shared_blocks.len() as u64);
+
+        for (block_id, references) in &self.shared_blocks {
+            e.u64(*block_id);
+            e.u32(*references);
+        }
+
+        let buf = e.finish();
+        let block_size = self.super_block.block_size as usize;
+        self.truncate_blocks(&mut inode, 0)?;
+        self.map_blocks(&mut inode, 0, buf.len().div_ceil(block_size) as u64)
+            .map_err(|code| anyhow!("Failed to write shared blocks: {}", code))?;
+
+        for (index, chunk) in buf.chunks(block_size).enumerate() {
+            let block_id = self.get_block_pointer(&inode, index as u64)?;
+            self.write_data(block_id, chunk)?;
+        }
+
+        inode.size = buf.len() as u64;
+        self.write_inode(&mut inode)
+    }
+
+    // Records a written byte range of the device so the next sync knows what to flush
+    fn mark_dirty(&mut self, offset: u64, len: u64) {
+        let (mut start, mut end) = (offset, offset + len);
+
+        // merge with every range it overlaps or touches
+        if let Some((&prev_start, &prev_end)) = self.dirty_ranges.range(..=start).next_back() {
+            if prev_end >= start {
+                start = prev_start;
+                end = end.max(prev_end);
+            }
+        }
+
+        let merged: Vec<(u64, u64)> = self
+            .dirty_ranges
+            .range(start..=end)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+
+        for (s, e) in merged {
+            self.dirty_ranges.remove(&s);
+            end = end.max(e);
+        }
+
+        self.dirty_ranges.insert(start, end);
+    }
+
+    // Commits the metadata and flushes everything written since the last sync
+    fn sync(&mut self) -> Result<()> {
+        if self.shared_blocks_dirty {
+            self.store_shared_blocks()?;
+        }
+
+        self.commit()?;
+        self.flush_dirty_ranges()
+    }
+
+    fn flush_dirty_ranges(&mut self) -> Result<()> {
+        for (start, end) in std::mem::take(&mut self.dirty_ranges) {
+            self.device.flush_range(start, end - start)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let block_size = self.super_block.block_size as u64;
+        let mut read = 0;
+
+        while read < buf.len() {
+            let position = offset + read as u64;
+            let block_offset = (position % block_size) as usize;
+            let len = (block_size as usize - block_offset).min(buf.len() - read);
+
+            // blocks changed by the open transaction are only up to date in memory
+            match self.transaction.get(&(position / block_size)) {
+                Some(image) => {
+                    buf[read..read + len].copy_from_slice(&image[block_offset..block_offset + len])
+                }
+                None => self.device.read_at(position, &mut buf[read..read + len])?,
+            }
+
+            read += len;
+        }
+
+        Ok(())
+    }
+
+    // Writes metadata as part of the open transaction
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if self.super_block.journal_blocks == 0 || data.is_empty() {
+            return self.write_bytes_direct(offset, data);
+        }
+
+        let block_size = self.super_block.block_size as u64;
+        let first = offset / block_size;
+        let last = (offset + data.len() as u64 - 1) / block_size;
+        let new_blocks = (first..=last)
+            .filter(|block| !self.transaction.contains_key(block))
+            .count();
+
+        // operations make room with reserve_journal before logging anything, committing here
+        // would split one of them across transactions
+        if !self.committing
+            && self.transaction.len() + new_blocks + self.commit_reserve()
+                > self.transaction_capacity()
+        {
+            return Err(anyhow!("Transaction doesn't fit in the journal"));
+        }
+
+        let mut written = 0;
+
+        for block in first..=last {
+            let start = block * block_size;
+
+            if !self.transaction.contains_key(&block) {
+                let mut image = vec![0; block_size as usize];
+                self.device.read_at(start, &mut image)?;
+                self.transaction.insert(block, image);
+            }
+
+            let image = self.transaction.get_mut(&block).unwrap();
+
+            let block_offset = (offset + written as u64 - start) as usize;
+            let len = (block_size as usize - block_offset).min(data.len() - written);
+            image[block_offset..block_offset + len].copy_from_slice(&data[written..written + len]);
+            written += len;
+        }
+
+        Ok(())
+    }
+
+    fn write_bytes_direct(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.device.write_at(offset, data)?;
+        self.mark_dirty(offset, data.len() as u64);
+
+        if self.transaction.is_empty() || data.is_empty() {
+            return Ok(());
+        }
+
+        // a block freed and reused in the same transaction must not be overwritten by its old
+        // contents when the transaction is checkpointed
+        let block_size = self.super_block.block_size as u64;
+
+        for block in offset / block_size..=(offset + data.len() as u64 - 1) / block_size {
+            if let Some(image) = self.transaction.get_mut(&block) {
+                let start = (block * block_size).max(offset);
+                let end = ((block + 1) * block_size).min(offset + data.len() as u64);
+                image[(start - block * block_size) as usize..(end - block * block_size) as usize]
+                    .copy_from_slice(&data[(start - offset) as usize..(end - offset) as usize]);
+            }
+        }
+
+        Ok(())
+    }
+
+    // how many blocks a transaction can log given the journal size
+    fn transaction_capacity(&self) -> usize {
+        let per_descriptor = JournalRecord::descriptor_capacity(self.super_block.block_size);
+        // the header and commit blocks aren't available
+        let blocks = (self.super_block.journal_blocks as usize).saturating_sub(2);
+
+        blocks / (per_descriptor + 1) * per_descriptor
+            + (blocks % (per_descriptor + 1)).saturating_sub(1)
+    }
+
+    // room kept for the bitmaps and the super block, logged with every transaction
+    fn commit_reserve(&self) -> usize {
+        2 * self.super_block.block_group_count as usize + 1
+    }
+
+    // how many blocks a single operation can log
+    fn journal_room(&self) -> usize {
+        if self.super_block.journal_blocks == 0 {
+            return usize::MAX;
+        }
+
+        self.transaction_capacity()
+            .saturating_sub(self.commit_reserve())
+    }
+
+    // An operation has to go out in a single transaction, so the room it can take is made before
+    // it logs anything, by committing the operations before it when they leave too little. One
+    // that can't fit even on its own fails without having changed anything
+    fn reserve_journal(&mut self, blocks: usize) -> Result<(), c_int> {
+        if self.super_block.journal_blocks == 0 {
+            return Ok(());
+        }
+
+        let room = self.journal_room();
+
+        if blocks > room {
+            return Err(EFBIG);
+        }
+
+        if self.transaction.len() + blocks > room {
+            self.commit().map_err(|_| EIO)?;
+        }
+
+        Ok(())
+    }
+
+    // The most blocks changing the mapping of `count` file blocks can log, the inode included.
+    // Extent maps are rewritten whole and may gain two extents for every block remapped, block
+    // mapped files log the pointer tables along the range. Unmapping only ever touches tables
+    // that are already there
+    fn mapping_journal_blocks(
+        &mut self,
+        inode: &Inode,
+        count: u64,
+        unmap: bool,
+    ) -> Result<usize, c_int> {
+        let pointers = self.pointers_per_block();
+
+        let blocks = if inode.uses_extents() {
+            let extents = self.read_extents(inode).map_err(|_| EIO)?.extents.len() as u64;
+            let added = if unmap { 1 } else { 2 * count + 2 };
+            let leaf_capacity = ExtentMap::leaf_capacity(self.super_block.block_size) as u64;
+
+            // the leaves and the block indexing them
+            (extents + added).div_ceil(leaf_capacity).min(pointers) + 1
+        } else {
+            // a range spans at most two more tables than it fills at every depth of every level
+            let tables = 2 * count.div_ceil(pointers) + 12;
+
+            if unmap {
+                tables.min(inode.block_count)
+            } else {
+                tables
+            }
+        };
+
+        Ok(blocks as usize + 1)
+    }
+
+    fn journal_block_address(&self, index: u64) -> u64 {
+        self.data_block_id_to_address(self.super_block.journal_start + index)
+    }
+
+    fn write_journal_block(&mut self, index: u64, buf: &[u8]) -> Result<()> {
+        let address = self.journal_block_address(index);
+        self.device.write_at(address, buf)
+    }
+
+    fn write_journal_header(&mut self) -> Result<()> {
+        let block_size = self.super_block.block_size;
+        let header = JournalRecord::Header {
+            sequence: self.journal_sequence,
+        };
+        self.write_journal_block(0, &header.encode(block_size))?;
+        self.device
+            .flush_range(self.journal_block_address(0), block_size as u64)?;
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let result = self.commit_transaction();
+
+        // every transaction logs the primary super block
+        if result.is_ok() && !self.options.read_only {
+            self.backup_group = None;
+        }
+
+        self.track_error(result)
+    }
+
+    // Logs the bitmaps and super block with the rest of the transaction, writes it to the journal
+    // and then to its place on disk. The journal header moves to the next sequence afterwards, so
+    // a transaction is never replayed twice
+    fn commit_transaction(&mut self) -> Result<()> {
+        self.committing = true;
+        let logged = self.log_metadata();
+        self.committing = false;
+        logged?;
+        self.last_commit = SystemTime::now();
+
+        if self.super_block.journal_blocks == 0 || self.transaction.is_empty() {
+            return Ok(());
+        }
+
+        // in ordered mode the data a transaction points at must be on disk first
+        if self.data_mode == DataMode::Ordered {
+            self.flush_dirty_ranges()?;
+        }
+
+        let block_size = self.super_block.block_size;
+        let per_descriptor = JournalRecord::descriptor_capacity(block_size);
+        let blocks: Vec<(u64, Vec<u8>)> =
+            std::mem::take(&mut self.transaction).into_iter().collect();
+        let mut index = 1;
+
+        for chunk in blocks.chunks(per_descriptor) {
+            let descriptor = JournalRecord::Descriptor {
+                sequence: self.journal_sequence,
+                targets: chunk.iter().map(|(target, _)| *target).collect(),
+            };
+            self.write_journal_block(index, &descriptor.encode(block_size))?;
+            index += 1;
+
+            for (_, image) in chunk {
+                self.write_journal_block(index, image)?;
+                index += 1;
+            }
+        }
+
+        let commit = JournalRecord::Commit {
+            sequence: self.journal_sequence,
+            checksum: JournalRecord::transaction_checksum(
+                blocks
+                    .iter()
+                    .map(|(target, image)| (*target, image.as_slice())),
+            ),
+        };
+        self.write_journal_block(index, &commit.encode(block_size))?;
+        self.device
+            .flush_range(self.journal_block_address(1), index * block_size as u64)?;
+
+        // checkpoint
+        for (target, image) in &blocks {
+            self.write_bytes_direct(target * block_size as u64, image)?;
+        }
+
+        self.flush_dirty_ranges()?;
+        self.journal_sequence += 1;
+        self.write_journal_header()
+    }
+
+    // Commits once the transaction gets big or old, for operations that don't need to be durable
+    // right away
+    fn commit_if_needed(&mut self) -> Result<()> {
+        let elapsed = self.last_commit.elapsed().unwrap_or(COMMIT_INTERVAL);
+
+        if self.transaction.len() * 2 >= self.transaction_capacity() || elapsed >= COMMIT_INTERVAL {
+            self.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn log_metadata(&mut self) -> Result<()> {
+        let block_size = self.super_block.block_size;
+        let group_size = self.super_block.group_size();
+
+        let dirty: Vec<usize> = self
+            .block_groups
+            .iter()
+            .filter(|(_, group)| group.dirty)
+            .map(|(&index, _)| index)
+            .collect();
+
+        for index in dirty {
+            let group = &self.block_groups[&index];
+            let mut bitmaps = group.data_bitmap.clone();
+            bitmaps.extend_from_slice(&group.inode_bitmap);
+            self.write_bytes(group_size * index as u64 + block_size as u64, &bitmaps)?;
+            self.block_groups.get_mut(&index).unwrap().dirty = false;
+        }
+
+        // the backups in the other groups are only refreshed on unmount
+        let buf = self.super_block.encode();
+        self.write_bytes(0, &buf)
+    }
+
+    fn replay_journal(&mut self) -> Result<()> {
+        let journal_blocks = self.super_block.journal_blocks as u64;
+
+        if journal_blocks == 0 {
+            return Ok(());
+        }
+
+        let block_size = self.super_block.block_size as usize;
+        let read_block = |fs: &Self, index: u64| -> Result<Vec<u8>> {
+            let mut buf = vec![0; block_size];
+            fs.device
+                .read_at(fs.journal_block_address(index), &mut buf)?;
+            Ok(buf)
+        };
+
+        let sequence = match JournalRecord::decode(&read_block(self, 0)?) {
+            Some(JournalRecord::Header { sequence }) => sequence,
+            _ => return Err(anyhow!("Invalid journal header")),
+        };
+
+        let mut blocks = vec![];
+        let mut index = 1;
+
+        while index < journal_blocks {
+            match JournalRecord::decode(&read_block(self, index)?) {
+                Some(JournalRecord::Descriptor {
+                    sequence: s,
+                    targets,
+                }) if s == sequence && index + (targets.len() as u64) < journal_blocks => {
+                    for target in targets {
+                        index += 1;
+                        blocks.push((target, read_block(self, index)?));
+                    }
+
+                    index += 1;
+                }
+                Some(JournalRecord::Commit {
+                    sequence: s,
+                    checksum,
+                }) if s == sequence
+                    && checksum
+                        == JournalRecord::transaction_checksum(
+                            blocks
+                                .iter()
+                                .map(|(target, image)| (*target, image.as_slice())),
+                        ) =>
+                {
+                    for (target, image) in &blocks {
+                        self.device.write_at(target * block_size as u64, image)?;
+                    }
+
+                    self.device.flush()?;
+                    break;
+                }
+                // anything else is a transaction that never committed
+                _ => break,
+            }
+        }
+
+        self.journal_sequence = sequence + 1;
+        self.write_journal_header()
+    }
+
+    fn get_dentry(&mut self, parent_inode: &Inode) -> Result<DirectoryEntry> {
+        let dentry = self.read_dentry(parent_inode);
+        self.track_error(dentry)
+    }
+
+    fn read_dentry(&mut self, parent_inode: &Inode) -> Result<DirectoryEntry> {
+        let block_size = self.super_block.block_size as usize;
+        let mut buf = vec![];
+
+        for direct_pointer in parent_inode.direct_pointers {
+            if direct_pointer == 0 {
+                break;
+            }
+
+            let mut block = vec![0; block_size];
+            self.read_data(direct_pointer, &mut block)?;
+            buf.extend_from_slice(&block);
+        }
+
+        let header = buf
+            .get(..size_of::<u64>())
+            .ok_or(anyhow!("Directory {} has no blocks", parent_inode.id))?;
+        let len = bytes_to_u64(header.try_into()?) as usize;
+        let result_buf = buf
+            .get(size_of::<u64>()..size_of::<u64>().saturating_add(len))
+            .ok_or(anyhow!("Directory {} is truncated", parent_inode.id))?;
+
+        DirectoryEntry::deserialize_from(Cursor::new(result_buf))
+    }
+
+    fn write_dentry(
+        &mut self,
+        parent_inode: &mut Inode,
+        dentry: &mut DirectoryEntry,
+    ) -> Result<(), c_int> {
+        let mut buf = vec![];
+        dentry
+            .serialize_into(Cursor::new(&mut buf))
+            .map_err(|_| EIO)?;
+        let block_size = self.super_block.block_size as usize;
+
+        // entries only ever live in the direct blocks
+        if buf.len() > block_size * DIRECT_POINTERS {
+            return Err(ENOSPC);
+        }
+
+        // every block is there before anything is written, so running out of space leaves the
+        // old entries intact
+        let mut allocated = vec![];
+
+        for i in 0..buf.len().div_ceil(block_size) {
+            if parent_inode.direct_pointers[i] != 0 {
+                continue;
+            }
+
+            match self.allocate_data_block() {
+                Ok(block_id) => {
+                    parent_inode.direct_pointers[i] = block_id;
+                    parent_inode.block_count += 1;
+                    allocated.push(i);
+                }
+                Err(code) => {
+                    for i in allocated {
+                        self.free_data_block(parent_inode.direct_pointers[i]);
+                        parent_inode.direct_pointers[i] = 0;
+                        parent_inode.block_count -= 1;
+                    }
+
+                    return Err(code);
+                }
+            }
+        }
+
+        for (i, chunk) in buf.chunks(block_size).enumerate() {
+            self.write_data(parent_inode.direct_pointers[i], chunk)
+                .map_err(|_| EIO)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_entry(
+        &mut self,
+        parent_inode: &mut Inode,
+        name: &OsStr,
+        inode_id: u64,
+    ) -> Result<(), c_int> {
+        let mut dentry = self.get_dentry(parent_inode).map_err(|_| EIO)?;
+        dentry
+            .entries
+            .insert(name.to_str().unwrap().to_string(), inode_id);
+        parent_inode.last_modified = current_timestamp();
+        parent_inode.last_metadata_changed = current_timestamp();
+        self.write_dentry(parent_inode, &mut dentry)?;
+        self.write_inode(parent_inode).map_err(|_| EIO)
+    }
+
+    fn write_symlink_target(&mut self, inode: &mut Inode, target: &[u8]) -> Result<(), c_int> {
+        inode.size = target.len() as u64;
+
+        if target.len() <= INLINE_DATA_SIZE {
+            inode.set_inline_data(target);
+            return Ok(());
+        }
+
+        let block_size = self.super_block.block_size as usize;
+
+        if target.len() > block_size * DIRECT_POINTERS {
+            return Err(ENAMETOOLONG);
+        }
+
+        for (i, chunk) in target.chunks(block_size).enumerate() {
+            let written = self.allocate_data_block().and_then(|block_id| {
+                inode.direct_pointers[i] = block_id;
+                inode.block_count += 1;
+                self.write_data(block_id, chunk).map_err(|_| EIO)
+            });
+
+            // nothing is left behind for a target that didn't fit
+            if let Err(code) = written {
+                for pointer in inode.direct_pointers.iter_mut().filter(|p| **p != 0) {
+                    self.free_data_block(*pointer);
+                    *pointer = 0;
+                }
+
+                inode.block_count = 0;
+                return Err(code);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_symlink_target(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        if inode.is_fast_symlink() {
+            return Ok(inode.inline_data());
+        }
+
+        let block_size = self.super_block.block_size as usize;
+        let mut target = Vec::with_capacity(inode.size as usize);
+
+        for pointer in inode.direct_pointers {
+            if pointer == 0 || target.len() >= inode.size as usize {
+                break;
+            }
+
+            let mut buf = vec![0; block_size];
+            self.read_data(pointer, &mut buf)?;
+            target.extend_from_slice(&buf);
+        }
+
+        target.truncate(inode.size as usize);
+        Ok(target)
+    }
+
+    fn check_xattr_access(
+        &mut self,
+        inode: &Inode,
+        name: &str,
+        uid: u32,
+        gid: u32,
+        access_mask: i32,
+    ) -> Result<(), c_int> {
+        if name.len() > MAX_XATTR_NAME_LENGTH {
+            return Err(ERANGE);
+        }
+
+        if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            if inode.kind == FileType::Symlink {
+                return Err(EOPNOTSUPP);
+            }
+
+            // only the owner may change an inode's ACLs
+            if access_mask & W_OK != 0 && uid != 0 && uid != inode.uid {
+                return Err(EPERM);
+            }
+
+            // default ACLs only make sense on directories
+            if name == ACL_DEFAULT_XATTR
+                && access_mask & W_OK != 0
+                && inode.kind != FileType::Directory
+            {
+                return Err(EACCES);
+            }
+        } else if name.starts_with(TRUSTED_PREFIX) {
+            // trusted attributes require CAP_SYS_ADMIN for any access
+            if uid != 0 {
+                return Err(EPERM);
+            }
+        } else if name.starts_with(SECURITY_PREFIX) {
+            // anyone may read security labels, only root may change them
+            if access_mask & W_OK != 0 && uid != 0 {
+                return Err(EPERM);
+            }
+        } else if name.starts_with(USER_PREFIX) {
+            // user attributes are only allowed on regular files and directories
+            if !matches!(inode.kind, FileType::RegularFile | FileType::Directory) {
+                return Err(if access_mask & W_OK != 0 {
+                    EPERM
+                } else {
+                    ENODATA
+                });
+            }
+
+            if inode.kind == FileType::Directory
+                && inode.mode & S_ISVTX != 0
+                && access_mask & W_OK != 0
+                && uid != 0
+                && uid != inode.uid
+            {
+                return Err(EPERM);
+            }
+
+            if !self.check_access(inode, uid, gid, access_mask) {
+                return Err(EACCES);
+            }
+        } else {
+            return Err(EOPNOTSUPP);
+        }
+
+        Ok(())
+    }
+
+    fn get_xattrs(&mut self, inode: &Inode) -> Result<ExtendedAttributes> {
+        if inode.xattr_pointer == 0 {
+            return Ok(ExtendedAttributes::default());
+        }
+
+        let mut buf = vec![0; self.super_block.block_size as usize];
+        let xattrs = self
+            .read_data(inode.xattr_pointer, &mut buf)
+            .and_then(|_| ExtendedAttributes::deserialize_from(Cursor::new(buf)));
+
+        self.track_error(xattrs)
+    }
+
+    fn write_xattrs(
+        &mut self,
+        inode: &mut Inode,
+        xattrs: &mut ExtendedAttributes,
+    ) -> Result<(), c_int> {
+        if xattrs.entries.is_empty() {
+            if inode.xattr_pointer != 0 {
+                self.free_data_block(inode.xattr_pointer);
+                inode.xattr_pointer = 0;
+            }
+
+            return Ok(());
+        }
+
+        let mut buf = vec![];
+        xattrs.serialize_into(&mut buf).map_err(|_| EIO)?;
+
+        if buf.len() > self.super_block.block_size as usize {
+            return Err(ENOSPC);
+        }
+
+        if inode.xattr_pointer == 0 {
+            inode.xattr_pointer = self.allocate_data_block()?;
+        }
+
+        self.write_data(inode.xattr_pointer, &buf)
+            .map_err(|_| EIO)?;
+
+        Ok(())
+    }
+
+    fn read_xattr_value(&mut self, value: &XattrValue) -> Result<Vec<u8>> {
+        match value {
+            XattrValue::Inline(v) => Ok(v.clone()),
+            XattrValue::Block { block_id, len } => {
+                let mut buf = vec![0; *len as usize];
+                self.read_data(*block_id, &mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+
+    fn set_xattr(
+        &mut self,
+        inode: &mut Inode,
+        name: &str,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), c_int> {
+        if value.len() > self.super_block.block_size as usize {
+            return Err(E2BIG);
+        }
+
+        let mut xattrs = self.get_xattrs(inode).map_err(|_| EIO)?;
+        let existing = xattrs.entries.get(name).cloned();
+
+        if flags & XATTR_CREATE != 0 && existing.is_some() {
+            return Err(EEXIST);
+        }
+
+        if flags & XATTR_REPLACE != 0 && existing.is_none() {
+            return Err(ENODATA);
+        }
+
+        let inline = value.len() <= MAX_INLINE_VALUE_SIZE;
+        let placeholder = if inline {
+            XattrValue::Inline(value.to_vec())
+        } else {
+            XattrValue::Block {
+                block_id: 0,
+                len: value.len() as u32,
+            }
+        };
+        xattrs.entries.insert(name.to_string(), placeholder);
+
+        // make sure the table still fits its block before touching any data
+        if xattrs.serialized_size() > self.super_block.block_size as usize {
+            return Err(ENOSPC);
+        }
+
+        let existing_block = match existing {
+            Some(XattrValue::Block { block_id, .. }) => Some(block_id),
+            _ => None,
+        };
+
+        if !inline {
+            let block_id = match existing_block {
+                Some(block_id) => block_id,
+                None => self.allocate_data_block()?,
+            };
+            self.write_data(block_id, value).map_err(|_| EIO)?;
+            xattrs.entries.insert(
+                name.to_string(),
+                XattrValue::Block {
+                    block_id,
+                    len: value.len() as u32,
+                },
+            );
+        } else if let Some(block_id) = existing_block {
+            self.free_data_block(block_id);
+        }
+
+        self.write_xattrs(inode, &mut xattrs)
+    }
+
+    // Looks up an attribute for getxattr, a size of 0 only asks how big it is
+    fn find_xattr(&mut self, inode: &Inode, name: &str, size: u32) -> Result<XattrValue, c_int> {
+        let mut xattrs = self.get_xattrs(inode).map_err(|_| EIO)?;
+        let value = xattrs.entries.remove(name).ok_or(ENODATA)?;
+
+        if size != 0 && value.size() > size as usize {
+            return Err(ERANGE);
+        }
+
+        Ok(value)
+    }
+
+    fn remove_xattr(&mut self, inode: &mut Inode, name: &str) -> Result<(), c_int> {
+        let mut xattrs = self.get_xattrs(inode).map_err(|_| EIO)?;
+
+        match xattrs.entries.remove(name) {
+            Some(XattrValue::Block { block_id, .. }) => self.free_data_block(block_id),
+            Some(XattrValue::Inline(_)) => {}
+            None => return Err(ENODATA),
+        }
+
+        self.write_xattrs(inode, &mut xattrs)
+    }
+
+    fn get_acl(&mut self, inode: &Inode, name: &str) -> Option<Acl> {
+        if inode.xattr_pointer == 0 {
+            return None;
+        }
+
+        let xattrs = self.get_xattrs(inode).ok()?;
+        let value = self.read_xattr_value(xattrs.entries.get(name)?).ok()?;
+
+        Acl::from_bytes(&value).ok()
+    }
+
+    fn set_acl(
+        &mut self,
+        inode: &mut Inode,
+        name: &str,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), c_int> {
+        let acl = Acl::from_bytes(value).map_err(|_| EINVAL)?;
+
+        if name == ACL_DEFAULT_XATTR {
+            return self.set_xattr(inode, name, &acl.to_bytes(), flags);
+        }
+
+        // the mode bits always mirror the owner, group class and other entries
+        inode.mode = (inode.mode & !0o777) | acl.mode();
+
+        if acl.is_minimal() {
+            return match self.remove_xattr(inode, name) {
+                Ok(()) | Err(ENODATA) => Ok(()),
+                Err(code) => Err(code),
+            };
+        }
+
+        self.set_xattr(inode, name, &acl.to_bytes(), flags)
+    }
+
+    // keeps the access ACL in sync after a chmod
+    fn update_acl_mode(&mut self, inode: &mut Inode) -> Result<(), c_int> {
+        if let Some(mut acl) = self.get_acl(inode, ACL_ACCESS_XATTR) {
+            acl.set_mode(inode.mode);
+            self.set_xattr(inode, ACL_ACCESS_XATTR, &acl.to_bytes(), 0)?;
+        }
+
+        Ok(())
+    }
+
+    // applies the parent's default ACL to a freshly created inode
+    fn inherit_acl(&mut self, parent_inode: &Inode, inode: &mut Inode) -> Result<(), c_int> {
+        let default_acl = match self.get_acl(parent_inode, ACL_DEFAULT_XATTR) {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+
+        let acl = default_acl.inherit(inode.mode);
+        inode.mode = (inode.mode & !0o777) | acl.mode();
+
+        if !acl.is_minimal() {
+            self.set_xattr(inode, ACL_ACCESS_XATTR, &acl.to_bytes(), 0)?;
+        }
+
+        // subdirectories pass the default ACL down
+        if inode.kind == FileType::Directory {
+            self.set_xattr(inode, ACL_DEFAULT_XATTR, &default_acl.to_bytes(), 0)?;
+        }
+
+        Ok(())
+    }
+
+    // Creates a file, fifo, socket or device node, only device nodes keep rdev
+    fn make_node(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+        mut mode: u32,
+        rdev: u32,
+    ) -> Result<Inode, c_int> {
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(ENAMETOOLONG);
+        }
+
+        let kind = match mode & S_IFMT {
+            S_IFREG => FileType::RegularFile,
+            S_IFIFO => FileType::NamedPipe,
+            S_IFSOCK => FileType::Socket,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFDIR => return Err(EPERM),
+            _ => return Err(EINVAL),
+        };
+
+        // creating device nodes requires CAP_MKNOD
+        if matches!(kind, FileType::CharDevice | FileType::BlockDevice) && uid != 0 {
+            return Err(EPERM);
+        }
+
+        let mut parent_inode = self.get_inode(parent).ok_or(ENOENT)?;
+
+        if self.lookup_inode(parent, name).is_some() {
+            return Err(EEXIST);
+        }
+
+        if !self.check_access(&parent_inode, uid, gid, W_OK) {
+            return Err(EACCES);
+        }
+
+        mode &= !S_IFMT;
+
+        if uid != 0 {
+            mode &= !(S_ISUID | S_ISGID);
+        }
+
+        // new regular files map their blocks with extents
+        let inode_flags = match kind {
+            FileType::RegularFile => EXTENTS_FLAG,
+            _ => 0,
+        };
+        let mut new_inode = Inode::new(self.next_inode_id(), kind, mode, uid, gid, inode_flags);
+
+        // only device nodes carry a device number
+        if matches!(kind, FileType::CharDevice | FileType::BlockDevice) {
+            new_inode.rdev = rdev;
+        }
+
+        if let Err(code) = self.inherit_acl(&parent_inode, &mut new_inode) {
+            return Err(self.discard_new_inode(&mut new_inode, code));
+        }
+
+        if self.write_inode(&mut new_inode).is_err() {
+            return Err(self.discard_new_inode(&mut new_inode, EIO));
+        }
+
+        if let Err(code) = self.add_entry(&mut parent_inode, name, new_inode.id) {
+            return Err(self.discard_new_inode(&mut new_inode, code));
+        }
+
+        self.commit().map_err(|_| EIO)?;
+
+        Ok(new_inode)
+    }
+
+    // Gives the inode another name in new_parent and returns it with its new link count
+    fn link_entry(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        ino: u64,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) -> Result<Inode, c_int> {
+        if new_name.len() > MAX_NAME_LENGTH {
+            return Err(ENAMETOOLONG);
+        }
+
+        let mut inode = self.get_inode(ino).ok_or(ENOENT)?;
+
+        // hard links to directories would create cycles
+        if inode.kind == FileType::Directory {
+            return Err(EPERM);
+        }
+
+        if inode.hard_links == u32::MAX {
+            return Err(EMLINK);
+        }
+
+        let mut new_parent_inode = self.get_inode(new_parent).ok_or(ENOENT)?;
+
+        if new_parent_inode.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+
+        if self.lookup_inode(new_parent, new_name).is_some() {
+            return Err(EEXIST);
+        }
+
+        if !self.check_access(&new_parent_inode, uid, gid, W_OK) {
+            return Err(EACCES);
+        }
+
+        inode.hard_links += 1;
+        inode.last_metadata_changed = current_timestamp();
+
+        self.write_inode(&mut inode).map_err(|_| EIO)?;
+        self.add_entry(&mut new_parent_inode, new_name, inode.id)?;
+        self.commit().map_err(|_| EIO)?;
+
+        Ok(inode)
+    }
+
+    // Removes a name of a non-directory, and the inode with its last one
+    fn unlink_entry(&mut self, uid: u32, gid: u32, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let mut parent_inode = self.get_inode(parent).ok_or(ENOENT)?;
+
+        if !self.check_access(&parent_inode, uid, gid, W_OK) {
+            return Err(EACCES);
+        }
+
+        let mut inode = self.lookup_inode(parent, name).ok_or(ENOENT)?;
+
+        if inode.kind == FileType::Directory {
+            return Err(EISDIR);
+        }
+
+        if parent_inode.mode & S_ISVTX != 0 // sticky bit
+            && uid != 0
+            && uid != parent_inode.uid
+            && uid != inode.uid
+        {
+            return Err(EACCES);
+        }
+
+        // the inode and its blocks are only freed once the last name is gone
+        inode.hard_links -= 1;
+
+        if inode.hard_links == 0 {
+            self.delete_inode(inode.id)?;
+        } else {
+            inode.last_metadata_changed = current_timestamp();
+            self.write_inode(&mut inode).map_err(|_| EIO)?;
+        }
+
+        parent_inode.last_metadata_changed = current_timestamp();
+        parent_inode.last_modified = current_timestamp();
+        let mut parent_dentry = self.get_dentry(&parent_inode).map_err(|_| EIO)?;
+        parent_dentry.entries.remove(name.to_str().unwrap());
+
+        self.write_dentry(&mut parent_inode, &mut parent_dentry)?;
+        self.write_inode(&mut parent_inode).map_err(|_| EIO)?;
+        self.commit().map_err(|_| EIO)?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn rename_entry(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        let mut inode = self.lookup_inode(parent, name).ok_or(ENOENT)?;
+        let mut parent_inode = self.get_inode(parent).ok_or(ENOENT)?;
+
+        if !self.check_access(&parent_inode, uid, gid, W_OK) {
+            return Err(EACCES);
+        }
+
+        // "Sticky bit" handling
+        if parent_inode.mode & S_ISVTX != 0
+            && uid != 0
+            && uid != parent_inode.uid
+            && uid != inode.uid
+        {
+            return Err(EACCES);
+        }
+
+        let mut new_parent_inode = self.get_inode(new_parent).ok_or(ENOENT)?;
+
+        if !self.check_access(&new_parent_inode, uid, gid, W_OK) {
+            return Err(EACCES);
+        }
+
+        // "Sticky bit" handling in new_parent
+        if new_parent_inode.mode & S_ISVTX != 0 {
+            if let Some(existing_inode) = self.lookup_inode(new_parent, new_name) {
+                if uid != 0 && uid != new_parent_inode.uid && uid != existing_inode.uid {
+                    return Err(EACCES);
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if flags & RENAME_EXCHANGE != 0 {
+            let mut new_inode = self.lookup_inode(new_parent, new_name).ok_or(ENOENT)?;
+            let name = name.to_str().unwrap().to_string();
+            let new_name = new_name.to_str().unwrap().to_string();
+
+            if parent == new_parent {
+                let mut dentry = self.get_dentry(&parent_inode).map_err(|_| EIO)?;
+                dentry.entries.insert(name, new_inode.id);
+                dentry.entries.insert(new_name, inode.id);
+                self.write_dentry(&mut parent_inode, &mut dentry)?;
+            } else {
+                let mut parent_dentry = self.get_dentry(&parent_inode).map_err(|_| EIO)?;
+                parent_dentry.entries.insert(name, new_inode.id);
+                self.write_dentry(&mut parent_inode, &mut parent_dentry)?;
+
+                let mut new_parent_dentry = self.get_dentry(&new_parent_inode).map_err(|_| EIO)?;
+                new_parent_dentry.entries.insert(new_name, inode.id);
+                self.write_dentry(&mut new_parent_inode, &mut new_parent_dentry)?;
+
+                // a directory changing parents takes its ".." link along
+                if inode.kind == FileType::Directory {
+                    parent_inode.hard_links -= 1;
+                    new_parent_inode.hard_links += 1;
+                }
+
+                if new_inode.kind == FileType::Directory {
+                    new_parent_inode.hard_links -= 1;
+                    parent_inode.hard_links += 1;
+                }
+
+                new_parent_inode.last_metadata_changed = current_timestamp();
+                new_parent_inode.last_modified = current_timestamp();
+                self.write_inode(&mut new_parent_inode).map_err(|_| EIO)?;
+            }
+
+            parent_inode.last_metadata_changed = current_timestamp();
+            parent_inode.last_modified = current_timestamp();
+            self.write_inode(&mut parent_inode).map_err(|_| EIO)?;
+            inode.last_metadata_changed = current_timestamp();
+            self.write_inode(&mut inode).map_err(|_| EIO)?;
+            new_inode.last_metadata_changed = current_timestamp();
+            self.write_inode(&mut new_inode).map_err(|_| EIO)?;
+
+            return self.commit().map_err(|_| EIO);
+        }
+
+        // Only overwrite an existing directory if it's empty
+        if let Some(new_name_inode) = self.lookup_inode(new_parent, new_name) {
+            // both names refer to the same inode, nothing to do
+            if new_name_inode.id == inode.id {
+                return Ok(());
+            }
+
+            if new_name_inode.kind == FileType::Directory && inode.kind != FileType::Directory {
+                return Err(EISDIR);
+            }
+
+            if new_name_inode.kind != FileType::Directory && inode.kind == FileType::Directory {
+                return Err(ENOTDIR);
+            }
+
+            if new_name_inode.kind == FileType::Directory
+                && !self
+                    .get_dentry(&new_name_inode)
+                    .map_err(|_| EIO)?
+                    .is_empty()
+            {
+                return Err(ENOTEMPTY);
+            }
+        }
+
+        if inode.kind == FileType::Directory
+            && parent != new_parent
+            && !self.check_access(&inode, uid, gid, W_OK)
+        {
+            return Err(EACCES);
+        }
+
+        // If target already exists decrement its hardlink count
+        if let Some(mut existing_inode) = self.lookup_inode(new_parent, new_name) {
+            let mut dentry = self.get_dentry(&new_parent_inode).map_err(|_| EIO)?;
+            dentry.entries.remove(new_name.to_str().unwrap());
+
+            self.write_dentry(&mut new_parent_inode, &mut dentry)?;
+
+            if existing_inode.kind == FileType::Directory {
+                existing_inode.hard_links = 0;
+                // the replaced directory's ".." no longer points to new_parent
+                new_parent_inode.hard_links -= 1;
+            } else {
+                existing_inode.hard_links -= 1;
+            }
+
+            existing_inode.last_metadata_changed = current_timestamp();
+
+            self.write_inode(&mut existing_inode).map_err(|_| EIO)?;
+
+            if existing_inode.hard_links == 0 {
+                self.delete_inode(existing_inode.id)?;
+            }
+        }
+
+        if inode.kind == FileType::Directory && parent != new_parent {
+            parent_inode.hard_links -= 1;
+            new_parent_inode.hard_links += 1;
+        }
+
+        let mut dentry = self.get_dentry(&parent_inode).map_err(|_| EIO)?;
+        dentry.entries.remove(name.to_str().unwrap());
+
+        self.write_dentry(&mut parent_inode, &mut dentry)?;
+
+        let mut dentry = self.get_dentry(&new_parent_inode).map_err(|_| EIO)?;
+        dentry
+            .entries
+            .insert(new_name.to_str().unwrap().to_string(), inode.id);
+
+        self.write_dentry(&mut new_parent_inode, &mut dentry)?;
+
+        parent_inode.last_metadata_changed = current_timestamp();
+        parent_inode.last_modified = current_timestamp();
+        self.write_inode(&mut parent_inode).map_err(|_| EIO)?;
+        new_parent_inode.last_metadata_changed = current_timestamp();
+        new_parent_inode.last_modified = current_timestamp();
+        self.write_inode(&mut new_parent_inode).map_err(|_| EIO)?;
+        inode.last_metadata_changed = current_timestamp();
+        self.write_inode(&mut inode).map_err(|_| EIO)?;
+
+        self.commit().map_err(|_| EIO)
+    }
+    // fallocate on a regular file: allocates, zeroes, punches out or collapses the range
+    fn allocate_range(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        length: u64,
+        mode: i32,
+    ) -> Result<(), c_int> {
+        let supported = FALLOC_FL_KEEP_SIZE
+            | FALLOC_FL_PUNCH_HOLE
+            | FALLOC_FL_ZERO_RANGE
+            | FALLOC_FL_COLLAPSE_RANGE;
+
+        if mode & !supported != 0 {
+            return Err(EOPNOTSUPP);
+        }
+
+        // punching holes must never change the size, collapsing can't be combined with anything
+        if (mode & FALLOC_FL_PUNCH_HOLE != 0
+            && (mode & FALLOC_FL_KEEP_SIZE == 0 || mode & FALLOC_FL_ZERO_RANGE != 0))
+            || (mode & FALLOC_FL_COLLAPSE_RANGE != 0 && mode != FALLOC_FL_COLLAPSE_RANGE)
+        {
+            return Err(EOPNOTSUPP);
+        }
+
+        let mut inode = self.get_inode(ino).ok_or(ENOENT)?;
+
+        match inode.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(EISDIR),
+            _ => return Err(ENODEV),
+        }
+
+        let end = offset.saturating_add(length);
+        let block_size = self.super_block.block_size as u64;
+
+        if end > self.max_file_size(&inode) {
+            return Err(EFBIG);
+        }
+
+        let (first, last) = (offset / block_size, end.div_ceil(block_size));
+        let journal_blocks = if mode & FALLOC_FL_PUNCH_HOLE != 0 {
+            // the partial blocks at the ends may be remapped by copy on write
+            self.mapping_journal_blocks(&inode, last - first, true)
+                .and_then(|blocks| {
+                    self.mapping_journal_blocks(&inode, 2, false)
+                        .map(|ends| blocks + ends)
+                })
+        } else if mode & FALLOC_FL_COLLAPSE_RANGE != 0 {
+            // block mapped files move every block after the range, possibly into new tables
+            let count = inode.size.div_ceil(block_size).saturating_sub(first);
+            self.mapping_journal_blocks(&inode, count, inode.uses_extents())
+        } else {
+            self.mapping_journal_blocks(&inode, last - first, false)
+        };
+
+        self.reserve_journal(journal_blocks?)?;
+
+        let result = if mode & FALLOC_FL_PUNCH_HOLE != 0 {
+            // only whole blocks can be freed, the partial ends are zeroed in place
+            let first = offset.div_ceil(block_size);
+            let last = end / block_size;
+
+            if first < last {
+                self.zero_range(&mut inode, offset, first * block_size)
+                    .and_then(|_| self.zero_range(&mut inode, last * block_size, end))
+                    .and_then(|_| self.punch_blocks(&mut inode, first, last))
+            } else {
+                self.zero_range(&mut inode, offset, end)
+            }
+        } else if mode & FALLOC_FL_COLLAPSE_RANGE != 0 {
+            if !offset.is_multiple_of(block_size)
+                || !length.is_multiple_of(block_size)
+                || end >= inode.size
+            {
+                return Err(EINVAL);
+            }
+
+            self.collapse_blocks(&mut inode, offset / block_size, end / block_size)
+                .map(|_| inode.size -= length)
+        } else {
+            self.map_blocks(&mut inode, first, last - first)
+                .and_then(|_| {
+                    if mode & FALLOC_FL_ZERO_RANGE != 0 {
+                        self.zero_range(&mut inode, offset, end)
+                    } else {
+                        Ok(())
+                    }
+                })
+                .map(|_| {
+                    if mode & FALLOC_FL_KEEP_SIZE == 0 && end > inode.size {
+                        inode.size = end;
+                    }
+                })
+        };
+
+        inode.last_metadata_changed = current_timestamp();
+
+        if mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE | FALLOC_FL_COLLAPSE_RANGE) != 0 {
+            inode.last_modified = current_timestamp();
+        }
+
+        // blocks allocated before running out of space stay with the file
+        if self.write_inode(&mut inode).is_err() || self.commit_if_needed().is_err() {
+            return Err(EIO);
+        }
+
+        result
+    }
+
+    // SEEK_DATA and SEEK_HOLE, with block granularity: the first offset from `offset` on that
+    // lies in data or in a hole
+    fn seek_block_data(&mut self, ino: u64, offset: u64, whence: i32) -> Result<u64, c_int> {
+        let inode = self.get_inode(ino).ok_or(ENOENT)?;
+
+        if offset >= inode.size {
+            return Err(ENXIO);
+        }
+
+        let block_size = self.super_block.block_size as u64;
+        let mut block_index = offset / block_size;
+
+        while block_index.saturating_mul(block_size) < inode.size {
+            let (block_id, run) = self
+                .resolve_block_range(&inode, block_index)
+                .map_err(|_| EIO)?;
+
+            if (block_id != 0) == (whence == SEEK_DATA) {
+                return Ok(offset.max(block_index * block_size).min(inode.size));
+            }
+
+            block_index = block_index.saturating_add(run);
+        }
+
+        // the end of the file counts as a hole
+        match whence {
+            SEEK_HOLE => Ok(inode.size),
+            _ => Err(ENXIO),
+        }
+    }
+
+    // Copies up to `len` bytes between regular files, sharing the whole blocks it can, and returns
+    // how many were copied
+    fn copy_range(
+        &mut self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<u32, c_int> {
+        let mut source = self.get_inode(ino_in).ok_or(ENOENT)?;
+        let mut dest = self.get_inode(ino_out).ok_or(ENOENT)?;
+
+        for inode in [&source, &dest] {
+            match inode.kind {
+                FileType::RegularFile => {}
+                FileType::Directory => return Err(EISDIR),
+                _ => return Err(EINVAL),
+            }
+        }
+
+        // a call copies at most a chunk, so it fits in one transaction and the reply can count it.
+        // The caller comes back for the rest, and as the chunk is made of whole blocks the next
+        // call still shares them
+        let len = len
+            .min(source.size.saturating_sub(offset_in))
+            .min(COPY_CHUNK_SIZE);
+        let block_size = self.super_block.block_size as u64;
+
+        if len == 0 {
+            return Ok(0);
+        }
+
+        if ino_in == ino_out && offset_in < offset_out + len && offset_out < offset_in + len {
+            return Err(EINVAL);
+        }
+
+        if offset_out + len > self.max_file_size(&dest) {
+            return Err(EFBIG);
+        }
+
+        let count = (offset_out + len).div_ceil(block_size) - offset_out / block_size;
+
+        let journal_blocks = self.mapping_journal_blocks(&dest, count, false)?;
+        self.reserve_journal(journal_blocks)?;
+
+        // whole blocks are shared when both ranges sit at the same offset within their blocks and
+        // the table of shared blocks still fits in a transaction, anything else is copied
+        let (mut head, mut shared) = (len, 0);
+        let entries = self.shared_blocks.len() + count as usize;
+
+        if offset_in % block_size == offset_out % block_size
+            && self.shared_blocks_journal_blocks(entries) <= self.journal_room()
+        {
+            let first = offset_in.next_multiple_of(block_size);
+            let last = (offset_in + len) / block_size * block_size;
+
+            if first < last {
+                head = first - offset_in;
+                shared = last - first;
+            }
+        }
+
+        let mut result = self.copy_file_data(&mut source, offset_in, &mut dest, offset_out, head);
+
+        if result.is_ok() && shared != 0 {
+            result = self.share_blocks(
+                &source,
+                (offset_in + head) / block_size,
+                &mut dest,
+                (offset_out + head) / block_size,
+                shared / block_size,
+            );
+            dest.size = dest.size.max(offset_out + head + shared);
+
+            if ino_in == ino_out {
+                source = dest.clone();
+            }
+        }
+
+        let copied = head + shared;
+
+        if result.is_ok() && copied < len {
+            result = self.copy_file_data(
+                &mut source,
+                offset_in + copied,
+                &mut dest,
+                offset_out + copied,
+                len - copied,
+            );
+        }
+
+        dest.last_metadata_changed = current_timestamp();
+        dest.last_modified = current_timestamp();
+        self.clear_suid_gid(&mut dest);
+
+        if self.write_inode(&mut dest).is_err() || self.commit_if_needed().is_err() {
+            return Err(EIO);
+        }
+
+        result.map(|_| len as u32)
+    }
+}
+
+impl Filesystem for Mfsr {
+    fn init(&mut self, req: &Request<'_>, config: &mut fuser::KernelConfig) -> Result<(), c_int> {
+        // a write has to fit in one transaction along with the blocks it maps
+        config
+            .set_max_write(COPY_CHUNK_SIZE as u32)
+            .map_err(|_| EINVAL)?;
+
+        self.super_block.update_last_mounted();
+        self.super_block.state = FsState::Dirty;
+        self.super_block.mount_count = self.super_block.mount_count.saturating_add(1);
+        self.super_block.uid = req.uid();
+        self.super_block.gid = req.gid();
+
+        // a crash from here on leaves the image marked dirty
+        self.sync().map_err(|_| EIO)
+    }
+
+    fn destroy(&mut self) {
+        // whatever is written back on unmount is on the filesystem's behalf
+        self.request_uid = 0;
+
+        // the super block only says clean once everything else is on disk, so a failure on the
+        // way leaves it dirty and the next mount asks for fsck
+        let result = self.close().and_then(|_| {
+            self.super_block.state = FsState::Clean;
+            self.close()
+        });
+
+        if let Err(e) = result {
+            eprintln!(
+                "Error: failed to write back the filesystem on unmount: {}",
+                e
+            );
+            self.super_block.state = FsState::Dirty;
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if name.len() > MAX_NAME_LENGTH {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+
+        match self.lookup_inode(parent, name) {
+            Some(i) => reply.entry(&FILE_ATTR_TTL, &i.to_file_attr(&self.super_block), 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        match self.get_inode(ino) {
+            Some(i) => reply.attr(&FILE_ATTR_TTL, &i.to_file_attr(&self.super_block)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let inode = match self.get_inode(ino) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if inode.kind != FileType::Symlink {
+            reply.error(EINVAL);
+            return;
+        }
+
+        match self.read_symlink_target(&inode) {
+            Ok(target) => reply.data(&target),
+            Err(_) => reply.error(EIO),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
@@ -587,6 +3347,11 @@ impl Filesystem for Mfsr {
         flags: Option<u32>,
         reply: fuser::ReplyAttr,
     ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
         let mut inode = match self.get_inode(ino) {
             Some(attrs) => attrs,
             None => {
@@ -607,6 +3372,10 @@ impl Filesystem for Mfsr {
             } else {
                 inode.mode = mode;
             }
+            if let Err(code) = self.update_acl_mode(&mut inode) {
+                reply.error(code);
+                return;
+            }
             inode.last_metadata_changed = current_timestamp();
             match self.write_inode(&mut inode) {
                 Ok(()) => {}
@@ -615,6 +3384,11 @@ impl Filesystem for Mfsr {
                     return;
                 }
             }
+            if self.commit().is_err() {
+                reply.error(EIO);
+                return;
+            }
+
             reply.attr(&Duration::new(0, 0), &inode.to_file_attr(&self.super_block));
             return;
         }
@@ -664,6 +3438,11 @@ impl Filesystem for Mfsr {
                     return;
                 }
             }
+            if self.commit().is_err() {
+                reply.error(EIO);
+                return;
+            }
+
             reply.attr(&Duration::new(0, 0), &inode.to_file_attr(&self.super_block));
             return;
         }
@@ -697,16 +3476,7 @@ impl Filesystem for Mfsr {
                 return;
             }
 
-            if inode.uid != req.uid()
-                && !self.check_access(
-                    inode.uid,
-                    inode.gid,
-                    inode.mode as u16,
-                    req.uid(),
-                    req.gid(),
-                    W_OK,
-                )
-            {
+            if inode.uid != req.uid() && !self.check_access(&inode, req.uid(), req.gid(), W_OK) {
                 reply.error(EACCES);
                 return;
             }
@@ -721,16 +3491,7 @@ impl Filesystem for Mfsr {
                 return;
             }
 
-            if inode.uid != req.uid()
-                && !self.check_access(
-                    inode.uid,
-                    inode.gid,
-                    inode.mode as u16,
-                    req.uid(),
-                    req.gid(),
-                    W_OK,
-                )
-            {
+            if inode.uid != req.uid() && !self.check_access(&inode, req.uid(), req.gid(), W_OK) {
                 reply.error(EACCES);
                 return;
             }
@@ -745,16 +3506,7 @@ impl Filesystem for Mfsr {
                 return;
             }
 
-            if inode.uid != req.uid()
-                && !self.check_access(
-                    inode.uid,
-                    inode.gid,
-                    inode.mode as u16,
-                    req.uid(),
-                    req.gid(),
-                    W_OK,
-                )
-            {
+            if inode.uid != req.uid() && !self.check_access(&inode, req.uid(), req.gid(), W_OK) {
                 reply.error(EACCES);
                 return;
             }
@@ -769,16 +3521,7 @@ impl Filesystem for Mfsr {
                 return;
             }
 
-            if inode.uid != req.uid()
-                && !self.check_access(
-                    inode.uid,
-                    inode.gid,
-                    inode.mode as u16,
-                    req.uid(),
-                    req.gid(),
-                    W_OK,
-                )
-            {
+            if inode.uid != req.uid() && !self.check_access(&inode, req.uid(), req.gid(), W_OK) {
                 reply.error(EACCES);
                 return;
             }
@@ -793,21 +3536,13 @@ impl Filesystem for Mfsr {
                 return;
             }
 
-            if inode.uid != req.uid()
-                && !self.check_access(
-                    inode.uid,
-                    inode.gid,
-                    inode.mode as u16,
-                    req.uid(),
-                    req.gid(),
-                    W_OK,
-                )
-            {
+            if inode.uid != req.uid() && !self.check_access(&inode, req.uid(), req.gid(), W_OK) {
                 reply.error(EACCES);
                 return;
             }
 
-            inode.flags = flags;
+            // the block mapping mode isn't a user settable flag
+            inode.flags = (flags & !EXTENTS_FLAG) | (inode.flags & EXTENTS_FLAG);
             inode.last_metadata_changed = current_timestamp();
         }
 
@@ -820,6 +3555,11 @@ impl Filesystem for Mfsr {
         }
 
         let inode = self.get_inode(ino).unwrap();
+        if self.commit().is_err() {
+            reply.error(EIO);
+            return;
+        }
+
         reply.attr(&Duration::new(0, 0), &inode.to_file_attr(&self.super_block));
     }
 
@@ -834,14 +3574,7 @@ impl Filesystem for Mfsr {
 
         match self.get_inode(ino) {
             Some(i) => {
-                if !self.check_access(
-                    i.uid,
-                    i.uid,
-                    i.mode as u16,
-                    req.uid(),
-                    req.gid(),
-                    access_mask,
-                ) {
+                if !self.check_access(&i, req.uid(), req.gid(), access_mask) {
                     reply.error(EACCES);
                     return;
                 }
@@ -853,6 +3586,27 @@ impl Filesystem for Mfsr {
         }
     }
 
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
+        match self.make_node(req.uid(), req.gid(), parent, name, mode, rdev) {
+            Ok(inode) => reply.entry(&FILE_ATTR_TTL, &inode.to_file_attr(&self.super_block), 0),
+            Err(code) => reply.error(code),
+        }
+    }
+
     fn mkdir(
         &mut self,
         req: &Request<'_>,
@@ -862,6 +3616,11 @@ impl Filesystem for Mfsr {
         _umask: u32,
         reply: ReplyEntry,
     ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
         if !self.inode_exists(parent) {
             reply.error(ENOENT);
             return;
@@ -874,14 +3633,7 @@ impl Filesystem for Mfsr {
 
         let mut parent_inode = self.get_inode(parent).unwrap();
 
-        if !self.check_access(
-            parent_inode.uid,
-            parent_inode.gid,
-            parent_inode.mode as u16,
-            req.uid(),
-            req.gid(),
-            W_OK,
-        ) {
+        if !self.check_access(&parent_inode, req.uid(), req.gid(), W_OK) {
             reply.error(EACCES);
             return;
         }
@@ -902,10 +3654,15 @@ impl Filesystem for Mfsr {
             0,
         );
         new_inode.hard_links = 2;
+        if let Err(code) = self.inherit_acl(&parent_inode, &mut new_inode) {
+            reply.error(self.discard_new_inode(&mut new_inode, code));
+            return;
+        }
+
         let mut parenty_dentry = match self.get_dentry(&parent_inode) {
             Ok(d) => d,
             Err(_) => {
-                reply.error(EIO);
+                reply.error(self.discard_new_inode(&mut new_inode, EIO));
                 return;
             }
         };
@@ -914,19 +3671,19 @@ impl Filesystem for Mfsr {
         parenty_dentry
             .entries
             .insert(name.to_str().unwrap().to_string(), new_inode.id);
+        // the new directory's ".." links back to the parent
+        parent_inode.hard_links += 1;
         parent_inode.last_modified = current_timestamp();
         parent_inode.last_metadata_changed = current_timestamp();
 
-        if self.write_dentry(&mut new_inode, &mut dentry).is_err() {
-            reply.error(EIO);
+        if let Err(code) = self.write_dentry(&mut new_inode, &mut dentry) {
+            reply.error(self.discard_new_inode(&mut new_inode, code));
             return;
         }
 
-        if self
-            .write_dentry(&mut parent_inode, &mut parenty_dentry)
-            .is_err()
-        {
-            reply.error(EIO);
+        if let Err(code) = self.write_dentry(&mut parent_inode, &mut parenty_dentry) {
+            // nothing points at the new directory yet
+            reply.error(self.discard_new_inode(&mut new_inode, code));
             return;
         }
 
@@ -946,6 +3703,84 @@ impl Filesystem for Mfsr {
             }
         }
 
+        if self.commit().is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        reply.entry(
+            &FILE_ATTR_TTL,
+            &new_inode.to_file_attr(&self.super_block),
+            0,
+        );
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
+        if link_name.len() > MAX_NAME_LENGTH {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+
+        let mut parent_inode = match self.get_inode(parent) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if self.lookup_inode(parent, link_name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+
+        if !self.check_access(&parent_inode, req.uid(), req.gid(), W_OK) {
+            reply.error(EACCES);
+            return;
+        }
+
+        let mut new_inode = Inode::new(
+            self.next_inode_id(),
+            FileType::Symlink,
+            0o777,
+            req.uid(),
+            req.gid(),
+            0,
+        );
+
+        if let Err(code) = self.write_symlink_target(&mut new_inode, target.as_os_str().as_bytes())
+        {
+            reply.error(code);
+            return;
+        }
+
+        if self.write_inode(&mut new_inode).is_err() {
+            reply.error(self.discard_new_inode(&mut new_inode, EIO));
+            return;
+        }
+
+        if let Err(code) = self.add_entry(&mut parent_inode, link_name, new_inode.id) {
+            reply.error(self.discard_new_inode(&mut new_inode, code));
+            return;
+        }
+
+        if self.commit().is_err() {
+            reply.error(EIO);
+            return;
+        }
+
         reply.entry(
             &FILE_ATTR_TTL,
             &new_inode.to_file_attr(&self.super_block),
@@ -953,9 +3788,28 @@ impl Filesystem for Mfsr {
         );
     }
 
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        new_parent: u64,
+        new_name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
+        match self.link_entry(req.uid(), req.gid(), ino, new_parent, new_name) {
+            Ok(inode) => reply.entry(&FILE_ATTR_TTL, &inode.to_file_attr(&self.super_block), 0),
+            Err(code) => reply.error(code),
+        }
+    }
+
     fn write(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -965,6 +3819,11 @@ impl Filesystem for Mfsr {
         _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
         if !self.check_file_handle_write(fh) {
             reply.error(EACCES);
             return;
@@ -978,63 +3837,46 @@ impl Filesystem for Mfsr {
             }
         };
 
-        let mut written = 0;
-        let start_block = (offset / self.super_block.block_size as i64) as usize;
-        let pointers_per_indirect_block =
-            self.super_block.block_size as usize / size_of_val(&inode.indirect_pointer);
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
 
-        for (i, chunk) in data
-            .chunks(self.super_block.block_size as usize)
-            .enumerate()
-        {
-            let is_direct_pointer = i + start_block < 12;
-            let is_indirect_pointer =
-                i + start_block >= 12 && i + start_block < pointers_per_indirect_block + 12;
-
-            if is_direct_pointer {
-                let is_new_block = inode.direct_pointers[i + start_block] == 0;
-
-                let block_id = if is_new_block {
-                    inode.block_count += 1;
-                    self.super_block.block_count += 1;
-                    self.super_block.free_blocks -= 1;
-                    self.next_free_data_block()
-                } else {
-                    inode.direct_pointers[i + start_block]
-                };
+        let offset = offset as u64;
 
-                if self.write_data(block_id, chunk).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
+        if offset + data.len() as u64 > self.max_file_size(&inode) {
+            reply.error(EFBIG);
+            return;
+        }
 
-                inode.direct_pointers[i + start_block] = block_id;
-                written += chunk.len();
-            } else if is_indirect_pointer {
-                match self.write_indirect_pointer(&mut inode, i + start_block - 12, chunk) {
-                    Ok(w) => written += w,
-                    Err(c) => {
-                        reply.error(c);
-                        return;
-                    }
-                }
-            } else {
-                reply.error(EFBIG);
-                return;
-            }
+        let block_size = self.super_block.block_size as u64;
+        let count = (offset + data.len() as u64).div_ceil(block_size) - offset / block_size;
+
+        if let Err(code) = self
+            .mapping_journal_blocks(&inode, count, false)
+            .and_then(|blocks| self.reserve_journal(blocks))
+        {
+            reply.error(code);
+            return;
         }
 
-        let new_size = (offset + written as i64) as u64;
-        if new_size > inode.size {
-            inode.size = new_size;
+        if let Err(code) = self.write_file(&mut inode, offset, data) {
+            // keep whatever was allocated before running out of space
+            let written = self.write_inode(&mut inode);
+            reply.error(if written.is_err() { EIO } else { code });
+            return;
         }
 
         inode.last_metadata_changed = current_timestamp();
         inode.last_modified = current_timestamp();
         self.clear_suid_gid(&mut inode);
-        self.write_inode(&mut inode).unwrap();
 
-        reply.written(written as u32);
+        if self.write_inode(&mut inode).is_err() || self.commit_if_needed().is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        reply.written(data.len() as u32);
     }
 
     fn read(
@@ -1061,60 +3903,179 @@ impl Filesystem for Mfsr {
             }
         };
 
-        let mut result_buf = Vec::with_capacity(size as usize);
-        let start_block = (offset / self.super_block.block_size as i64) as usize;
-        let end_block = ((offset + size as i64 - 1) / self.super_block.block_size as i64) as usize;
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
 
-        for i in start_block..=end_block {
-            if i < 12 {
-                let direct_pointer = inode.direct_pointers[i];
-                let mut buf = vec![0; self.super_block.block_size as usize];
+        let offset = (offset as u64).min(inode.size);
+        let end = (offset + size as u64).min(inode.size);
+        let mut result_buf = vec![0; (end - offset) as usize];
 
-                if self.read_data(direct_pointer, &mut buf).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
+        if self.read_file(&inode, offset, &mut result_buf).is_err() {
+            reply.error(EIO);
+            return;
+        }
 
-                result_buf.extend_from_slice(buf.as_slice());
-            } else if i >= 12 && i < self.super_block.block_size as usize {
-                let mut buf = vec![0; self.super_block.block_size as usize];
+        inode.last_accessed = current_timestamp();
 
-                if self.read_data(inode.indirect_pointer, &mut buf).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
+        if self.reserve_journal(1).is_err()
+            || self.write_inode(&mut inode).is_err()
+            || self.commit_if_needed().is_err()
+        {
+            reply.error(EIO);
+            return;
+        }
 
-                let pointers: Vec<u32> = buf.chunks_exact(4).map(bytes_to_pointer).collect();
-                let block_id = pointers[i - 12];
+        reply.data(&result_buf);
+    }
 
-                if self.read_data(block_id, &mut buf).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
 
-                result_buf.extend_from_slice(&buf);
-            }
+        if !self.check_file_handle_write(fh) {
+            reply.error(EACCES);
+            return;
+        }
+
+        if offset < 0 || length <= 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        match self.allocate_range(ino, offset as u64, length as u64, mode) {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
+        }
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
+        if !self.check_file_handle_read(fh_in) || !self.check_file_handle_write(fh_out) {
+            reply.error(EACCES);
+            return;
+        }
+
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        match self.copy_range(ino_in, offset_in as u64, ino_out, offset_out as u64, len) {
+            Ok(copied) => reply.written(copied),
+            Err(code) => reply.error(code),
+        }
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        // the kernel handles every other whence by itself
+        if whence != SEEK_DATA && whence != SEEK_HOLE {
+            reply.error(EINVAL);
+            return;
+        }
+
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        match self.seek_block_data(ino, offset as u64, whence) {
+            Ok(position) => reply.offset(position as i64),
+            Err(code) => reply.error(code),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        if !self.inode_exists(ino) {
+            reply.error(ENOENT);
+            return;
         }
 
-        inode.last_accessed = current_timestamp();
+        match self.sync() {
+            Ok(_) => reply.ok(),
+            Err(_) => reply.error(EIO),
+        }
+    }
 
-        if self.write_inode(&mut inode).is_err() {
-            reply.error(EIO);
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        if !self.inode_exists(ino) {
+            reply.error(ENOENT);
             return;
         }
 
-        reply.data(&result_buf);
+        match self.sync() {
+            Ok(_) => reply.ok(),
+            Err(_) => reply.error(EIO),
+        }
     }
 
-    fn flush(
+    fn fsyncdir(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _lock_owner: u64,
+        _datasync: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        if !self.inode_exists(ino) {
+            reply.error(ENOENT);
+            return;
+        }
+
+        match self.sync() {
+            Ok(_) => reply.ok(),
+            Err(_) => reply.error(EIO),
+        }
     }
 
     fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
@@ -1128,14 +4089,7 @@ impl Filesystem for Mfsr {
                     }
                 };
 
-                if !self.check_access(
-                    i.uid,
-                    i.gid,
-                    i.mode as u16,
-                    _req.uid(),
-                    _req.gid(),
-                    access_mask,
-                ) {
+                if !self.check_access(&i, _req.uid(), _req.gid(), access_mask) {
                     reply.error(EACCES);
                     return;
                 }
@@ -1162,14 +4116,7 @@ impl Filesystem for Mfsr {
             }
         };
 
-        if !self.check_access(
-            inode.uid,
-            inode.gid,
-            inode.mode as u16,
-            req.uid(),
-            req.gid(),
-            R_OK,
-        ) {
+        if !self.check_access(&inode, req.uid(), req.gid(), R_OK) {
             reply.error(EACCES);
             return;
         }
@@ -1223,11 +4170,15 @@ impl Filesystem for Mfsr {
     }
 
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        let available = self
+            .super_block
+            .free_blocks
+            .saturating_sub(self.super_block.reserved_blocks);
         reply.statfs(
             self.super_block.block_count,
             self.super_block.free_blocks,
-            self.super_block.free_blocks,
-            self.super_block.inode_count - self.super_block.free_inodes,
+            available,
+            self.super_block.inode_count,
             self.super_block.free_inodes,
             self.super_block.block_size,
             MAX_NAME_LENGTH as u32,
@@ -1245,6 +4196,11 @@ impl Filesystem for Mfsr {
         flags: i32,
         reply: fuser::ReplyCreate,
     ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
         if !self.inode_exists(parent) {
             reply.error(ENOENT);
             return;
@@ -1268,14 +4224,7 @@ impl Filesystem for Mfsr {
 
         let mut parent_inode = self.get_inode(parent).unwrap();
 
-        if !self.check_access(
-            parent_inode.uid,
-            parent_inode.gid,
-            parent_inode.mode as u16,
-            req.uid(),
-            req.gid(),
-            W_OK,
-        ) {
+        if !self.check_access(&parent_inode, req.uid(), req.gid(), W_OK) {
             reply.error(EACCES);
             return;
         }
@@ -1290,13 +4239,18 @@ impl Filesystem for Mfsr {
             mode,
             req.uid(),
             req.gid(),
-            flags as u32,
+            EXTENTS_FLAG,
         );
 
+        if let Err(code) = self.inherit_acl(&parent_inode, &mut new_inode) {
+            reply.error(self.discard_new_inode(&mut new_inode, code));
+            return;
+        }
+
         let mut parent_dentry = match self.get_dentry(&parent_inode) {
             Ok(d) => d,
             Err(_) => {
-                reply.error(EIO);
+                reply.error(self.discard_new_inode(&mut new_inode, EIO));
                 return;
             }
         };
@@ -1306,11 +4260,8 @@ impl Filesystem for Mfsr {
         parent_inode.last_modified = current_timestamp();
         parent_inode.last_metadata_changed = current_timestamp();
 
-        if self
-            .write_dentry(&mut parent_inode, &mut parent_dentry)
-            .is_err()
-        {
-            reply.error(EIO);
+        if let Err(code) = self.write_dentry(&mut parent_inode, &mut parent_dentry) {
+            reply.error(self.discard_new_inode(&mut new_inode, code));
             return;
         }
 
@@ -1319,86 +4270,30 @@ impl Filesystem for Mfsr {
             return;
         }
 
-        match self.write_inode(&mut new_inode) {
-            Ok(_) => {
-                reply.created(
-                    &FILE_ATTR_TTL,
-                    &new_inode.to_file_attr(&self.super_block),
-                    0,
-                    self.get_next_file_handle(read, write),
-                    flags as u32,
-                );
-            }
-            Err(_) => reply.error(EIO),
-        }
-    }
-
-    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let mut parent_inode = match self.get_inode(parent) {
-            Some(i) => i,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        if !self.check_access(
-            parent_inode.uid,
-            parent_inode.gid,
-            parent_inode.mode as u16,
-            req.uid(),
-            req.gid(),
-            W_OK,
-        ) {
-            reply.error(EACCES);
-            return;
-        }
-
-        let uid = req.uid();
-
-        if parent_inode.mode & S_ISVTX != 0 // sticky bit
-            && uid != 0
-            && uid != parent_inode.uid
-            && uid != parent_inode.uid
-        {
-            reply.error(EACCES);
+        if self.write_inode(&mut new_inode).is_err() || self.commit().is_err() {
+            reply.error(EIO);
             return;
         }
 
-        let inode_id = match self.lookup_inode(parent, name) {
-            Some(i) => i.id,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        self.delete_inode(inode_id);
-        parent_inode.last_metadata_changed = current_timestamp();
-        parent_inode.last_modified = current_timestamp();
-        let mut parent_dentry = match self.get_dentry(&parent_inode) {
-            Ok(d) => d,
-            Err(_) => {
-                reply.error(EIO);
-                return;
-            }
-        };
-        parent_dentry.entries.remove(name.to_str().unwrap());
+        reply.created(
+            &FILE_ATTR_TTL,
+            &new_inode.to_file_attr(&self.super_block),
+            0,
+            self.get_next_file_handle(read, write),
+            flags as u32,
+        );
+    }
 
-        if self
-            .write_dentry(&mut parent_inode, &mut parent_dentry)
-            .is_err()
-        {
-            reply.error(EIO);
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
             return;
         }
 
-        if self.write_inode(&mut parent_inode).is_err() {
-            reply.error(EIO);
-            return;
+        match self.unlink_entry(req.uid(), req.gid(), parent, name) {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
         }
-
-        reply.ok();
     }
 
     fn rename(
@@ -1411,6 +4306,31 @@ impl Filesystem for Mfsr {
         flags: u32,
         reply: ReplyEmpty,
     ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
+        match self.rename_entry(
+            req.uid(),
+            req.gid(),
+            parent,
+            name,
+            new_parent,
+            new_name,
+            flags,
+        ) {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
+
         let mut inode = match self.lookup_inode(parent, name) {
             Some(i) => i,
             None => {
@@ -1427,19 +4347,16 @@ impl Filesystem for Mfsr {
             }
         };
 
-        if !self.check_access(
-            parent_inode.uid,
-            parent_inode.gid,
-            parent_inode.mode as u16,
-            req.uid(),
-            req.gid(),
-            W_OK,
-        ) {
+        if !self.get_dentry(&inode).unwrap().is_empty() {
+            reply.error(ENOTEMPTY);
+            return;
+        }
+
+        if !self.check_access(&parent_inode, req.uid(), req.gid(), W_OK) {
             reply.error(EACCES);
             return;
         }
 
-        // "Sticky bit" handling
         if parent_inode.mode & S_ISVTX != 0
             && req.uid() != 0
             && req.uid() != parent_inode.uid
@@ -1449,334 +4366,951 @@ impl Filesystem for Mfsr {
             return;
         }
 
-        let mut new_parent_inode = match self.get_inode(new_parent) {
-            Some(i) => i,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+        // the removed directory's ".." was a link to the parent
+        parent_inode.hard_links -= 1;
+        parent_inode.last_metadata_changed = current_timestamp();
+        parent_inode.last_modified = current_timestamp();
 
-        if !self.check_access(
-            new_parent_inode.uid,
-            new_parent_inode.gid,
-            new_parent_inode.mode as u16,
-            req.uid(),
-            req.gid(),
-            W_OK,
-        ) {
-            reply.error(EACCES);
+        if self.write_inode(&mut parent_inode).is_err() {
+            reply.error(EIO);
             return;
         }
 
-        // "Sticky bit" handling in new_parent
-        if new_parent_inode.mode & S_ISVTX != 0 {
-            if let Some(existing_inode) = self.lookup_inode(new_parent, new_name) {
-                if req.uid() != 0
-                    && req.uid() != new_parent_inode.uid
-                    && req.uid() != existing_inode.uid
-                {
+        inode.hard_links = 0;
+        inode.last_metadata_changed = current_timestamp();
+
+        if self.write_inode(&mut inode).is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        if let Err(code) = self.delete_inode(inode.id) {
+            reply.error(code);
+            return;
+        }
+
+        let mut dentry = self.get_dentry(&parent_inode).unwrap();
+        dentry.entries.remove(name.to_str().unwrap());
+
+        if let Err(code) = self.write_dentry(&mut parent_inode, &mut dentry) {
+            reply.error(code);
+            return;
+        }
+
+        if self.commit().is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        reply.ok();
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        match self.get_inode(ino) {
+            Some(inode) => {
+                if self.check_access(&inode, req.uid(), req.gid(), mask) {
+                    reply.ok();
+                } else {
                     reply.error(EACCES);
-                    return;
                 }
             }
+            None => reply.error(ENOENT),
         }
+    }
 
-        #[cfg(target_os = "linux")]
-        if flags & RENAME_EXCHANGE != 0 {
-            let mut new_inode = match self.lookup_inode(new_parent, new_name) {
-                Some(i) => i,
-                None => {
-                    reply.error(ENOENT);
-                    return;
-                }
-            };
-
-            let mut new_inode_dentry = match self.get_dentry(&new_inode) {
-                Ok(d) => d,
-                Err(_) => {
-                    reply.error(EIO);
-                    return;
-                }
-            };
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
+            return;
+        }
 
-            new_inode_dentry
-                .entries
-                .insert(new_name.to_str().unwrap().to_string(), inode.id);
+        // position is only meaningful for macOS resource forks
+        if position != 0 {
+            reply.error(EINVAL);
+            return;
+        }
 
-            if self
-                .write_dentry(&mut new_inode, &mut new_inode_dentry)
-                .is_err()
-            {
-                reply.error(EIO);
+        let mut inode = match self.get_inode(ino) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
                 return;
             }
+        };
 
-            let mut parent_dentry = match self.get_dentry(&parent_inode) {
-                Ok(d) => d,
-                Err(_) => {
-                    reply.error(EIO);
-                    return;
-                }
-            };
-            parent_dentry
-                .entries
-                .insert(name.to_str().unwrap().to_string(), new_inode.id);
+        let name = name.to_str().unwrap();
 
-            parent_inode.last_metadata_changed = current_timestamp();
-            parent_inode.last_modified = current_timestamp();
+        if let Err(code) = self.check_xattr_access(&inode, name, req.uid(), req.gid(), W_OK) {
+            reply.error(code);
+            return;
+        }
 
-            if self.write_inode(&mut parent_inode).is_err() {
-                reply.error(EIO);
+        let result = if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            self.set_acl(&mut inode, name, value, flags)
+        } else {
+            self.set_xattr(&mut inode, name, value, flags)
+        };
+
+        if let Err(code) = result {
+            reply.error(code);
+            return;
+        }
+
+        inode.last_metadata_changed = current_timestamp();
+
+        if self.write_inode(&mut inode).is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        if self.commit().is_err() {
+            reply.error(EIO);
+            return;
+        }
+
+        reply.ok();
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let inode = match self.get_inode(ino) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
                 return;
             }
+        };
 
-            new_parent_inode.last_metadata_changed = current_timestamp();
-            new_parent_inode.last_modified = current_timestamp();
+        let name = name.to_str().unwrap();
 
-            if self.write_inode(&mut new_parent_inode).is_err() {
-                reply.error(EIO);
-                return;
-            }
+        if let Err(code) = self.check_xattr_access(&inode, name, req.uid(), req.gid(), R_OK) {
+            reply.error(code);
+            return;
+        }
 
-            inode.last_metadata_changed = current_timestamp();
+        match self.find_xattr(&inode, name, size) {
+            Ok(value) if size == 0 => reply.size(value.size() as u32),
+            Ok(value) => match self.read_xattr_value(&value) {
+                Ok(v) => reply.data(&v),
+                Err(_) => reply.error(EIO),
+            },
+            Err(code) => reply.error(code),
+        }
+    }
 
-            if self.write_inode(&mut inode).is_err() {
-                reply.error(EIO);
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let inode = match self.get_inode(ino) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
                 return;
             }
+        };
 
-            new_inode.last_metadata_changed = current_timestamp();
-
-            if self.write_inode(&mut new_inode).is_err() {
+        let xattrs = match self.get_xattrs(&inode) {
+            Ok(x) => x,
+            Err(_) => {
                 reply.error(EIO);
                 return;
             }
+        };
 
-            if inode.kind == FileType::Directory {
-                let mut dentry = self.get_dentry(&inode).unwrap();
-                dentry.entries.insert("..".to_string(), new_parent);
-
-                if self.write_dentry(&mut inode, &mut dentry).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
-
-                if self.write_inode(&mut inode).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
-            }
-
-            if new_inode.kind == FileType::Directory {
-                let mut dentry = self.get_dentry(&new_inode).unwrap();
-                dentry.entries.insert("..".to_string(), parent);
-
-                if self.write_dentry(&mut new_inode, &mut dentry).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
+        let mut names = vec![];
 
-                if self.write_inode(&mut new_inode).is_err() {
-                    reply.error(EIO);
-                    return;
-                }
+        for name in xattrs.entries.keys() {
+            // trusted attributes are hidden from unprivileged processes
+            if name.starts_with(TRUSTED_PREFIX) && req.uid() != 0 {
+                continue;
             }
 
-            reply.ok();
-            return;
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
         }
 
-        // Only overwrite an existing directory if it's empty
-        if let Some(new_name_inode) = self.lookup_inode(new_parent, new_name) {
-            if new_name_inode.kind == FileType::Directory
-                && self.get_dentry(&new_name_inode).unwrap().entries.len() > 2
-            {
-                reply.error(ENOTEMPTY);
-                return;
-            }
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if names.len() > size as usize {
+            reply.error(ERANGE);
+        } else {
+            reply.data(&names);
         }
+    }
 
-        if inode.kind == FileType::Directory
-            && parent != new_parent
-            && !self.check_access(
-                inode.uid,
-                inode.gid,
-                inode.mode as u16,
-                req.uid(),
-                req.gid(),
-                W_OK,
-            )
-        {
-            reply.error(EACCES);
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(e) = self.begin_change(req) {
+            reply.error(e);
             return;
         }
 
-        // If target already exists decrement its hardlink count
-        if let Some(mut existing_inode) = self.lookup_inode(new_parent, new_name) {
-            let mut dentry = self.get_dentry(&parent_inode).unwrap();
-            dentry.entries.remove(new_name.to_str().unwrap());
-
-            if self.write_dentry(&mut parent_inode, &mut dentry).is_err() {
-                reply.error(EIO);
+        let mut inode = match self.get_inode(ino) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
                 return;
             }
+        };
 
-            if existing_inode.kind == FileType::Directory {
-                existing_inode.hard_links = 0;
-            } else {
-                existing_inode.hard_links -= 1;
-            }
-
-            existing_inode.last_metadata_changed = current_timestamp();
+        let name = name.to_str().unwrap();
 
-            if self.write_inode(&mut existing_inode).is_err() {
-                reply.error(EIO);
-                return;
-            }
+        if let Err(code) = self.check_xattr_access(&inode, name, req.uid(), req.gid(), W_OK) {
+            reply.error(code);
+            return;
+        }
 
-            if existing_inode.hard_links == 0 {
-                self.delete_inode(existing_inode.id);
-            }
+        if let Err(code) = self.remove_xattr(&mut inode, name) {
+            reply.error(code);
+            return;
         }
 
-        let mut dentry = self.get_dentry(&parent_inode).unwrap();
-        dentry.entries.remove(name.to_str().unwrap());
+        inode.last_metadata_changed = current_timestamp();
 
-        if self.write_dentry(&mut parent_inode, &mut dentry).is_err() {
+        if self.write_inode(&mut inode).is_err() {
             reply.error(EIO);
             return;
         }
 
-        let mut dentry = self.get_dentry(&new_parent_inode).unwrap();
-        dentry
-            .entries
-            .insert(new_name.to_str().unwrap().to_string(), inode.id);
-
-        if self
-            .write_dentry(&mut new_parent_inode, &mut dentry)
-            .is_err()
-        {
+        if self.commit().is_err() {
             reply.error(EIO);
             return;
         }
 
-        parent_inode.last_metadata_changed = current_timestamp();
-        parent_inode.last_modified = current_timestamp();
-        self.write_inode(&mut parent_inode).unwrap();
-        new_parent_inode.last_metadata_changed = current_timestamp();
-        new_parent_inode.last_modified = current_timestamp();
-        self.write_inode(&mut new_parent_inode).unwrap();
-        inode.last_metadata_changed = current_timestamp();
-        self.write_inode(&mut inode).unwrap();
+        reply.ok();
+    }
+}
 
-        if inode.kind == FileType::Directory {
-            let mut dentry = self.get_dentry(&inode).unwrap();
-            dentry.entries.insert("..".to_string(), new_parent);
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        cli::{format, FormatOptions},
+        device::MemoryDevice,
+        types::acl::{AclEntry, TAG_GROUP, TAG_GROUP_OBJ, TAG_MASK, TAG_OTHER, TAG_USER_OBJ},
+        utils::get_block_group_size,
+    };
+
+    // Room for `groups` block groups of 1 KiB blocks, the first `formatted` of them formatted
+    pub(crate) fn memory_device(groups: u64, formatted: u64) -> MemoryDevice {
+        let options = FormatOptions {
+            block_size: 1024,
+            bytes_per_inode: 1024,
+            reserved_percent: 0,
+            ..Default::default()
+        };
+        let group_size = get_block_group_size(1024, 8192);
+        let mut device = MemoryDevice::new(group_size * groups).unwrap();
+        format(&mut device, group_size * formatted, &options).unwrap();
 
-            if self.write_dentry(&mut inode, &mut dentry).is_err() {
-                reply.error(EIO);
-                return;
-            }
+        device
+    }
 
-            if self.write_inode(&mut inode).is_err() {
-                reply.error(EIO);
-                return;
-            }
-        }
+    pub(crate) fn memory_fs(groups: u64) -> Mfsr {
+        let device = memory_device(groups, groups);
 
-        reply.ok();
+        Mfsr::from_device(Box::new(device), MountOptions::default()).unwrap()
     }
 
-    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let mut inode = match self.lookup_inode(parent, name) {
-            Some(i) => i,
-            None => {
-                reply.error(ENOENT);
-                return;
+    // Closes the filesystem and opens a copy of what it left on the device
+    pub(crate) fn reopen(mut fs: Mfsr) -> Mfsr {
+        fs.close().unwrap();
+
+        crash(fs)
+    }
+
+    // Opens a copy of the device as it is, like after a crash
+    pub(crate) fn crash(fs: Mfsr) -> Mfsr {
+        let device = fs.into_device();
+        let mut image = vec![0; device.len() as usize];
+        device.read_at(0, &mut image).unwrap();
+
+        Mfsr::open_from_device(Box::new(MemoryDevice::from(image)), MountOptions::default())
+            .unwrap()
+    }
+
+    pub(crate) fn add_file(fs: &mut Mfsr, parent: u64, name: &str, data: &[u8]) -> Inode {
+        let id = fs.next_inode_id();
+        let mut inode = Inode::new(id, FileType::RegularFile, 0o644, 0, 0, EXTENTS_FLAG);
+        fs.write_file(&mut inode, 0, data).unwrap();
+        inode.size = data.len() as u64;
+        fs.write_inode(&mut inode).unwrap();
+
+        let mut parent_inode = fs.get_inode(parent).unwrap();
+        fs.add_entry(&mut parent_inode, OsStr::new(name), id)
+            .unwrap();
+        fs.write_inode(&mut parent_inode).unwrap();
+
+        inode
+    }
+
+    pub(crate) fn read_all(fs: &mut Mfsr, inode: &Inode) -> Vec<u8> {
+        let mut buf = vec![0; inode.size as usize];
+        fs.read_file(inode, 0, &mut buf).unwrap();
+
+        buf
+    }
+
+    pub(crate) fn add_directory(fs: &mut Mfsr, parent: u64, name: &str) -> Inode {
+        let mut inode = Inode::new(fs.next_inode_id(), FileType::Directory, 0o755, 0, 0, 0);
+        inode.hard_links = 2;
+        let mut dentry = DirectoryEntry::new(inode.id);
+        fs.write_dentry(&mut inode, &mut dentry).unwrap();
+        fs.write_inode(&mut inode).unwrap();
+
+        let mut parent_inode = fs.get_inode(parent).unwrap();
+        parent_inode.hard_links += 1;
+        fs.add_entry(&mut parent_inode, OsStr::new(name), inode.id)
+            .unwrap();
+        fs.write_inode(&mut parent_inode).unwrap();
+
+        inode
+    }
+
+    fn entries(fs: &mut Mfsr, directory: u64) -> Vec<String> {
+        let inode = fs.get_inode(directory).unwrap();
+
+        fs.get_dentry(&inode).unwrap().entries.into_keys().collect()
+    }
+
+    fn rename(fs: &mut Mfsr, parent: u64, name: &str, new_parent: u64, new_name: &str, flags: u32) {
+        fs.rename_entry(
+            0,
+            0,
+            parent,
+            OsStr::new(name),
+            new_parent,
+            OsStr::new(new_name),
+            flags,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rename_keeps_fsck_clean() {
+        let mut fs = memory_fs(2);
+        let a = add_directory(&mut fs, 1, "a");
+        let b = add_directory(&mut fs, 1, "b");
+        add_directory(&mut fs, a.id, "c");
+        add_directory(&mut fs, b.id, "d");
+
+        rename(&mut fs, a.id, "c", b.id, "c", 0);
+        assert_eq!(entries(&mut fs, a.id), Vec::<String>::new());
+        assert_eq!(entries(&mut fs, b.id), ["c", "d"]);
+
+        // d is empty, so c can take its place
+        rename(&mut fs, b.id, "c", b.id, "d", 0);
+        assert_eq!(entries(&mut fs, b.id), ["d"]);
+
+        rename(&mut fs, 1, "a", b.id, "d", RENAME_EXCHANGE);
+        assert_eq!(entries(&mut fs, 1), ["a", "b"]);
+        assert_eq!(entries(&mut fs, b.id), ["d"]);
+        assert_eq!(fs.lookup_inode(b.id, OsStr::new("d")).unwrap().id, a.id);
+
+        fs.close().unwrap();
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn files_survive_a_remount() {
+        let mut fs = memory_fs(2);
+        let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        let directory = add_directory(&mut fs, 1, "a");
+        add_file(&mut fs, directory.id, "f", &data);
+
+        let mut fs = reopen(fs);
+        let file = fs.lookup_inode(directory.id, OsStr::new("f")).unwrap();
+        assert_eq!(file.size, data.len() as u64);
+        assert_eq!(read_all(&mut fs, &file), data);
+    }
+
+    #[test]
+    fn journal_replays_committed_transactions() {
+        let mut fs = memory_fs(1);
+        let block_size = fs.super_block.block_size;
+        let block_id = fs.next_free_data_block();
+        let target = fs.data_block_id_to_address(block_id) / block_size as u64;
+        let image = vec![0xab; block_size as usize];
+        let log = |fs: &mut Mfsr, commit: bool| {
+            let sequence = fs.journal_sequence;
+            let descriptor = JournalRecord::Descriptor {
+                sequence,
+                targets: vec![target],
+            };
+            fs.write_journal_block(1, &descriptor.encode(block_size))
+                .unwrap();
+            fs.write_journal_block(2, &image).unwrap();
+
+            if commit {
+                let checksum = JournalRecord::transaction_checksum([(target, image.as_slice())]);
+                let commit = JournalRecord::Commit { sequence, checksum };
+                fs.write_journal_block(3, &commit.encode(block_size))
+                    .unwrap();
             }
         };
+        let mut buf = vec![0; block_size as usize];
+
+        // without its commit block the transaction never happened
+        fs.close().unwrap();
+        log(&mut fs, false);
+        let mut fs = crash(fs);
+        fs.read_data(block_id, &mut buf).unwrap();
+        assert_eq!(buf, vec![0; block_size as usize]);
+
+        fs.close().unwrap();
+        log(&mut fs, true);
+        let mut fs = crash(fs);
+        fs.read_data(block_id, &mut buf).unwrap();
+        assert_eq!(buf, image);
+    }
 
-        let mut parent_inode = match self.get_inode(parent) {
-            Some(i) => i,
-            None => {
-                reply.error(ENOENT);
-                return;
+    // Loses every write once the log has been flushed, like a machine going down right after a
+    // commit block reached the disk
+    #[derive(Debug)]
+    struct CrashingDevice {
+        device: MemoryDevice,
+        log: u64,
+        crashed: bool,
+    }
+
+    impl BlockDevice for CrashingDevice {
+        fn len(&self) -> u64 {
+            self.device.len()
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+            self.device.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+            if self.crashed {
+                return Ok(());
             }
-        };
 
-        if self.get_dentry(&inode).unwrap().entries.len() > 2 {
-            reply.error(ENOTEMPTY);
-            return;
+            self.device.write_at(offset, data)
         }
 
-        if !self.check_access(
-            parent_inode.uid,
-            parent_inode.gid,
-            parent_inode.mode as u16,
-            req.uid(),
-            req.gid(),
-            W_OK,
-        ) {
-            reply.error(EACCES);
-            return;
+        fn flush_range(&mut self, offset: u64, len: u64) -> Result<()> {
+            self.crashed |= offset <= self.log && self.log < offset + len;
+
+            Ok(())
         }
 
-        if parent_inode.mode & S_ISVTX != 0
-            && req.uid() != 0
-            && req.uid() != parent_inode.uid
-            && req.uid() != inode.uid
-        {
-            reply.error(EACCES);
-            return;
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
         }
+    }
 
-        parent_inode.last_metadata_changed = current_timestamp();
-        parent_inode.last_modified = current_timestamp();
+    #[test]
+    fn operations_are_replayed_whole() {
+        let mut fs = memory_fs(2);
+        let a = add_directory(&mut fs, 1, "a");
+        let b = add_directory(&mut fs, 1, "b");
+        let c = add_directory(&mut fs, a.id, "c");
+        add_file(&mut fs, c.id, "f", &[7; 5000]);
+        fs.close().unwrap();
+
+        let log = fs.journal_block_address(1);
+        let mut image = vec![0; fs.device.len() as usize];
+        fs.device.read_at(0, &mut image).unwrap();
+        let device = CrashingDevice {
+            device: MemoryDevice::from(image),
+            log,
+            crashed: false,
+        };
+        let mut fs = Mfsr::open_from_device(Box::new(device), MountOptions::default()).unwrap();
+
+        // the rename is only ever in the journal, nothing of it is checkpointed
+        fs.reserve_journal(CHANGE_JOURNAL_BLOCKS).unwrap();
+        rename(&mut fs, a.id, "c", b.id, "d", 0);
+
+        let mut fs = crash(fs);
+        assert_eq!(entries(&mut fs, a.id), Vec::<String>::new());
+        assert_eq!(entries(&mut fs, b.id), ["d"]);
+        assert_eq!(fs.get_inode(a.id).unwrap().hard_links, 2);
+        assert_eq!(fs.get_inode(b.id).unwrap().hard_links, 3);
+        let file = fs.lookup_inode(c.id, OsStr::new("f")).unwrap();
+        assert_eq!(read_all(&mut fs, &file), [7; 5000]);
+
+        fs.close().unwrap();
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
 
-        if self.write_inode(&mut parent_inode).is_err() {
-            reply.error(EIO);
-            return;
+    #[test]
+    fn operations_too_big_for_the_journal_fail_up_front() {
+        let mut fs = memory_fs(1);
+        let room = fs.journal_room();
+        add_directory(&mut fs, 1, "a");
+        let logged = fs.transaction.len();
+
+        assert_eq!(fs.reserve_journal(room + 1), Err(EFBIG));
+        assert_eq!(fs.transaction.len(), logged);
+
+        // what was logged before is committed to make room
+        fs.reserve_journal(room).unwrap();
+        assert!(fs.transaction.is_empty());
+    }
+
+    #[test]
+    fn failed_creates_free_the_inherited_acl() {
+        let mut fs = memory_fs(1);
+        let mut parent = add_directory(&mut fs, 1, "a");
+        let entries = [
+            (TAG_USER_OBJ, 0o7, 0),
+            (TAG_GROUP_OBJ, 0o5, 0),
+            (TAG_GROUP, 0o7, 100),
+            (TAG_MASK, 0o7, 0),
+            (TAG_OTHER, 0o5, 0),
+        ];
+        let acl = Acl {
+            entries: entries
+                .iter()
+                .map(|&(tag, perm, id)| AclEntry { tag, perm, id })
+                .collect(),
+        };
+        fs.set_acl(&mut parent, ACL_DEFAULT_XATTR, &acl.to_bytes(), 0)
+            .unwrap();
+        fs.write_inode(&mut parent).unwrap();
+        let (free_blocks, free_inodes) = (fs.super_block.free_blocks, fs.super_block.free_inodes);
+
+        // a directory gets both ACLs and its entries block, then is written before failing
+        let mut inode = Inode::new(fs.next_inode_id(), FileType::Directory, 0o755, 0, 0, 0);
+        fs.inherit_acl(&parent, &mut inode).unwrap();
+        assert!(fs.get_acl(&inode, ACL_ACCESS_XATTR).is_some());
+        assert_eq!(
+            fs.get_acl(&inode, ACL_DEFAULT_XATTR),
+            fs.get_acl(&parent, ACL_DEFAULT_XATTR)
+        );
+        let mut dentry = DirectoryEntry::new(inode.id);
+        fs.write_dentry(&mut inode, &mut dentry).unwrap();
+        fs.write_inode(&mut inode).unwrap();
+        assert_eq!(fs.discard_new_inode(&mut inode, ENOSPC), ENOSPC);
+
+        // a file that never got written
+        let mut inode = Inode::new(fs.next_inode_id(), FileType::RegularFile, 0o644, 0, 0, 0);
+        fs.inherit_acl(&parent, &mut inode).unwrap();
+        assert_ne!(inode.xattr_pointer, 0);
+        assert_eq!(fs.discard_new_inode(&mut inode, EIO), EIO);
+
+        assert_eq!(fs.super_block.free_blocks, free_blocks);
+        assert_eq!(fs.super_block.free_inodes, free_inodes);
+
+        let mut fs = reopen(fs);
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn pointer_tables_map_every_level() {
+        let mut fs = memory_fs(2);
+        let free_blocks = fs.super_block.free_blocks;
+        let block_size = fs.super_block.block_size as u64;
+        let pointers = fs.pointers_per_block();
+        // the first block mapped directly, then through one, two and three tables
+        let starts = [
+            0,
+            DIRECT_POINTERS as u64,
+            DIRECT_POINTERS as u64 + pointers,
+            DIRECT_POINTERS as u64 + pointers + pointers * pointers,
+        ];
+        let mut inode = Inode::new(fs.next_inode_id(), FileType::RegularFile, 0o644, 0, 0, 0);
+
+        for (i, start) in starts.iter().enumerate() {
+            fs.write_file(&mut inode, start * block_size, &[i as u8 + 1; 10])
+                .unwrap();
         }
 
-        inode.hard_links = 0;
-        inode.last_metadata_changed = current_timestamp();
+        fs.write_inode(&mut inode).unwrap();
+        // a data block at every level and the tables leading to them
+        assert_eq!(inode.block_count, 4 + 1 + 2 + 3);
 
-        if self.write_inode(&mut inode).is_err() {
-            reply.error(EIO);
-            return;
+        let mut fs = reopen(fs);
+        let inode = fs.get_inode(inode.id).unwrap();
+
+        for (i, start) in starts.iter().enumerate() {
+            let mut buf = [0; 10];
+            fs.read_file(&inode, start * block_size, &mut buf).unwrap();
+            assert_eq!(buf, [i as u8 + 1; 10]);
         }
 
-        self.delete_inode(inode.id);
+        // everything in between is a hole
+        assert_eq!(fs.resolve_block_range(&inode, starts[3] - 1).unwrap().0, 0);
 
-        let mut dentry = self.get_dentry(&parent_inode).unwrap();
-        dentry.entries.remove(name.to_str().unwrap());
+        fs.delete_inode(inode.id).unwrap();
+        assert_eq!(fs.super_block.free_blocks, free_blocks);
+        assert_eq!(fs.delete_inode(u64::MAX), Err(EIO));
+    }
 
-        if self.write_dentry(&mut parent_inode, &mut dentry).is_err() {
-            reply.error(EIO);
-            return;
+    #[test]
+    fn fallocate_modes_change_the_range() {
+        for flags in [EXTENTS_FLAG, 0] {
+            let mut fs = memory_fs(1);
+            let mut expected: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+            let mut inode = Inode::new(
+                fs.next_inode_id(),
+                FileType::RegularFile,
+                0o644,
+                0,
+                0,
+                flags,
+            );
+            fs.write_file(&mut inode, 0, &expected).unwrap();
+            inode.size = expected.len() as u64;
+            fs.write_inode(&mut inode).unwrap();
+            let mut root = fs.get_inode(1).unwrap();
+            fs.add_entry(&mut root, OsStr::new("f"), inode.id).unwrap();
+            let id = inode.id;
+            let blocks = |fs: &mut Mfsr| fs.get_inode(id).unwrap().block_count;
+
+            // plain allocation grows the file, unless it keeps the size
+            fs.allocate_range(id, 4096, 2048, 0).unwrap();
+            expected.resize(6144, 0);
+            assert_eq!(blocks(&mut fs), 6);
+            fs.allocate_range(id, 6144, 1024, FALLOC_FL_KEEP_SIZE)
+                .unwrap();
+            assert_eq!(blocks(&mut fs), 7);
+
+            fs.allocate_range(id, 100, 200, FALLOC_FL_ZERO_RANGE)
+                .unwrap();
+            expected[100..300].fill(0);
+
+            // only the whole block in the middle is freed, the ends are zeroed
+            assert_eq!(
+                fs.allocate_range(id, 500, 2048, FALLOC_FL_PUNCH_HOLE),
+                Err(EOPNOTSUPP)
+            );
+            fs.allocate_range(id, 500, 2048, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE)
+                .unwrap();
+            expected[500..2548].fill(0);
+            assert_eq!(blocks(&mut fs), 6);
+
+            assert_eq!(
+                fs.allocate_range(id, 1000, 1024, FALLOC_FL_COLLAPSE_RANGE),
+                Err(EINVAL)
+            );
+            fs.allocate_range(id, 3072, 1024, FALLOC_FL_COLLAPSE_RANGE)
+                .unwrap();
+            expected.drain(3072..4096);
+            // the block kept past the end goes along with the collapsed one
+            assert_eq!(blocks(&mut fs), 4);
+
+            assert_eq!(
+                fs.allocate_range(id, 0, 1024, libc::FALLOC_FL_INSERT_RANGE),
+                Err(EOPNOTSUPP)
+            );
+
+            let mut fs = reopen(fs);
+            let inode = fs.get_inode(id).unwrap();
+            assert_eq!(inode.size, expected.len() as u64);
+            assert_eq!(read_all(&mut fs, &inode), expected);
+
+            let report = fs.fsck(false).unwrap();
+            assert!(report.problems.is_empty(), "{:?}", report.problems);
         }
+    }
 
-        reply.ok();
+    #[test]
+    fn seeking_finds_data_and_holes() {
+        let mut fs = memory_fs(1);
+        let block_size = fs.super_block.block_size as u64;
+        // data in blocks 0 and 4 to 5, a hole in between and another one up to the end
+        let mut inode = add_file(&mut fs, 1, "f", &[1; 100]);
+        fs.write_file(
+            &mut inode,
+            4 * block_size,
+            &vec![2; 2 * block_size as usize],
+        )
+        .unwrap();
+        inode.size = 8 * block_size;
+        fs.write_inode(&mut inode).unwrap();
+        let id = inode.id;
+
+        assert_eq!(fs.seek_block_data(id, 0, SEEK_DATA), Ok(0));
+        assert_eq!(fs.seek_block_data(id, 50, SEEK_DATA), Ok(50));
+        assert_eq!(fs.seek_block_data(id, 50, SEEK_HOLE), Ok(block_size));
+        assert_eq!(
+            fs.seek_block_data(id, block_size + 1, SEEK_DATA),
+            Ok(4 * block_size)
+        );
+        assert_eq!(
+            fs.seek_block_data(id, 4 * block_size, SEEK_HOLE),
+            Ok(6 * block_size)
+        );
+        assert_eq!(
+            fs.seek_block_data(id, 6 * block_size, SEEK_DATA),
+            Err(ENXIO)
+        );
+        assert_eq!(
+            fs.seek_block_data(id, 7 * block_size, SEEK_HOLE),
+            Ok(7 * block_size)
+        );
+        assert_eq!(
+            fs.seek_block_data(id, 8 * block_size, SEEK_HOLE),
+            Err(ENXIO)
+        );
+
+        // a file without holes only has the one at its end
+        let full = add_file(&mut fs, 1, "g", &[3; 3000]);
+        assert_eq!(fs.seek_block_data(full.id, 10, SEEK_HOLE), Ok(3000));
     }
 
-    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
-        match self.get_inode(ino) {
-            Some(inode) => {
-                if self.check_access(
-                    inode.uid,
-                    inode.gid,
-                    inode.mode as u16,
-                    req.uid(),
-                    req.gid(),
-                    mask,
-                ) {
-                    reply.ok();
-                } else {
-                    reply.error(EACCES);
-                }
-            }
-            None => reply.error(ENOENT),
+    #[test]
+    fn copies_share_blocks_until_written() {
+        let mut fs = memory_fs(2);
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let source = add_file(&mut fs, 1, "a", &data);
+        let copy = add_file(&mut fs, 1, "b", &[]);
+        let unaligned = add_file(&mut fs, 1, "c", &[]);
+
+        assert_eq!(fs.copy_range(source.id, 0, copy.id, 0, 10000), Ok(8192));
+        assert_eq!(
+            fs.copy_range(source.id, 10, unaligned.id, 0, 3000),
+            Ok(3000)
+        );
+        assert!(!fs.shared_blocks.is_empty());
+
+        let mut copy = fs.get_inode(copy.id).unwrap();
+        assert_eq!(read_all(&mut fs, &copy), data);
+        assert_eq!(
+            fs.resolve_block_range(&copy, 1).unwrap().0,
+            fs.resolve_block_range(&source, 1).unwrap().0
+        );
+
+        // ranges at different offsets within their blocks are copied instead
+        let unaligned = fs.get_inode(unaligned.id).unwrap();
+        assert_eq!(read_all(&mut fs, &unaligned), data[10..3010]);
+        assert_ne!(
+            fs.resolve_block_range(&unaligned, 0).unwrap().0,
+            fs.resolve_block_range(&source, 0).unwrap().0
+        );
+
+        fs.write_file(&mut copy, 1024, &[0; 100]).unwrap();
+        fs.write_inode(&mut copy).unwrap();
+        assert_ne!(
+            fs.resolve_block_range(&copy, 1).unwrap().0,
+            fs.resolve_block_range(&source, 1).unwrap().0
+        );
+
+        let mut fs = reopen(fs);
+        let source = fs.get_inode(source.id).unwrap();
+        let copy = fs.get_inode(copy.id).unwrap();
+        let mut expected = data.clone();
+        expected[1024..1124].fill(0);
+        assert_eq!(read_all(&mut fs, &source), data);
+        assert_eq!(read_all(&mut fs, &copy), expected);
+
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn xattrs_follow_the_flags() {
+        let mut fs = memory_fs(1);
+        let free_blocks = fs.super_block.free_blocks;
+        let mut inode = add_file(&mut fs, 1, "f", &[]);
+        let big = vec![7; MAX_INLINE_VALUE_SIZE + 1];
+
+        assert_eq!(
+            fs.set_xattr(&mut inode, "user.a", b"1", XATTR_REPLACE),
+            Err(ENODATA)
+        );
+        fs.set_xattr(&mut inode, "user.a", b"1", XATTR_CREATE)
+            .unwrap();
+        assert_eq!(
+            fs.set_xattr(&mut inode, "user.a", b"2", XATTR_CREATE),
+            Err(EEXIST)
+        );
+        fs.set_xattr(&mut inode, "user.a", b"22", XATTR_REPLACE)
+            .unwrap();
+        // too big to keep inline, the value gets a block of its own
+        fs.set_xattr(&mut inode, "user.b", &big, 0).unwrap();
+        fs.write_inode(&mut inode).unwrap();
+
+        let mut fs = reopen(fs);
+        let mut inode = fs.get_inode(inode.id).unwrap();
+        assert_eq!(fs.find_xattr(&inode, "user.a", 0).unwrap().size(), 2);
+        assert_eq!(fs.find_xattr(&inode, "user.a", 1).map(|_| ()), Err(ERANGE));
+        let value = fs.find_xattr(&inode, "user.b", big.len() as u32).unwrap();
+        assert_eq!(fs.read_xattr_value(&value).unwrap(), big);
+        assert_eq!(fs.find_xattr(&inode, "user.c", 0).map(|_| ()), Err(ENODATA));
+
+        fs.remove_xattr(&mut inode, "user.a").unwrap();
+        assert_eq!(fs.remove_xattr(&mut inode, "user.a"), Err(ENODATA));
+        assert_eq!(fs.find_xattr(&inode, "user.a", 0).map(|_| ()), Err(ENODATA));
+
+        // the table's block goes with the last attribute
+        fs.remove_xattr(&mut inode, "user.b").unwrap();
+        assert_eq!(inode.xattr_pointer, 0);
+        assert_eq!(fs.super_block.free_blocks, free_blocks);
+    }
+
+    #[test]
+    fn only_device_nodes_keep_rdev() {
+        let mut fs = memory_fs(1);
+        let rdev = 8 << 8 | 1;
+
+        let device = fs
+            .make_node(0, 0, 1, OsStr::new("sda1"), S_IFBLK | 0o660, rdev)
+            .unwrap();
+        let pipe = fs
+            .make_node(0, 0, 1, OsStr::new("p"), S_IFIFO | 0o644, rdev)
+            .unwrap();
+        assert_eq!(
+            fs.make_node(1000, 1000, 1, OsStr::new("c"), S_IFCHR | 0o666, rdev)
+                .map(|_| ()),
+            Err(EPERM)
+        );
+        assert_eq!(
+            fs.make_node(0, 0, 1, OsStr::new("d"), S_IFDIR | 0o755, 0)
+                .map(|_| ()),
+            Err(EPERM)
+        );
+
+        let mut fs = reopen(fs);
+        let device = fs.get_inode(device.id).unwrap();
+        assert_eq!(device.kind, FileType::BlockDevice);
+        assert_eq!(device.to_file_attr(&fs.super_block).rdev, rdev);
+        assert_eq!(fs.get_inode(pipe.id).unwrap().rdev, 0);
+    }
+
+    #[test]
+    fn hard_links_count_names() {
+        let mut fs = memory_fs(1);
+        let directory = add_directory(&mut fs, 1, "a");
+        let (free_blocks, free_inodes) = (fs.super_block.free_blocks, fs.super_block.free_inodes);
+        let file = add_file(&mut fs, 1, "f", &[7; 3000]);
+        assert_eq!(file.hard_links, 1);
+
+        let linked = fs
+            .link_entry(0, 0, file.id, directory.id, OsStr::new("g"))
+            .unwrap();
+        assert_eq!(linked.hard_links, 2);
+        assert_eq!(
+            fs.link_entry(0, 0, directory.id, 1, OsStr::new("b"))
+                .map(|_| ()),
+            Err(EPERM)
+        );
+        assert_eq!(fs.unlink_entry(0, 0, 1, OsStr::new("a")), Err(EISDIR));
+
+        fs.unlink_entry(0, 0, 1, OsStr::new("f")).unwrap();
+        let mut fs = reopen(fs);
+        let file = fs.lookup_inode(directory.id, OsStr::new("g")).unwrap();
+        assert_eq!(file.hard_links, 1);
+        assert_eq!(read_all(&mut fs, &file), [7; 3000]);
+        assert!(fs.lookup_inode(1, OsStr::new("f")).is_none());
+
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        // the last name takes the inode and its blocks along
+        fs.unlink_entry(0, 0, directory.id, OsStr::new("g"))
+            .unwrap();
+        assert!(!fs.inode_exists(file.id));
+        assert_eq!(fs.super_block.free_blocks, free_blocks);
+        assert_eq!(fs.super_block.free_inodes, free_inodes);
+    }
+
+    #[test]
+    fn holes_read_as_zeroes() {
+        let mut fs = memory_fs(1);
+        let block_size = fs.super_block.block_size as u64;
+        let mut inode = add_file(&mut fs, 1, "f", &[7; 3000]);
+
+        // what a shrink cuts off a partial block must not come back when the file grows again
+        fs.truncate_inode(&mut inode, 1500, 0, 0).unwrap();
+        fs.truncate_inode(&mut inode, 5000, 0, 0).unwrap();
+        fs.write_file(&mut inode, 10 * block_size, &[8; 100])
+            .unwrap();
+        inode.size = 10 * block_size + 100;
+        fs.write_inode(&mut inode).unwrap();
+
+        let mut fs = reopen(fs);
+        let inode = fs.get_inode(inode.id).unwrap();
+        let mut expected = vec![0; inode.size as usize];
+        expected[..1500].fill(7);
+        expected[10 * block_size as usize..].fill(8);
+        assert_eq!(read_all(&mut fs, &inode), expected);
+
+        // only the two blocks the shrink kept and the one written past the hole take space
+        assert_eq!(inode.block_count, 3);
+        assert_eq!(
+            inode.allocated_sectors(block_size as u32),
+            3 * block_size / 512
+        );
+    }
+
+    #[test]
+    fn symlink_targets_round_trip() {
+        let mut fs = memory_fs(1);
+        let free_blocks = fs.super_block.free_blocks;
+        let block_size = fs.super_block.block_size as usize;
+        // kept in the inode, in two data blocks, and too long for the direct pointers
+        let targets = [
+            b"../a".to_vec(),
+            vec![b'a'; block_size + 1],
+            vec![b'a'; block_size * DIRECT_POINTERS + 1],
+        ];
+        let mut ids = vec![];
+
+        for target in &targets[..2] {
+            let mut inode = Inode::new(fs.next_inode_id(), FileType::Symlink, 0o777, 0, 0, 0);
+            fs.write_symlink_target(&mut inode, target).unwrap();
+            fs.write_inode(&mut inode).unwrap();
+            ids.push(inode.id);
+        }
+
+        let mut inode = Inode::new(fs.next_inode_id(), FileType::Symlink, 0o777, 0, 0, 0);
+        assert_eq!(
+            fs.write_symlink_target(&mut inode, &targets[2]),
+            Err(ENAMETOOLONG)
+        );
+        assert_eq!(fs.super_block.free_blocks, free_blocks - 2);
+
+        let mut fs = reopen(fs);
+
+        for (id, target) in ids.iter().zip(&targets) {
+            let inode = fs.get_inode(*id).unwrap();
+            assert_eq!(inode.size, target.len() as u64);
+            assert_eq!(&fs.read_symlink_target(&inode).unwrap(), target);
+        }
+
+        assert!(fs.get_inode(ids[0]).unwrap().is_fast_symlink());
+
+        for id in ids {
+            fs.delete_inode(id).unwrap();
         }
+
+        assert_eq!(fs.super_block.free_blocks, free_blocks);
+    }
+
+    #[test]
+    fn bitmaps_reload_after_being_dropped() {
+        let mut fs = memory_fs(2);
+        let directory = add_directory(&mut fs, 1, "a");
+        let block_id = fs.allocate_data_block().unwrap();
+        fs.commit().unwrap();
+
+        // clean groups are only kept as long as the cache has room
+        assert!(fs.block_groups.values().all(|group| !group.dirty));
+        fs.block_groups.clear();
+
+        assert!(fs.inode_exists(directory.id));
+        assert!(fs.is_data_block_used(block_id));
+        assert_ne!(fs.next_free_data_block(), block_id);
+        assert_ne!(fs.next_inode_id(), directory.id);
     }
 }
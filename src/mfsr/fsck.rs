@@ -0,0 +1,1023 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fmt,
+};
+
+use anyhow::{anyhow, Result};
+use fuser::{FileType, FUSE_ROOT_ID};
+
+use crate::types::{
+    directory_entry::DirectoryEntry,
+    extended_attributes::XattrValue,
+    inode::{Inode, INODE_SIZE},
+};
+
+use super::{Mfsr, CHANGE_JOURNAL_BLOCKS, INDIRECTION_LEVELS};
+
+const LOST_AND_FOUND: &str = "lost+found";
+// owner recorded for the journal blocks, which belong to no inode
+const JOURNAL_OWNER: u64 = 0;
+const NOT_IN_USE: &str = "not in use";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // the inode can't be trusted and gets cleared, entries naming it are reported on their own
+    BadInode {
+        inode: u64,
+        reason: &'static str,
+    },
+    // the entries of a directory can't be read, its children end up as orphans
+    BadDirectory {
+        inode: u64,
+    },
+    DanglingEntry {
+        parent: u64,
+        name: String,
+        inode: u64,
+    },
+    // directories can only have a single parent
+    ExtraDirectoryLink {
+        parent: u64,
+        name: String,
+        inode: u64,
+    },
+    // in use but not reachable from the root, it gets reconnected under lost+found
+    Orphan {
+        inode: u64,
+    },
+    LinkCount {
+        inode: u64,
+        expected: u32,
+        found: u32,
+    },
+    BlockCount {
+        inode: u64,
+        expected: u64,
+        found: u64,
+    },
+    // a block holding metadata claimed more than once, there's no telling which owner is right
+    DuplicateBlock {
+        block: u64,
+        owners: Vec<u64>,
+    },
+    SharedBlocksTable,
+    SharedCount {
+        block: u64,
+        expected: u32,
+        found: u32,
+    },
+    BlockBitmap {
+        group: usize,
+        unmarked: u32,
+        unused: u32,
+    },
+    InodeBitmap {
+        group: usize,
+        unmarked: u32,
+        unused: u32,
+    },
+    FreeBlocks {
+        expected: u64,
+        found: u64,
+    },
+    FreeInodes {
+        expected: u64,
+        found: u64,
+    },
+    // the primary super block was unusable or passed over for a backup
+    BackupSuperBlock {
+        group: u64,
+    },
+}
+
+impl Problem {
+    pub fn repairable(&self) -> bool {
+        !matches!(self, Problem::DuplicateBlock { .. })
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadInode { inode, reason } => write!(f, "Inode {}: {}", inode, reason),
+            Problem::BadDirectory { inode } => {
+                write!(f, "Directory {} has unreadable entries", inode)
+            }
+            Problem::DanglingEntry {
+                parent,
+                name,
+                inode,
+            } => write!(
+                f,
+                "Entry '{}' in directory {} points to unusable inode {}",
+                name, parent, inode
+            ),
+            Problem::ExtraDirectoryLink {
+                parent,
+                name,
+                inode,
+            } => write!(
+                f,
+                "Entry '{}' in directory {} is an extra link to directory {}",
+                name, parent, inode
+            ),
+            Problem::Orphan { inode } => {
+                write!(f, "Inode {} is in use but not linked anywhere", inode)
+            }
+            Problem::LinkCount {
+                inode,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Inode {} has {} links, should have {}",
+                inode, found, expected
+            ),
+            Problem::BlockCount {
+                inode,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Inode {} counts {} blocks, should count {}",
+                inode, found, expected
+            ),
+            Problem::DuplicateBlock { block, owners } => {
+                let owners: Vec<String> = owners
+                    .iter()
+                    .map(|owner| match *owner {
+                        JOURNAL_OWNER => "the journal".to_string(),
+                        inode => format!("inode {}", inode),
+                    })
+                    .collect();
+                write!(f, "Block {} is claimed by {}", block, owners.join(", "))
+            }
+            Problem::SharedBlocksTable => write!(f, "The shared blocks table can't be read"),
+            Problem::SharedCount {
+                block,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Block {} has {} extra references, should have {}",
+                block, found, expected
+            ),
+            Problem::BlockBitmap {
+                group,
+                unmarked,
+                unused,
+            } => write!(
+                f,
+                "Block group {}: {} blocks in use are marked free, {} unused blocks are marked in use",
+                group, unmarked, unused
+            ),
+            Problem::InodeBitmap {
+                group,
+                unmarked,
+                unused,
+            } => write!(
+                f,
+                "Block group {}: {} inodes in use are marked free, {} unused inodes are marked in use",
+                group, unmarked, unused
+            ),
+            Problem::FreeBlocks { expected, found } => write!(
+                f,
+                "Super block counts {} free blocks, should be {}",
+                found, expected
+            ),
+            Problem::FreeInodes { expected, found } => write!(
+                f,
+                "Super block counts {} free inodes, should be {}",
+                found, expected
+            ),
+            Problem::BackupSuperBlock { group } => write!(
+                f,
+                "Using the super block backup from block group {}, the primary copy needs rewriting",
+                group
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    // everything the check found
+    pub problems: Vec<Problem>,
+    // what's left after repairing, the same as problems when only checking
+    pub remaining: Vec<Problem>,
+}
+
+#[derive(Debug, Default)]
+struct Scan {
+    problems: Vec<Problem>,
+    // inodes in use, the ones reachable from the root and the orphans
+    inodes: BTreeMap<u64, Inode>,
+    bad_inodes: BTreeSet<u64>,
+    // entries naming each inode and child directories of each directory
+    links: BTreeMap<u64, u32>,
+    subdirs: BTreeMap<u64, u32>,
+    // blocks each inode should have in its block_count
+    block_counts: BTreeMap<u64, u64>,
+    // owners of every referenced block, once per reference. File contents can be shared between
+    // files, metadata blocks have a single owner
+    data_refs: BTreeMap<u64, Vec<u64>>,
+    meta_refs: BTreeMap<u64, Vec<u64>>,
+    // orphans that aren't named by another orphaned directory
+    orphans: Vec<u64>,
+}
+
+impl Scan {
+    fn add(&mut self, inode: Inode, blocks: InodeBlocks) {
+        self.block_counts.insert(inode.id, blocks.counted);
+
+        for block in blocks.data {
+            self.data_refs.entry(block).or_default().push(inode.id);
+        }
+
+        for block in blocks.meta {
+            self.meta_refs.entry(block).or_default().push(inode.id);
+        }
+
+        self.inodes.insert(inode.id, inode);
+    }
+
+    fn expected_links(&self, inode: &Inode) -> u32 {
+        // "." and the parent's entry, plus the ".." of every subdirectory
+        if inode.kind == FileType::Directory {
+            return 2 + self.subdirs.get(&inode.id).copied().unwrap_or(0);
+        }
+
+        self.links.get(&inode.id).copied().unwrap_or(0)
+    }
+
+    fn is_referenced(&self, block: u64) -> bool {
+        self.data_refs.contains_key(&block) || self.meta_refs.contains_key(&block)
+    }
+}
+
+// Blocks referenced by a single inode
+#[derive(Debug, Default)]
+struct InodeBlocks {
+    data: Vec<u64>,
+    meta: Vec<u64>,
+    // how many of them are part of the block_count, extended attribute blocks aren't
+    counted: u64,
+}
+
+impl InodeBlocks {
+    fn push(&mut self, block_id: u64, shareable: bool) {
+        if shareable {
+            self.data.push(block_id);
+        } else {
+            self.meta.push(block_id);
+        }
+    }
+}
+
+impl Mfsr {
+    // Checks the whole image, fixing what it can when asked to repair
+    pub fn fsck(&mut self, repair: bool) -> Result<FsckReport> {
+        if repair && self.read_only() {
+            return Err(anyhow!(
+                "The filesystem can only be opened read-only, it can't be repaired"
+            ));
+        }
+
+        let scan = self.scan();
+        let problems = scan.problems.clone();
+
+        if !repair {
+            return Ok(FsckReport {
+                remaining: problems.clone(),
+                problems,
+            });
+        }
+
+        if problems.iter().any(Problem::repairable) {
+            self.repair(scan)?;
+        }
+
+        let remaining = self.scan().problems;
+
+        // only a filesystem that came out clean counts as checked
+        if remaining.is_empty() {
+            self.super_block.record_check();
+            self.close()?;
+        }
+
+        Ok(FsckReport {
+            problems,
+            remaining,
+        })
+    }
+
+    fn scan(&mut self) -> Scan {
+        let mut scan = Scan::default();
+
+        if let Some(group) = self.backup_group {
+            scan.problems.push(Problem::BackupSuperBlock { group });
+        }
+
+        let shared_blocks_inode = self.super_block.shared_blocks_inode;
+        self.shared_blocks.clear();
+
+        // the shared blocks file has no name, only the super block points at it
+        if shared_blocks_inode != 0 {
+            match self.check_inode(shared_blocks_inode) {
+                Ok((inode, blocks)) => {
+                    let readable = inode.size
+                        <= blocks.counted * self.super_block.block_size as u64
+                        && self.load_shared_blocks().is_ok();
+
+                    if !readable {
+                        self.shared_blocks.clear();
+                        scan.problems.push(Problem::SharedBlocksTable);
+                    }
+
+                    scan.add(inode, blocks);
+                }
+                Err(reason) => {
+                    scan.bad_inodes.insert(shared_blocks_inode);
+                    scan.problems.push(Problem::BadInode {
+                        inode: shared_blocks_inode,
+                        reason,
+                    });
+                }
+            }
+        }
+
+        match self.check_inode(FUSE_ROOT_ID) {
+            Ok((inode, blocks)) => {
+                scan.add(inode, blocks);
+                self.walk(&mut scan, FUSE_ROOT_ID);
+            }
+            Err(reason) => {
+                scan.bad_inodes.insert(FUSE_ROOT_ID);
+                scan.problems.push(Problem::BadInode {
+                    inode: FUSE_ROOT_ID,
+                    reason,
+                });
+            }
+        }
+
+        self.find_orphans(&mut scan);
+
+        let journal_start = self.super_block.journal_start;
+
+        for block in journal_start..journal_start + self.super_block.journal_blocks as u64 {
+            scan.meta_refs.entry(block).or_default().push(JOURNAL_OWNER);
+        }
+
+        self.check_counts(&mut scan);
+        self.check_blocks(&mut scan);
+        self.check_bitmaps(&mut scan);
+
+        scan
+    }
+
+    // Reads an inode and everything it points at without trusting any of it
+    fn check_inode(&mut self, inode_id: u64) -> Result<(Inode, InodeBlocks), &'static str> {
+        if !self.valid_inode_id(inode_id) || !self.inode_exists(inode_id) {
+            return Err(NOT_IN_USE);
+        }
+
+        let mut buf = vec![0; INODE_SIZE];
+        self.read_bytes(self.inode_table_offset(inode_id), &mut buf)
+            .map_err(|_| "unreadable")?;
+        let inode = Inode::decode(&buf).map_err(|_| "bad checksum")?;
+
+        if inode.id != inode_id {
+            return Err("holds another inode's id");
+        }
+
+        let blocks = self.inode_blocks(&inode)?;
+
+        Ok((inode, blocks))
+    }
+
+    fn check_block(&self, block_id: u64) -> Result<(), &'static str> {
+        let data_blocks =
+            self.super_block.data_blocks_per_group * self.super_block.block_group_count;
+
+        if block_id == 0 || block_id > data_blocks {
+            return Err("points outside the filesystem");
+        }
+
+        Ok(())
+    }
+
+    fn inode_blocks(&mut self, inode: &Inode) -> Result<InodeBlocks, &'static str> {
+        let mut blocks = InodeBlocks::default();
+        // only regular file contents can be shared by reflinks
+        let shareable =
+            inode.kind == FileType::RegularFile && inode.id != self.super_block.shared_blocks_inode;
+
+        if inode.uses_extents() {
+            if inode.indirect_pointer != 0 {
+                self.check_block(inode.indirect_pointer)?;
+                blocks.meta.push(inode.indirect_pointer);
+
+                for leaf in self
+                    .extent_leaves(inode)
+                    .map_err(|_| "unreadable extent index")?
+                {
+                    self.check_block(leaf)?;
+                    blocks.meta.push(leaf);
+                }
+            }
+
+            let map = self
+                .read_extents(inode)
+                .map_err(|_| "unreadable extent map")?;
+
+            for extent in map.extents.iter().filter(|e| e.len != 0) {
+                self.check_block(extent.start)?;
+                let last = extent
+                    .start
+                    .checked_add(extent.len - 1)
+                    .ok_or("bad extent")?;
+                self.check_block(last)?;
+
+                for block_id in extent.start..=last {
+                    blocks.push(block_id, shareable);
+                }
+            }
+        } else if !inode.is_fast_symlink() {
+            for pointer in inode.direct_pointers.into_iter().filter(|p| *p != 0) {
+                self.check_block(pointer)?;
+                blocks.push(pointer, shareable);
+            }
+
+            for level in 1..=INDIRECTION_LEVELS {
+                let root = inode.indirect_root(level);
+
+                if root != 0 {
+                    self.pointer_table_blocks(root, level, shareable, &mut blocks)?;
+                }
+            }
+        }
+
+        blocks.counted = (blocks.data.len() + blocks.meta.len()) as u64;
+
+        if inode.xattr_pointer != 0 {
+            self.check_block(inode.xattr_pointer)?;
+            blocks.meta.push(inode.xattr_pointer);
+            let xattrs = self
+                .get_xattrs(inode)
+                .map_err(|_| "unreadable extended attributes")?;
+
+            for value in xattrs.entries.values() {
+                if let XattrValue::Block { block_id, .. } = value {
+                    self.check_block(*block_id)?;
+                    blocks.meta.push(*block_id);
+                }
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    fn pointer_table_blocks(
+        &mut self,
+        table: u64,
+        level: u32,
+        shareable: bool,
+        blocks: &mut InodeBlocks,
+    ) -> Result<(), &'static str> {
+        self.check_block(table)?;
+        blocks.meta.push(table);
+
+        for index in 0..self.pointers_per_block() {
+            let pointer = self
+                .read_pointer(table, index)
+                .map_err(|_| "unreadable pointer table")?;
+
+            if pointer == 0 {
+                continue;
+            }
+
+            if level > 1 {
+                self.pointer_table_blocks(pointer, level - 1, shareable, blocks)?;
+                continue;
+            }
+
+            self.check_block(pointer)?;
+            blocks.push(pointer, shareable);
+        }
+
+        Ok(())
+    }
+
+    // Follows the directory tree down from `start`, which must already be part of the scan
+    fn walk(&mut self, scan: &mut Scan, start: u64) {
+        let mut pending = vec![start];
+
+        while let Some(parent) = pending.pop() {
+            let dentry = match self.get_dentry(&scan.inodes[&parent].clone()) {
+                Ok(d) => d,
+                Err(_) => {
+                    scan.problems.push(Problem::BadDirectory { inode: parent });
+                    continue;
+                }
+            };
+
+            for (name, inode_id) in dentry.entries {
+                if let Some(inode) = scan.inodes.get(&inode_id) {
+                    if inode.kind == FileType::Directory {
+                        scan.problems.push(Problem::ExtraDirectoryLink {
+                            parent,
+                            name,
+                            inode: inode_id,
+                        });
+                    } else if inode_id == self.super_block.shared_blocks_inode {
+                        scan.problems.push(Problem::DanglingEntry {
+                            parent,
+                            name,
+                            inode: inode_id,
+                        });
+                    } else {
+                        *scan.links.entry(inode_id).or_default() += 1;
+                    }
+
+                    continue;
+                }
+
+                let checked = if scan.bad_inodes.contains(&inode_id) {
+                    // already reported when another entry led to it
+                    Err(NOT_IN_USE)
+                } else {
+                    self.check_inode(inode_id)
+                };
+
+                match checked {
+                    Ok((inode, blocks)) => {
+                        if inode.kind == FileType::Directory {
+                            *scan.subdirs.entry(parent).or_default() += 1;
+                            pending.push(inode_id);
+                        }
+
+                        scan.links.insert(inode_id, 1);
+                        scan.add(inode, blocks);
+                    }
+                    Err(reason) => {
+                        // entries naming free inodes are only stale, there's no inode to clear
+                        if reason != NOT_IN_USE {
+                            scan.bad_inodes.insert(inode_id);
+                            scan.problems.push(Problem::BadInode {
+                                inode: inode_id,
+                                reason,
+                            });
+                        }
+
+                        scan.problems.push(Problem::DanglingEntry {
+                            parent,
+                            name,
+                            inode: inode_id,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn find_orphans(&mut self, scan: &mut Scan) {
+        let mut candidates = BTreeMap::new();
+        let inode_count = self.super_block.block_group_count * self.super_block.inodes_per_group;
+
+        for inode_id in 1..=inode_count {
+            if !self.inode_exists(inode_id)
+                || scan.inodes.contains_key(&inode_id)
+                || scan.bad_inodes.contains(&inode_id)
+            {
+                continue;
+            }
+
+            match self.check_inode(inode_id) {
+                Ok(checked) => {
+                    candidates.insert(inode_id, checked);
+                }
+                Err(reason) => {
+                    scan.bad_inodes.insert(inode_id);
+                    scan.problems.push(Problem::BadInode {
+                        inode: inode_id,
+                        reason,
+                    });
+                }
+            }
+        }
+
+        // orphans named by another orphaned directory are reconnected together with it
+        let mut claimed: BTreeSet<u64> = BTreeSet::new();
+
+        for (inode, _) in candidates.values() {
+            if inode.kind != FileType::Directory {
+                continue;
+            }
+
+            if let Ok(dentry) = self.get_dentry(inode) {
+                claimed.extend(
+                    dentry
+                        .entries
+                        .values()
+                        .filter(|id| **id != inode.id && candidates.contains_key(id)),
+                );
+            }
+        }
+
+        // directories only named by each other come after the rest
+        let (unclaimed, claimed): (Vec<u64>, Vec<u64>) =
+            candidates.keys().partition(|id| !claimed.contains(id));
+
+        for inode_id in unclaimed.into_iter().chain(claimed) {
+            if scan.inodes.contains_key(&inode_id) {
+                continue;
+            }
+
+            let (inode, blocks) = candidates.remove(&inode_id).unwrap();
+            let is_directory = inode.kind == FileType::Directory;
+            scan.problems.push(Problem::Orphan { inode: inode_id });
+            scan.orphans.push(inode_id);
+            // the entry lost+found is going to hold
+            scan.links.insert(inode_id, 1);
+            scan.add(inode, blocks);
+
+            if is_directory {
+                self.walk(scan, inode_id);
+            }
+        }
+    }
+
+    fn check_counts(&self, scan: &mut Scan) {
+        for (inode_id, inode) in &scan.inodes {
+            let expected = scan.expected_links(inode);
+
+            if *inode_id != self.super_block.shared_blocks_inode && inode.hard_links != expected {
+                scan.problems.push(Problem::LinkCount {
+                    inode: *inode_id,
+                    expected,
+                    found: inode.hard_links,
+                });
+            }
+
+            let expected = scan.block_counts[inode_id];
+
+            if inode.block_count != expected {
+                scan.problems.push(Problem::BlockCount {
+                    inode: *inode_id,
+                    expected,
+                    found: inode.block_count,
+                });
+            }
+        }
+    }
+
+    fn check_blocks(&self, scan: &mut Scan) {
+        let blocks: BTreeSet<u64> = scan
+            .data_refs
+            .keys()
+            .chain(scan.meta_refs.keys())
+            .chain(self.shared_blocks.keys())
+            .copied()
+            .collect();
+
+        for block in blocks {
+            let data = scan.data_refs.get(&block).map(Vec::as_slice).unwrap_or(&[]);
+            let meta = scan.meta_refs.get(&block).map(Vec::as_slice).unwrap_or(&[]);
+
+            if !meta.is_empty() && data.len() + meta.len() > 1 {
+                scan.problems.push(Problem::DuplicateBlock {
+                    block,
+                    owners: data.iter().chain(meta).copied().collect(),
+                });
+                continue;
+            }
+
+            // file contents referenced more than once are shared, even if nothing recorded it
+            let expected = data.len().saturating_sub(1) as u32;
+            let found = self.shared_blocks.get(&block).copied().unwrap_or(0);
+
+            if expected != found {
+                scan.problems.push(Problem::SharedCount {
+                    block,
+                    expected,
+                    found,
+                });
+            }
+        }
+    }
+
+    // The bitmaps as they should be given what the scan found in use
+    fn expected_bitmaps(&self, scan: &Scan) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let block_size = self.super_block.block_size as usize;
+        let count = self.super_block.block_group_count;
+        let mut bitmaps = vec![(vec![0u8; block_size], vec![0u8; block_size]); count as usize];
+        let data_blocks = self.super_block.data_blocks_per_group * count;
+
+        for block_id in 1..=data_blocks {
+            if scan.is_referenced(block_id) {
+                let (group, byte, bit) = self.data_block_bitmap_offset(block_id);
+                bitmaps[group].0[byte] |= 1 << bit;
+            }
+        }
+
+        for inode_id in scan.inodes.keys() {
+            let (group, byte, bit) = self.inode_bitmap_offset(*inode_id);
+            bitmaps[group].1[byte] |= 1 << bit;
+        }
+
+        bitmaps
+    }
+
+    fn check_bitmaps(&mut self, scan: &mut Scan) {
+        let count_bits = |bytes: &[u8]| bytes.iter().map(|b| b.count_ones()).sum::<u32>();
+        let differences = |expected: &[u8], found: &[u8]| {
+            let unmarked: Vec<u8> = expected.iter().zip(found).map(|(e, f)| e & !f).collect();
+            let unused: Vec<u8> = expected.iter().zip(found).map(|(e, f)| !e & f).collect();
+            (count_bits(&unmarked), count_bits(&unused))
+        };
+        let mut used_blocks = 0;
+        let mut used_inodes = 0;
+
+        for (group, (data, inodes)) in self.expected_bitmaps(scan).into_iter().enumerate() {
+            used_blocks += count_bits(&data) as u64;
+            used_inodes += count_bits(&inodes) as u64;
+
+            let (unmarked, unused) = differences(&data, &self.group(group).data_bitmap);
+
+            if unmarked != 0 || unused != 0 {
+                scan.problems.push(Problem::BlockBitmap {
+                    group,
+                    unmarked,
+                    unused,
+                });
+            }
+
+            let (unmarked, unused) = differences(&inodes, &self.group(group).inode_bitmap);
+
+            if unmarked != 0 || unused != 0 {
+                scan.problems.push(Problem::InodeBitmap {
+                    group,
+                    unmarked,
+                    unused,
+                });
+            }
+        }
+
+        let expected = self.super_block.block_count - used_blocks;
+
+        if self.super_block.free_blocks != expected {
+            scan.problems.push(Problem::FreeBlocks {
+                expected,
+                found: self.super_block.free_blocks,
+            });
+        }
+
+        let expected = self.super_block.inode_count - used_inodes;
+
+        if self.super_block.free_inodes != expected {
+            scan.problems.push(Problem::FreeInodes {
+                expected,
+                found: self.super_block.free_inodes,
+            });
+        }
+    }
+
+    fn repair(&mut self, scan: Scan) -> Result<()> {
+        // nothing allocated while repairing may land on something that's still referenced
+        let bitmaps = self.expected_bitmaps(&scan);
+
+        for (group_id, (data, inodes)) in bitmaps.into_iter().enumerate() {
+            let group = self.group(group_id);
+
+            for (byte, expected) in group.data_bitmap.iter_mut().zip(data) {
+                *byte |= expected;
+            }
+
+            for (byte, expected) in group.inode_bitmap.iter_mut().zip(inodes) {
+                *byte |= expected;
+            }
+
+            group.dirty = true;
+        }
+
+        let mut stale_entries: BTreeMap<u64, Vec<&str>> = BTreeMap::new();
+
+        for problem in &scan.problems {
+            match problem {
+                Problem::BadInode { inode, .. } => self.clear_inode(*inode),
+                Problem::BadDirectory { inode } => {
+                    self.make_room()?;
+                    let mut directory = scan.inodes[inode].clone();
+                    self.write_dentry(&mut directory, &mut DirectoryEntry::new(*inode))
+                        .map_err(|code| anyhow!("Failed to clear directory {}: {}", inode, code))?;
+                    self.write_inode(&mut directory)?;
+                }
+                Problem::DanglingEntry { parent, name, .. }
+                | Problem::ExtraDirectoryLink { parent, name, .. } => {
+                    stale_entries.entry(*parent).or_default().push(name);
+                }
+                _ => {}
+            }
+        }
+
+        for (parent, names) in stale_entries {
+            self.make_room()?;
+            let mut parent_inode = scan.inodes[&parent].clone();
+            let mut dentry = self.get_dentry(&parent_inode)?;
+
+            for name in names {
+                dentry.entries.remove(name);
+            }
+
+            self.write_dentry(&mut parent_inode, &mut dentry)
+                .map_err(|code| anyhow!("Failed to write directory {}: {}", parent, code))?;
+            self.write_inode(&mut parent_inode)?;
+        }
+
+        // a lost root is recreated empty, everything under it was picked up as an orphan
+        self.make_room()?;
+        self.create_root()?;
+
+        if !scan.orphans.is_empty() {
+            self.make_room()?;
+            let mut lost_and_found = self.lost_and_found()?;
+
+            for inode_id in &scan.orphans {
+                self.make_room()?;
+                let name = format!("#{}", inode_id);
+                self.add_entry(&mut lost_and_found, OsStr::new(&name), *inode_id)
+                    .map_err(|code| anyhow!("Failed to reconnect inode {}: {}", inode_id, code))?;
+            }
+        }
+
+        // with the tree in shape, counts and the shared blocks table follow from what
+        // references what
+        let scan = self.scan();
+
+        for inode in scan.inodes.values() {
+            let mut fixed = inode.clone();
+            fixed.block_count = scan.block_counts[&inode.id];
+
+            if inode.id != self.super_block.shared_blocks_inode {
+                fixed.hard_links = scan.expected_links(inode);
+            }
+
+            if fixed.block_count != inode.block_count || fixed.hard_links != inode.hard_links {
+                self.make_room()?;
+                self.write_inode(&mut fixed)?;
+            }
+        }
+
+        self.shared_blocks = scan
+            .data_refs
+            .iter()
+            .filter(|(block, owners)| owners.len() > 1 && !scan.meta_refs.contains_key(block))
+            .map(|(block, owners)| (*block, owners.len() as u32 - 1))
+            .collect();
+        self.store_shared_blocks()?;
+
+        let scan = self.scan();
+        let bitmaps = self.expected_bitmaps(&scan);
+
+        let count_bits = |bitmap: &[u8]| bitmap.iter().map(|b| b.count_ones() as u64).sum::<u64>();
+        let mut used_blocks = 0;
+        let mut used_inodes = 0;
+
+        for (group_id, (data, inodes)) in bitmaps.into_iter().enumerate() {
+            used_blocks += count_bits(&data);
+            used_inodes += count_bits(&inodes);
+
+            let group = self.group(group_id);
+            group.data_bitmap = data;
+            group.inode_bitmap = inodes;
+            group.dirty = true;
+        }
+
+        self.first_free_data_group = 0;
+        self.first_free_inode_group = 0;
+        self.super_block.free_blocks = self.super_block.block_count - used_blocks;
+        self.super_block.free_inodes = self.super_block.inode_count - used_inodes;
+
+        self.close()
+    }
+
+    // Every repair is logged on its own, the repairs before it are committed when the journal
+    // runs out of room
+    fn make_room(&mut self) -> Result<()> {
+        self.reserve_journal(CHANGE_JOURNAL_BLOCKS)
+            .map_err(|code| anyhow!("Failed to make room in the journal: {}", code))
+    }
+
+    // Drops an inode from the bitmap without following anything it points at, its blocks are
+    // freed when the bitmaps are rebuilt
+    fn clear_inode(&mut self, inode_id: u64) {
+        if inode_id == self.super_block.shared_blocks_inode {
+            self.super_block.shared_blocks_inode = 0;
+        }
+
+        if !self.valid_inode_id(inode_id) {
+            return;
+        }
+
+        let (group_id, byte_index, bit_index) = self.inode_bitmap_offset(inode_id);
+        let group = self.group(group_id);
+        group.inode_bitmap[byte_index] &= !(1 << bit_index);
+        group.dirty = true;
+        self.first_free_inode_group = self.first_free_inode_group.min(group_id);
+    }
+
+    fn lost_and_found(&mut self) -> Result<Inode> {
+        if let Some(inode) = self.lookup_inode(FUSE_ROOT_ID, OsStr::new(LOST_AND_FOUND)) {
+            return match inode.kind {
+                FileType::Directory => Ok(inode),
+                _ => Err(anyhow!("/{} is not a directory", LOST_AND_FOUND)),
+            };
+        }
+
+        let mut root = self
+            .get_inode(FUSE_ROOT_ID)
+            .ok_or(anyhow!("Missing root directory"))?;
+        let mut inode = Inode::new(self.next_inode_id(), FileType::Directory, 0o700, 0, 0, 0);
+        inode.hard_links = 2;
+        let mut dentry = DirectoryEntry::new(inode.id);
+        self.write_dentry(&mut inode, &mut dentry)
+            .map_err(|code| anyhow!("Failed to create /{}: {}", LOST_AND_FOUND, code))?;
+        self.write_inode(&mut inode)?;
+        root.hard_links += 1;
+        self.add_entry(&mut root, OsStr::new(LOST_AND_FOUND), inode.id)
+            .map_err(|code| anyhow!("Failed to create /{}: {}", LOST_AND_FOUND, code))?;
+
+        Ok(inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mfsr::tests::{add_directory, add_file, memory_fs, reopen};
+
+    #[test]
+    fn finds_and_repairs_damage() {
+        let mut fs = memory_fs(2);
+        let directory = add_directory(&mut fs, FUSE_ROOT_ID, "a");
+        let file = add_file(&mut fs, directory.id, "f", &[1; 3000]);
+        let other = add_file(&mut fs, FUSE_ROOT_ID, "g", &[2; 100]);
+        fs.close().unwrap();
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        // lose the directory, point an entry nowhere, miscount links and leak a block
+        let mut root = fs.get_inode(FUSE_ROOT_ID).unwrap();
+        let mut dentry = fs.get_dentry(&root).unwrap();
+        dentry.entries.remove("a");
+        dentry.entries.insert("stale".to_string(), 900);
+        fs.write_dentry(&mut root, &mut dentry).unwrap();
+        fs.write_inode(&mut root).unwrap();
+        let mut other = fs.get_inode(other.id).unwrap();
+        other.hard_links = 5;
+        fs.write_inode(&mut other).unwrap();
+        fs.allocate_data_block().unwrap();
+        fs.commit().unwrap();
+
+        let report = fs.fsck(false).unwrap();
+        let problems = &report.problems;
+        assert!(problems.contains(&Problem::Orphan {
+            inode: directory.id
+        }));
+        assert!(problems.contains(&Problem::DanglingEntry {
+            parent: FUSE_ROOT_ID,
+            name: "stale".to_string(),
+            inode: 900
+        }));
+        assert!(problems.contains(&Problem::LinkCount {
+            inode: other.id,
+            expected: 1,
+            found: 5
+        }));
+        assert!(problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::BlockBitmap { unused: 1, .. })));
+        assert_eq!(report.remaining, report.problems);
+
+        let report = fs.fsck(true).unwrap();
+        assert!(report.remaining.is_empty(), "{:?}", report.remaining);
+
+        let mut fs = reopen(fs);
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        let lost_and_found = fs
+            .lookup_inode(FUSE_ROOT_ID, OsStr::new(LOST_AND_FOUND))
+            .unwrap();
+        let name = format!("#{}", directory.id);
+        let found = fs
+            .lookup_inode(lost_and_found.id, OsStr::new(&name))
+            .unwrap();
+        assert_eq!(found.id, directory.id);
+        assert_eq!(
+            fs.lookup_inode(directory.id, OsStr::new("f")).unwrap().id,
+            file.id
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use std::{collections::BTreeMap, mem::size_of};
+
+use anyhow::{anyhow, Result};
+use fuser::FileType;
+
+use crate::{
+    types::{
+        block_group::BlockGroup,
+        extended_attributes::XattrValue,
+        extent::{Extent, ExtentMap},
+        inode::Inode,
+        super_block::FsState,
+    },
+    utils::{bytes_to_u64, max_block_group_count, u64_to_bytes},
+};
+
+use super::{Mfsr, INDIRECTION_LEVELS};
+
+impl Mfsr {
+    // Grows or shrinks the filesystem to the block groups that fit in `size` bytes, returns how
+    // many that is. Shrinking moves the inodes and blocks of the groups going away into the ones
+    // that stay first
+    pub fn resize(&mut self, size: u64) -> Result<u64> {
+        self.check_unmounted()?;
+
+        let block_size = self.super_block.block_size;
+        let inodes_per_group = self.super_block.inodes_per_group;
+        let count = size / self.super_block.group_size();
+        let current = self.super_block.block_group_count;
+
+        if count == 0 {
+            return Err(anyhow!("The size is too small for a single block group"));
+        }
+
+        if count > max_block_group_count(block_size, inodes_per_group) {
+            return Err(anyhow!(
+                "At most {} block groups of {} byte blocks can be addressed",
+                max_block_group_count(block_size, inodes_per_group),
+                block_size
+            ));
+        }
+
+        if self.device.len() < count * self.super_block.group_size() {
+            return Err(anyhow!(
+                "The device is smaller than the filesystem, {} bytes for {}",
+                self.device.len(),
+                count * self.super_block.group_size()
+            ));
+        }
+
+        // every transaction logs the bitmaps of all groups, and the journal can't move out of
+        // the first group to grow
+        let min_journal_blocks = 2 * count + 64;
+
+        if self.super_block.journal_blocks != 0
+            && (self.super_block.journal_blocks as u64) < min_journal_blocks
+        {
+            return Err(anyhow!(
+                "The journal needs at least {} blocks for {} block groups, it has {}",
+                min_journal_blocks,
+                count,
+                self.super_block.journal_blocks
+            ));
+        }
+
+        // the shared blocks table is written back on close and may have to move
+        self.load_shared_blocks()?;
+
+        if count > current {
+            let empty_bitmap = vec![0; block_size as usize];
+
+            // what's on the device past the old end is garbage, so the new groups can't be loaded
+            for group_id in current..count {
+                let mut group = BlockGroup::new(empty_bitmap.clone(), empty_bitmap.clone());
+                group.dirty = true;
+                self.block_groups.insert(group_id as usize, group);
+            }
+        } else if count < current {
+            // the evacuation goes out as one transaction, so a failed one is dropped whole and the
+            // bitmaps are read back as they were. Without a journal it has already reached the
+            // disk and is left for fsck
+            self.commit()?;
+            let super_block = self.super_block.clone();
+
+            if let Err(e) = self.evacuate_groups(count as usize) {
+                if self.super_block.journal_blocks == 0 {
+                    self.super_block.state = FsState::Dirty;
+                    self.close()?;
+
+                    return Err(anyhow!("{}, run `mfsr fsck --repair`", e));
+                }
+
+                self.transaction.clear();
+                self.block_groups.clear();
+                self.first_free_data_group = 0;
+                self.first_free_inode_group = 0;
+                self.super_block = super_block;
+
+                return Err(e);
+            }
+
+            self.block_groups
+                .retain(|&group_id, _| group_id < count as usize);
+        }
+
+        let super_block = &mut self.super_block;
+        let block_count = super_block.data_blocks_per_group * count;
+        // the same share of the blocks stays reserved
+        super_block.reserved_blocks = (super_block.reserved_blocks as u128 * block_count as u128
+            / super_block.block_count as u128) as u64;
+        super_block.block_group_count = count;
+        super_block.block_count = block_count;
+        super_block.inode_count = inodes_per_group * count;
+
+        let count_bits = |bitmap: &[u8]| bitmap.iter().map(|b| b.count_ones() as u64).sum::<u64>();
+        let mut used_blocks = 0;
+        let mut used_inodes = 0;
+
+        for group_id in 0..count as usize {
+            let group = self.group(group_id);
+            used_blocks += count_bits(&group.data_bitmap);
+            used_inodes += count_bits(&group.inode_bitmap);
+        }
+
+        self.super_block.free_blocks = self.super_block.block_count - used_blocks;
+        self.super_block.free_inodes = self.super_block.inode_count - used_inodes;
+
+        self.close()?;
+
+        Ok(count)
+    }
+
+    // Moves everything in use in the groups from `first` on to the groups before it and points
+    // every reference at the new places. The groups left behind are only dropped by the caller
+    fn evacuate_groups(&mut self, first: usize) -> Result<()> {
+        let data_blocks_per_group = self.super_block.data_blocks_per_group;
+        let inodes_per_group = self.super_block.inodes_per_group;
+        let count = self.super_block.block_group_count as usize;
+        let mut blocks = vec![];
+        let mut inodes = vec![];
+
+        for group_id in first..count {
+            let group = self.group(group_id);
+            let group_id = group_id as u64;
+
+            for index in 0..data_blocks_per_group {
+                if group.data_bitmap[index as usize / 8] >> (index % 8) & 1 != 0 {
+                    blocks.push(group_id * data_blocks_per_group + index + 1);
+                }
+            }
+
+            for index in 0..inodes_per_group {
+                if group.inode_bitmap[index as usize / 8] >> (index % 8) & 1 != 0 {
+                    inodes.push(group_id * inodes_per_group + index + 1);
+                }
+            }
+        }
+
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+
+        for group_id in 0..first {
+            let group = self.group(group_id);
+            free_blocks += group
+                .data_bitmap
+                .iter()
+                .map(|b| b.count_zeros() as u64)
+                .sum::<u64>();
+            // the inode bitmap may have more bits than the group has inodes
+            free_inodes += (0..inodes_per_group)
+                .filter(|index| group.inode_bitmap[*index as usize / 8] >> (index % 8) & 1 == 0)
+                .count() as u64;
+        }
+
+        if free_blocks < blocks.len() as u64 {
+            return Err(anyhow!(
+                "{} blocks are in use past the new end, only {} are free before it",
+                blocks.len(),
+                free_blocks
+            ));
+        }
+
+        if free_inodes < inodes.len() as u64 {
+            return Err(anyhow!(
+                "{} inodes are in use past the new end, only {} are free before it",
+                inodes.len(),
+                free_inodes
+            ));
+        }
+
+        // nothing allocated from here on may land in a group that's going away. Being dirty keeps
+        // them loaded until this is logged
+        for group_id in first..count {
+            let group = self.group(group_id);
+            group.data_bitmap.fill(0xff);
+            group.inode_bitmap.fill(0xff);
+            group.dirty = true;
+        }
+
+        let mut moved_blocks = BTreeMap::new();
+        let mut buf = vec![0; self.super_block.block_size as usize];
+        let mut goal = 0;
+
+        for block_id in blocks {
+            // consecutive blocks stay consecutive when there's room for it
+            let (new_block_id, _) = self
+                .allocate_contiguous(goal, 1)
+                .map_err(|code| anyhow!("Failed to move block {}: {}", block_id, code))?;
+            self.read_data(block_id, &mut buf)?;
+            self.write_file_data_at(new_block_id, 0, &buf)?;
+            moved_blocks.insert(block_id, new_block_id);
+            goal = new_block_id + 1;
+        }
+
+        let mut moved_inodes = BTreeMap::new();
+
+        for inode_id in inodes {
+            let mut inode = self
+                .get_inode(inode_id)
+                .ok_or(anyhow!("Inode {} can't be read", inode_id))?;
+            inode.id = self.next_inode_id();
+            self.write_inode(&mut inode)?;
+            moved_inodes.insert(inode_id, inode.id);
+        }
+
+        for inode_id in 1..=first as u64 * inodes_per_group {
+            if !self.inode_exists(inode_id) {
+                continue;
+            }
+
+            let mut inode = self
+                .get_inode(inode_id)
+                .ok_or(anyhow!("Inode {} can't be read", inode_id))?;
+
+            if !moved_blocks.is_empty() {
+                self.move_block_references(&mut inode, &moved_blocks)?;
+            }
+
+            if inode.kind == FileType::Directory && !moved_inodes.is_empty() {
+                self.move_inode_references(&mut inode, &moved_inodes)?;
+            }
+
+            self.write_inode(&mut inode)?;
+        }
+
+        let moved = |id: u64| moved_blocks.get(&id).copied().unwrap_or(id);
+        self.shared_blocks = std::mem::take(&mut self.shared_blocks)
+            .into_iter()
+            .map(|(block_id, references)| (moved(block_id), references))
+            .collect();
+        self.shared_blocks_dirty = true;
+
+        let shared_blocks_inode = self.super_block.shared_blocks_inode;
+
+        if let Some(&inode_id) = moved_inodes.get(&shared_blocks_inode) {
+            self.super_block.shared_blocks_inode = inode_id;
+        }
+
+        Ok(())
+    }
+
+    // Points an inode at the new places of the blocks in `moved`, including the ones its pointer
+    // tables, extent leaves and extended attributes name
+    fn move_block_references(
+        &mut self,
+        inode: &mut Inode,
+        moved: &BTreeMap<u64, u64>,
+    ) -> Result<()> {
+        let new_place = |block_id: u64| moved.get(&block_id).copied().unwrap_or(block_id);
+
+        if inode.uses_extents() {
+            if inode.indirect_pointer != 0 {
+                inode.indirect_pointer = new_place(inode.indirect_pointer);
+                self.move_table_references(inode.indirect_pointer, 1, moved)?;
+            }
+
+            let map = self.read_extents(inode)?;
+            let mut relocated = ExtentMap::default();
+
+            for extent in &map.extents {
+                if moved
+                    .range(extent.start..extent.start + extent.len)
+                    .next()
+                    .is_none()
+                {
+                    relocated.insert(*extent);
+                    continue;
+                }
+
+                // a run that moved may have been split up on the way
+                let mut offset = 0;
+
+                while offset < extent.len {
+                    let start = new_place(extent.start + offset);
+                    let mut len = 1;
+
+                    while offset + len < extent.len
+                        && new_place(extent.start + offset + len) == start + len
+                    {
+                        len += 1;
+                    }
+
+                    relocated.insert(Extent::new(extent.logical + offset, start, len));
+                    offset += len;
+                }
+            }
+
+            if relocated != map {
+                self.write_extents(inode, &relocated)
+                    .map_err(|code| anyhow!("Failed to write extents: {}", code))?;
+            }
+        } else if !inode.is_fast_symlink() {
+            for pointer in inode.direct_pointers.iter_mut() {
+                *pointer = new_place(*pointer);
+            }
+
+            for level in 1..=INDIRECTION_LEVELS {
+                let root = new_place(inode.indirect_root(level));
+
+                if root != 0 {
+                    inode.set_indirect_root(level, root);
+                    self.move_table_references(root, level, moved)?;
+                }
+            }
+        }
+
+        if inode.xattr_pointer != 0 {
+            inode.xattr_pointer = new_place(inode.xattr_pointer);
+            let mut xattrs = self.get_xattrs(inode)?;
+            let mut changed = false;
+
+            for value in xattrs.entries.values_mut() {
+                if let XattrValue::Block { block_id, .. } = value {
+                    changed |= moved.contains_key(block_id);
+                    *block_id = new_place(*block_id);
+                }
+            }
+
+            if changed {
+                self.write_xattrs(inode, &mut xattrs)
+                    .map_err(|code| anyhow!("Failed to write extended attributes: {}", code))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Rewrites the pointers of a pointer table, or an extent index at level 1, and of the tables
+    // below it
+    fn move_table_references(
+        &mut self,
+        table: u64,
+        level: u32,
+        moved: &BTreeMap<u64, u64>,
+    ) -> Result<()> {
+        let mut buf = vec![0; self.super_block.block_size as usize];
+        self.read_data(table, &mut buf)?;
+        let mut changed = false;
+
+        for chunk in buf.chunks_mut(size_of::<u64>()) {
+            let pointer = bytes_to_u64(chunk.try_into()?);
+
+            if pointer == 0 {
+                continue;
+            }
+
+            let new_pointer = moved.get(&pointer).copied().unwrap_or(pointer);
+
+            if new_pointer != pointer {
+                chunk.copy_from_slice(&u64_to_bytes(new_pointer));
+                changed = true;
+            }
+
+            if level > 1 {
+                self.move_table_references(new_pointer, level - 1, moved)?;
+            }
+        }
+
+        if changed {
+            self.write_data(table, &buf)?;
+        }
+
+        Ok(())
+    }
+
+    // Renames the inodes in `moved` in the entries of a directory
+    fn move_inode_references(
+        &mut self,
+        directory: &mut Inode,
+        moved: &BTreeMap<u64, u64>,
+    ) -> Result<()> {
+        let mut dentry = self.get_dentry(directory)?;
+        let mut changed = dentry.inode_id != directory.id;
+        dentry.inode_id = directory.id;
+
+        for inode_id in dentry.entries.values_mut() {
+            if let Some(&new_id) = moved.get(inode_id) {
+                *inode_id = new_id;
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.write_dentry(directory, &mut dentry)
+                .map_err(|code| anyhow!("Failed to write directory {}: {}", directory.id, code))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use fuser::FUSE_ROOT_ID;
+
+    use super::*;
+    use crate::{
+        cli::{format, FormatOptions},
+        device::MemoryDevice,
+        mfsr::{
+            tests::{add_directory, add_file, memory_device, memory_fs, read_all, reopen},
+            MountOptions,
+        },
+        utils::get_block_group_size,
+    };
+
+    #[test]
+    fn grows_into_the_rest_of_the_device() {
+        let device = memory_device(3, 2);
+        let mut fs = Mfsr::from_device(Box::new(device), MountOptions::default()).unwrap();
+        let free_blocks = fs.super_block.free_blocks;
+        let group_size = fs.super_block.group_size();
+
+        assert_eq!(fs.resize(group_size * 3).unwrap(), 3);
+        assert_eq!(
+            fs.super_block.free_blocks,
+            free_blocks + fs.super_block.data_blocks_per_group
+        );
+
+        let mut fs = reopen(fs);
+        assert_eq!(fs.super_block.block_group_count, 3);
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn shrinking_moves_what_is_in_the_last_groups() {
+        let mut fs = memory_fs(3);
+        let group_size = fs.super_block.group_size();
+        let data: Vec<u8> = (0..5000).map(|i| (i % 253) as u8).collect();
+
+        // fill the first groups for a while, so the files land in the last one
+        let saved: Vec<_> = (0..2)
+            .map(|group_id| {
+                let group = fs.group(group_id);
+                let saved = (group.data_bitmap.clone(), group.inode_bitmap.clone());
+                group.data_bitmap.fill(0xff);
+                group.inode_bitmap.fill(0xff);
+                group.dirty = true;
+                saved
+            })
+            .collect();
+        let directory = add_directory(&mut fs, FUSE_ROOT_ID, "a");
+        let file = add_file(&mut fs, directory.id, "f", &data);
+
+        for (group_id, (data_bitmap, inode_bitmap)) in saved.into_iter().enumerate() {
+            let group = fs.group(group_id);
+            group.data_bitmap = data_bitmap;
+            group.inode_bitmap = inode_bitmap;
+        }
+
+        fs.first_free_data_group = 0;
+        fs.first_free_inode_group = 0;
+
+        let inodes_per_group = fs.super_block.inodes_per_group;
+        assert!(directory.id > 2 * inodes_per_group && file.id > 2 * inodes_per_group);
+
+        assert_eq!(fs.resize(group_size * 2).unwrap(), 2);
+
+        let mut fs = reopen(fs);
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        let directory = fs.lookup_inode(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+        let file = fs.lookup_inode(directory.id, OsStr::new("f")).unwrap();
+        assert!(file.id <= 2 * inodes_per_group);
+        assert_eq!(read_all(&mut fs, &file), data);
+    }
+
+    #[test]
+    fn failed_shrink_changes_nothing() {
+        // the smallest journal three groups can have
+        let options = FormatOptions {
+            block_size: 1024,
+            bytes_per_inode: 1024,
+            reserved_percent: 0,
+            journal_blocks: Some(2 * 3 + 64),
+            ..Default::default()
+        };
+        let group_size = get_block_group_size(1024, 8192);
+        let mut device = MemoryDevice::new(group_size * 3).unwrap();
+        format(&mut device, group_size * 3, &options).unwrap();
+        let mut fs = Mfsr::from_device(Box::new(device), MountOptions::default()).unwrap();
+
+        let saved: Vec<_> = (0..2)
+            .map(|group_id| {
+                let group = fs.group(group_id);
+                let saved = group.inode_bitmap.clone();
+                group.inode_bitmap.fill(0xff);
+                group.dirty = true;
+                saved
+            })
+            .collect();
+
+        // more inodes in the last group than the journal has room for once they move
+        for i in 0..400 {
+            add_file(&mut fs, FUSE_ROOT_ID, &i.to_string(), &[i as u8; 10]);
+            fs.commit().unwrap();
+        }
+
+        // no inode is free before the new end yet
+        assert!(fs.resize(group_size * 2).is_err());
+
+        for (group_id, inode_bitmap) in saved.into_iter().enumerate() {
+            let group = fs.group(group_id);
+            group.inode_bitmap = inode_bitmap;
+            group.dirty = true;
+        }
+
+        fs.first_free_inode_group = 0;
+        fs.close().unwrap();
+        let (free_blocks, free_inodes) = (fs.super_block.free_blocks, fs.super_block.free_inodes);
+
+        assert!(fs.resize(group_size * 2).is_err());
+        assert_eq!(fs.super_block.block_group_count, 3);
+        assert_eq!(fs.super_block.free_blocks, free_blocks);
+        assert_eq!(fs.super_block.free_inodes, free_inodes);
+
+        let mut fs = reopen(fs);
+        let report = fs.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        let file = fs.lookup_inode(FUSE_ROOT_ID, OsStr::new("399")).unwrap();
+        assert!(file.id > 2 * fs.super_block.inodes_per_group);
+        assert_eq!(read_all(&mut fs, &file), [143; 10]);
+    }
+}
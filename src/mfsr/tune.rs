@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+
+use crate::types::super_block::{ErrorBehavior, DEFAULT_MOUNT_WRITEBACK, LABEL_SIZE};
+
+use super::{DataMode, Mfsr};
+
+// Super block parameters that can change after mkfs, anything not set is left alone
+#[derive(Debug, Clone, Default)]
+pub struct Tuning {
+    pub label: Option<String>,
+    pub uuid: Option<[u8; 16]>,
+    // share of the blocks only root can allocate
+    pub reserved_percent: Option<u8>,
+    // data mode used when mount doesn't ask for one
+    pub data_mode: Option<DataMode>,
+    pub max_mount_count: Option<u32>,
+    pub errors: Option<ErrorBehavior>,
+}
+
+impl Mfsr {
+    // Changes the super block and writes it back to the primary copy and every backup
+    pub fn tune(&mut self, tuning: &Tuning) -> Result<()> {
+        self.check_unmounted()?;
+
+        if let Some(label) = &tuning.label {
+            if label.len() > LABEL_SIZE {
+                return Err(anyhow!(
+                    "The label can't be longer than {} bytes",
+                    LABEL_SIZE
+                ));
+            }
+        }
+
+        if tuning.reserved_percent.is_some_and(|percent| percent > 50) {
+            return Err(anyhow!("At most 50% of the blocks can be reserved"));
+        }
+
+        // close() writes the shared blocks table back, it has to be loaded first
+        self.load_shared_blocks()?;
+
+        let super_block = &mut self.super_block;
+
+        if let Some(label) = &tuning.label {
+            super_block.label = label.clone();
+        }
+
+        if let Some(uuid) = tuning.uuid {
+            super_block.uuid = uuid;
+        }
+
+        if let Some(percent) = tuning.reserved_percent {
+            super_block.reserved_blocks = super_block.block_count * percent as u64 / 100;
+        }
+
+        match tuning.data_mode {
+            Some(DataMode::Ordered) => {
+                super_block.default_mount_options &= !DEFAULT_MOUNT_WRITEBACK
+            }
+            Some(DataMode::Writeback) => {
+                super_block.default_mount_options |= DEFAULT_MOUNT_WRITEBACK
+            }
+            None => {}
+        }
+
+        if let Some(max_mount_count) = tuning.max_mount_count {
+            super_block.max_mount_count = max_mount_count;
+        }
+
+        if let Some(errors) = tuning.errors {
+            super_block.errors = errors;
+        }
+
+        self.close()
+    }
+}
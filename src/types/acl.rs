@@ -0,0 +1,326 @@
+use anyhow::{anyhow, Result};
+
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+// see include/uapi/linux/posix_acl_xattr.h
+const XATTR_VERSION: u32 = 0x0002;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+pub const TAG_USER_OBJ: u16 = 0x01;
+pub const TAG_USER: u16 = 0x02;
+pub const TAG_GROUP_OBJ: u16 = 0x04;
+pub const TAG_GROUP: u16 = 0x08;
+pub const TAG_MASK: u16 = 0x10;
+pub const TAG_OTHER: u16 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    // Parses and validates the xattr representation used by the kernel and libacl
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE
+            || !bytes[HEADER_SIZE..]
+                .chunks_exact(ENTRY_SIZE)
+                .remainder()
+                .is_empty()
+        {
+            return Err(anyhow!("Invalid ACL size"));
+        }
+
+        let version = u32::from_le_bytes(bytes[..HEADER_SIZE].try_into()?);
+
+        if version != XATTR_VERSION {
+            return Err(anyhow!("Unsupported ACL version {}", version));
+        }
+
+        let mut entries = vec![];
+
+        for chunk in bytes[HEADER_SIZE..].chunks_exact(ENTRY_SIZE) {
+            let tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+            let perm = u16::from_le_bytes([chunk[2], chunk[3]]);
+            let id = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            let id = match tag {
+                TAG_USER | TAG_GROUP => id,
+                _ => UNDEFINED_ID,
+            };
+            entries.push(AclEntry { tag, perm, id });
+        }
+
+        let mut acl = Self { entries };
+        acl.validate()?;
+
+        Ok(acl)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.entries.len() * ENTRY_SIZE);
+        buf.extend_from_slice(&XATTR_VERSION.to_le_bytes());
+
+        for entry in &self.entries {
+            buf.extend_from_slice(&entry.tag.to_le_bytes());
+            buf.extend_from_slice(&entry.perm.to_le_bytes());
+            buf.extend_from_slice(&entry.id.to_le_bytes());
+        }
+
+        buf
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        let mut seen = [0; 6];
+        let mut named_ids = vec![];
+
+        for entry in &self.entries {
+            if entry.perm & !0o7 != 0 {
+                return Err(anyhow!("Invalid ACL permissions"));
+            }
+
+            let index = match entry.tag {
+                TAG_USER_OBJ => 0,
+                TAG_USER => 1,
+                TAG_GROUP_OBJ => 2,
+                TAG_GROUP => 3,
+                TAG_MASK => 4,
+                TAG_OTHER => 5,
+                _ => return Err(anyhow!("Invalid ACL tag {}", entry.tag)),
+            };
+            seen[index] += 1;
+
+            if matches!(entry.tag, TAG_USER | TAG_GROUP) {
+                if named_ids.contains(&(entry.tag, entry.id)) {
+                    return Err(anyhow!("Duplicate ACL entry"));
+                }
+
+                named_ids.push((entry.tag, entry.id));
+            }
+        }
+
+        if seen[0] != 1 || seen[2] != 1 || seen[5] != 1 || seen[4] > 1 {
+            return Err(anyhow!(
+                "ACL must have exactly one owner, group and other entry"
+            ));
+        }
+
+        // named entries are limited by the mask, so it can't be missing
+        if (seen[1] > 0 || seen[3] > 0) && seen[4] == 0 {
+            return Err(anyhow!("ACL with named entries requires a mask"));
+        }
+
+        self.entries.sort_by_key(|e| (e.tag, e.id));
+
+        Ok(())
+    }
+
+    fn entry(&self, tag: u16) -> Option<&AclEntry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+
+    fn entry_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.entries.iter_mut().find(|e| e.tag == tag)
+    }
+
+    // An ACL with only the owner, group and other entries is fully described by the mode bits
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    // The permission bits the ACL maps to, the group class comes from the mask when there's one
+    pub fn mode(&self) -> u32 {
+        let perm = |tag| self.entry(tag).map(|e| e.perm as u32).unwrap_or(0);
+        let group = match self.entry(TAG_MASK) {
+            Some(mask) => mask.perm as u32,
+            None => perm(TAG_GROUP_OBJ),
+        };
+
+        perm(TAG_USER_OBJ) << 6 | group << 3 | perm(TAG_OTHER)
+    }
+
+    // Applies chmod style permission bits to the owner, group class and other entries
+    pub fn set_mode(&mut self, mode: u32) {
+        let group_tag = if self.entry(TAG_MASK).is_some() {
+            TAG_MASK
+        } else {
+            TAG_GROUP_OBJ
+        };
+
+        for (tag, shift) in [(TAG_USER_OBJ, 6), (group_tag, 3), (TAG_OTHER, 0)] {
+            if let Some(entry) = self.entry_mut(tag) {
+                entry.perm = ((mode >> shift) & 0o7) as u16;
+            }
+        }
+    }
+
+    // Builds the access ACL for a new inode from its parent's default ACL, the requested
+    // mode can only take permissions away
+    pub fn inherit(&self, mode: u32) -> Self {
+        let mut acl = self.clone();
+        let group_tag = if acl.entry(TAG_MASK).is_some() {
+            TAG_MASK
+        } else {
+            TAG_GROUP_OBJ
+        };
+
+        for (tag, shift) in [(TAG_USER_OBJ, 6), (group_tag, 3), (TAG_OTHER, 0)] {
+            if let Some(entry) = acl.entry_mut(tag) {
+                entry.perm &= ((mode >> shift) & 0o7) as u16;
+            }
+        }
+
+        acl
+    }
+
+    // POSIX.1e access check algorithm
+    pub fn permits(
+        &self,
+        inode_uid: u32,
+        inode_gid: u32,
+        uid: u32,
+        gid: u32,
+        access_mask: i32,
+    ) -> bool {
+        let access_mask = access_mask as u16 & 0o7;
+        let mask = self.entry(TAG_MASK).map(|e| e.perm).unwrap_or(0o7);
+
+        if uid == inode_uid {
+            return self
+                .entry(TAG_USER_OBJ)
+                .is_some_and(|e| e.perm & access_mask == access_mask);
+        }
+
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|e| e.tag == TAG_USER && e.id == uid)
+        {
+            return entry.perm & mask & access_mask == access_mask;
+        }
+
+        let mut group_matched = false;
+
+        for entry in &self.entries {
+            let matches = match entry.tag {
+                TAG_GROUP_OBJ => gid == inode_gid,
+                TAG_GROUP => gid == entry.id,
+                _ => false,
+            };
+
+            if matches {
+                group_matched = true;
+
+                if entry.perm & mask & access_mask == access_mask {
+                    return true;
+                }
+            }
+        }
+
+        if group_matched {
+            return false;
+        }
+
+        self.entry(TAG_OTHER)
+            .is_some_and(|e| e.perm & access_mask == access_mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: u32 = 1000;
+    const GROUP: u32 = 100;
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Acl {
+        let mut bytes = XATTR_VERSION.to_le_bytes().to_vec();
+
+        for &(tag, perm, id) in entries {
+            bytes.extend_from_slice(&tag.to_le_bytes());
+            bytes.extend_from_slice(&perm.to_le_bytes());
+            bytes.extend_from_slice(&id.to_le_bytes());
+        }
+
+        Acl::from_bytes(&bytes).unwrap()
+    }
+
+    fn named() -> Acl {
+        acl(&[
+            (TAG_OTHER, 0o4, UNDEFINED_ID),
+            (TAG_USER_OBJ, 0o6, UNDEFINED_ID),
+            (TAG_USER, 0o7, 2000),
+            (TAG_GROUP_OBJ, 0o4, UNDEFINED_ID),
+            (TAG_GROUP, 0o6, 300),
+            (TAG_MASK, 0o6, UNDEFINED_ID),
+        ])
+    }
+
+    #[test]
+    fn round_trips_sorted() {
+        let acl = named();
+        let tags: Vec<u16> = acl.entries.iter().map(|e| e.tag).collect();
+
+        assert_eq!(
+            tags,
+            [
+                TAG_USER_OBJ,
+                TAG_USER,
+                TAG_GROUP_OBJ,
+                TAG_GROUP,
+                TAG_MASK,
+                TAG_OTHER
+            ]
+        );
+        assert_eq!(Acl::from_bytes(&acl.to_bytes()).unwrap(), acl);
+        assert_eq!(acl.mode(), 0o664);
+        assert!(!acl.is_minimal());
+    }
+
+    #[test]
+    fn rejects_invalid_acls() {
+        let minimal = acl(&[
+            (TAG_USER_OBJ, 0o6, 0),
+            (TAG_GROUP_OBJ, 0o4, 0),
+            (TAG_OTHER, 0o4, 0),
+        ]);
+        let mut bytes = minimal.to_bytes();
+        assert!(minimal.is_minimal());
+
+        // no mask for a named entry
+        bytes.extend_from_slice(&[TAG_USER as u8, 0, 0o7, 0, 1, 0, 0, 0]);
+        assert!(Acl::from_bytes(&bytes).is_err());
+        assert!(Acl::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Acl::from_bytes(&bytes[..12]).is_err());
+    }
+
+    #[test]
+    fn permits_follows_posix() {
+        let acl = named();
+        let (read, write, exec) = (4, 2, 1);
+
+        // the owner entry isn't limited by the mask
+        assert!(acl.permits(OWNER, GROUP, OWNER, 1, read | write));
+        assert!(!acl.permits(OWNER, GROUP, OWNER, 1, exec));
+
+        // named users and groups are
+        assert!(acl.permits(OWNER, GROUP, 2000, 1, read | write));
+        assert!(!acl.permits(OWNER, GROUP, 2000, 1, exec));
+        assert!(acl.permits(OWNER, GROUP, 3000, 300, write));
+
+        // a matching group that doesn't grant it doesn't fall through to other
+        assert!(acl.permits(OWNER, GROUP, 3000, GROUP, read));
+        assert!(!acl.permits(OWNER, GROUP, 3000, GROUP, write));
+
+        assert!(acl.permits(OWNER, GROUP, 3000, 1, read));
+        assert!(!acl.permits(OWNER, GROUP, 3000, 1, write));
+    }
+}
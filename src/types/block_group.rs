@@ -1,14 +1,18 @@
-use std::io::{Read, Seek, SeekFrom, Write};
-
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
 
-use crate::{types::super_block::SuperBlock, utils::get_block_group_size};
+use crate::{device::BlockDevice, types::super_block::SuperBlock};
+
+// groups whose bitmaps a mount keeps in memory, dirty ones stay past it until they're logged
+pub const CACHED_GROUPS: usize = 256;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct BlockGroup {
     pub data_bitmap: Vec<u8>,
     pub inode_bitmap: Vec<u8>,
+    // bitmaps changed since they were last written back
+    pub dirty: bool,
+    // when the bitmaps were last looked at, the least recent clean group is dropped first
+    pub last_used: u64,
 }
 
 impl BlockGroup {
@@ -16,46 +20,29 @@ impl BlockGroup {
         Self {
             data_bitmap,
             inode_bitmap,
+            dirty: false,
+            last_used: 0,
         }
     }
 
-    pub fn serialize_into<W>(mut w: W, groups: &[Self], super_block: &mut SuperBlock) -> Result<()>
-    where
-        W: Write + Seek,
-    {
+    pub fn write_all(
+        device: &mut dyn BlockDevice,
+        groups: &[Self],
+        super_block: &SuperBlock,
+    ) -> Result<()> {
         assert!(!groups.is_empty());
-        let block_size = super_block.block_size;
+        let block_size = super_block.block_size as u64;
+        let group_size = super_block.group_size();
+        let super_block = super_block.encode();
 
         for (i, g) in groups.iter().enumerate() {
-            let offset = get_block_group_size(block_size) * i as u64;
-            let mut w_ref = w.by_ref();
-            w_ref.seek(SeekFrom::Start(offset))?;
-            super_block.serialize_into(&mut w_ref)?;
+            let offset = group_size * i as u64;
+            device.write_at(offset, &super_block)?;
             // first block of the group will always be the super block
-            w_ref.seek(SeekFrom::Start(offset + block_size as u64))?;
-            w_ref.write_all(&g.data_bitmap)?;
-            w_ref.write_all(&g.inode_bitmap)?;
+            device.write_at(offset + block_size, &g.data_bitmap)?;
+            device.write_at(offset + block_size * 2, &g.inode_bitmap)?;
         }
 
         Ok(())
     }
-
-    pub fn deserialize_from<R>(mut r: R, block_size: u32, count: usize) -> Result<Vec<Self>>
-    where
-        R: Read + Seek,
-    {
-        let mut groups = Vec::with_capacity(count);
-
-        for i in 0..count {
-            let offset = get_block_group_size(block_size) * i as u64 + block_size as u64;
-            r.seek(SeekFrom::Start(offset))?;
-            let mut data_bitmap = vec![0; block_size as usize];
-            let mut inode_bitmap = vec![0; block_size as usize];
-            r.read_exact(&mut data_bitmap)?;
-            r.read_exact(&mut inode_bitmap)?;
-            groups.push(BlockGroup::new(data_bitmap, inode_bitmap));
-        }
-
-        Ok(groups)
-    }
 }
@@ -4,16 +4,18 @@ use std::{
 };
 
 use anyhow::{anyhow, Result};
-use crc32fast::Hasher;
-use serde::{Deserialize, Serialize};
 
 use crate::utils::u64_to_bytes;
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::encoding::{Decoder, Encoder};
+
+// On disk, in little-endian after the u64 length of the rest: inode_id u64, entry count u32,
+// then each entry as its inode u64 and its name (u16 length and UTF-8 bytes), and a CRC32 of
+// everything before it
+#[derive(Debug)]
 pub struct DirectoryEntry {
     pub inode_id: u64,
     pub entries: BTreeMap<String, u64>,
-    pub checksum: u32,
 }
 
 impl DirectoryEntry {
@@ -21,51 +23,59 @@ impl DirectoryEntry {
         Self {
             inode_id,
             entries: BTreeMap::new(),
-            checksum: 0,
         }
     }
 
-    pub fn serialize_into<W>(&mut self, mut w: W) -> Result<()>
-    where
-        W: Write + Seek,
-    {
-        self.checksum();
-        let len = bincode::serialized_size(self)?;
-        let buf = u64_to_bytes(len);
-        w.write_all(&buf)?;
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+    // "." and ".." are never stored, the kernel resolves them itself
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 
-    pub fn deserialize_from<R>(r: R) -> Result<Self>
-    where
-        R: Read,
-    {
-        let mut dentry: Self = bincode::deserialize_from(r)?;
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.u64(self.inode_id);
+        e.u32(self.entries.len() as u32);
 
-        if !dentry.verify_checksum() {
-            Err(anyhow!("Invalid superblock checksum"))
-        } else {
-            Ok(dentry)
+        for (name, inode_id) in &self.entries {
+            e.u64(*inode_id);
+            e.string(name);
         }
-    }
 
-    pub fn checksum(&mut self) {
-        self.checksum = self.calculate_checksum();
+        e.checksum();
+        e.finish()
     }
 
-    pub fn calculate_checksum(&mut self) -> u32 {
-        self.checksum = 0;
-        let mut hasher = Hasher::new();
-        hasher.update(&bincode::serialize(&self).unwrap());
-        hasher.finalize()
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if !Decoder::verify_checksum(buf) {
+            return Err(anyhow!("Invalid directory entry checksum"));
+        }
+
+        let mut d = Decoder::new(buf);
+        let mut dentry = Self::new(d.u64()?);
+
+        for _ in 0..d.u32()? {
+            let inode_id = d.u64()?;
+            dentry.entries.insert(d.string()?, inode_id);
+        }
+
+        Ok(dentry)
     }
 
-    pub fn verify_checksum(&mut self) -> bool {
-        let checksum = self.checksum;
-        self.checksum = 0;
-        let ok = checksum == self.calculate_checksum();
-        self.checksum = checksum;
+    pub fn serialize_into<W>(&self, mut w: W) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        let buf = self.encode();
+        w.write_all(&u64_to_bytes(buf.len() as u64))?;
+        w.write_all(&buf).map_err(|e| e.into())
+    }
 
-        ok
+    pub fn deserialize_from<R>(mut r: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut buf = vec![];
+        r.read_to_end(&mut buf)?;
+        Self::decode(&buf)
     }
 }
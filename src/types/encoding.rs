@@ -0,0 +1,193 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use crc32fast::Hasher;
+
+// Every on-disk structure is written field by field in little-endian with these, so the layout
+// only depends on the code in this crate and not on serde, the compiler or the host. Times are
+// seconds since the epoch as a u64 followed by the nanoseconds as a u32, strings and byte
+// strings are prefixed by their length
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn time(&mut self, time: SystemTime) {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.u64(since_epoch.as_secs());
+        self.u32(since_epoch.subsec_nanos());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn string(&mut self, s: &str) {
+        self.u16(s.len() as u16);
+        self.bytes(s.as_bytes());
+    }
+
+    // fixed size records are zero filled up to their size
+    pub fn pad_to(&mut self, len: usize) {
+        assert!(self.buf.len() <= len);
+        self.buf.resize(len, 0);
+    }
+
+    // appends the CRC32 of everything written so far
+    pub fn checksum(&mut self) {
+        let checksum = crc32(&self.buf);
+        self.u32(checksum);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+
+    // checks the CRC32 in the last 4 bytes of the buffer against everything before it
+    pub fn verify_checksum(buf: &[u8]) -> bool {
+        match buf.len().checked_sub(4) {
+            Some(end) => crc32(&buf[..end]).to_le_bytes() == buf[end..],
+            None => false,
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .position
+            .checked_add(len)
+            .and_then(|end| self.buf.get(self.position..end))
+            .ok_or(anyhow!("Unexpected end of data at byte {}", self.position))?;
+        self.position += len;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    pub fn time(&mut self) -> Result<SystemTime> {
+        let secs = self.u64()?;
+        let nanos = self.u32()?;
+
+        UNIX_EPOCH
+            .checked_add(Duration::new(secs, nanos))
+            .ok_or(anyhow!("Invalid time {}.{}", secs, nanos))
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+}
+
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_round_trip() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let mut e = Encoder::new();
+        e.u8(0xab);
+        e.u16(0xbeef);
+        e.u32(0xdead_beef);
+        e.u64(u64::MAX - 1);
+        e.time(time);
+        e.string("héllo");
+        e.bytes(&[1, 2, 3]);
+        e.pad_to(64);
+        e.checksum();
+        let buf = e.finish();
+
+        assert_eq!(buf.len(), 68);
+        assert!(Decoder::verify_checksum(&buf));
+
+        let mut d = Decoder::new(&buf);
+        assert_eq!(d.u8().unwrap(), 0xab);
+        assert_eq!(d.u16().unwrap(), 0xbeef);
+        assert_eq!(d.u32().unwrap(), 0xdead_beef);
+        assert_eq!(d.u64().unwrap(), u64::MAX - 1);
+        assert_eq!(d.time().unwrap(), time);
+        assert_eq!(d.string().unwrap(), "héllo");
+        assert_eq!(d.bytes(3).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn layout_is_little_endian() {
+        let mut e = Encoder::new();
+        e.u32(0x0102_0304);
+        e.string("ab");
+
+        assert_eq!(e.finish(), [4, 3, 2, 1, 2, 0, b'a', b'b']);
+    }
+
+    #[test]
+    fn damage_is_caught() {
+        let mut e = Encoder::new();
+        e.u64(42);
+        e.checksum();
+        let mut buf = e.finish();
+        buf[3] ^= 1;
+
+        assert!(!Decoder::verify_checksum(&buf));
+        assert!(!Decoder::verify_checksum(&[0; 3]));
+
+        let mut d = Decoder::new(&buf[..6]);
+        assert!(d.u64().is_err());
+        assert_eq!(d.u32().unwrap(), 0x0100_002a);
+        assert!(d.u32().is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    mem::size_of,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::utils::{bytes_to_u64, u64_to_bytes};
+
+use super::encoding::{Decoder, Encoder};
+
+pub const USER_PREFIX: &str = "user.";
+pub const TRUSTED_PREFIX: &str = "trusted.";
+pub const SECURITY_PREFIX: &str = "security.";
+// values up to this size are kept in the attribute table itself
+pub const MAX_INLINE_VALUE_SIZE: usize = 128;
+pub const MAX_XATTR_NAME_LENGTH: usize = 255;
+const INLINE_VALUE: u8 = 0;
+const BLOCK_VALUE: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub enum XattrValue {
+    Inline(Vec<u8>),
+    // larger values get a data block of their own
+    Block { block_id: u64, len: u32 },
+}
+
+impl XattrValue {
+    pub fn size(&self) -> usize {
+        match self {
+            XattrValue::Inline(v) => v.len(),
+            XattrValue::Block { len, .. } => *len as usize,
+        }
+    }
+}
+
+// On disk, in little-endian after the u64 length of the rest: entry count u32, then each entry
+// as its name (u16 length and UTF-8 bytes) and a u8 kind, followed by the value length u32 and
+// the value when inline or by the block id u64 and value length u32 otherwise, and a CRC32 of
+// everything before it
+#[derive(Debug, Default)]
+pub struct ExtendedAttributes {
+    pub entries: BTreeMap<String, XattrValue>,
+}
+
+impl ExtendedAttributes {
+    pub fn serialized_size(&self) -> usize {
+        self.encode().len() + size_of::<u64>()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.u32(self.entries.len() as u32);
+
+        for (name, value) in &self.entries {
+            e.string(name);
+
+            match value {
+                XattrValue::Inline(value) => {
+                    e.u8(INLINE_VALUE);
+                    e.u32(value.len() as u32);
+                    e.bytes(value);
+                }
+                XattrValue::Block { block_id, len } => {
+                    e.u8(BLOCK_VALUE);
+                    e.u64(*block_id);
+                    e.u32(*len);
+                }
+            }
+        }
+
+        e.checksum();
+        e.finish()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if !Decoder::verify_checksum(buf) {
+            return Err(anyhow!("Invalid extended attributes checksum"));
+        }
+
+        let mut d = Decoder::new(buf);
+        let mut xattrs = Self::default();
+
+        for _ in 0..d.u32()? {
+            let name = d.string()?;
+            let value = match d.u8()? {
+                INLINE_VALUE => {
+                    let len = d.u32()? as usize;
+                    XattrValue::Inline(d.bytes(len)?.to_vec())
+                }
+                BLOCK_VALUE => XattrValue::Block {
+                    block_id: d.u64()?,
+                    len: d.u32()?,
+                },
+                kind => return Err(anyhow!("Invalid extended attribute kind {}", kind)),
+            };
+            xattrs.entries.insert(name, value);
+        }
+
+        Ok(xattrs)
+    }
+
+    pub fn serialize_into<W>(&self, mut w: W) -> Result<()>
+    where
+        W: Write,
+    {
+        let buf = self.encode();
+        w.write_all(&u64_to_bytes(buf.len() as u64))?;
+        w.write_all(&buf).map_err(|e| e.into())
+    }
+
+    pub fn deserialize_from<R>(mut r: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf)?;
+        let len = bytes_to_u64(buf);
+        let mut buf = vec![];
+        r.take(len).read_to_end(&mut buf)?;
+
+        if buf.len() as u64 != len {
+            return Err(anyhow!("Truncated extended attributes"));
+        }
+
+        Self::decode(&buf)
+    }
+}
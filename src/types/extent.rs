@@ -0,0 +1,263 @@
+use std::mem::size_of;
+
+use crate::utils::{bytes_to_pointer, bytes_to_u64, pointer_to_bytes, u64_to_bytes};
+
+// number of extents that fit in the inode pointers before a tree is needed
+pub const INLINE_EXTENTS: usize = 4;
+pub const EXTENT_SIZE: usize = 3 * size_of::<u64>();
+const LEAF_HEADER_SIZE: usize = size_of::<u32>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    // first file block covered by the extent
+    pub logical: u64,
+    // first data block of the run
+    pub start: u64,
+    pub len: u64,
+}
+
+impl Extent {
+    pub fn new(logical: u64, start: u64, len: u64) -> Self {
+        Self {
+            logical,
+            start,
+            len,
+        }
+    }
+
+    #[inline(always)]
+    pub fn end(&self) -> u64 {
+        self.logical + self.len
+    }
+
+    pub fn to_pointers(self) -> [u64; 3] {
+        [self.logical, self.start, self.len]
+    }
+
+    pub fn from_pointers(pointers: &[u64]) -> Self {
+        Self::new(pointers[0], pointers[1], pointers[2])
+    }
+}
+
+// Sorted, non overlapping list of the extents mapping a file
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtentMap {
+    pub extents: Vec<Extent>,
+}
+
+impl ExtentMap {
+    pub fn new(extents: Vec<Extent>) -> Self {
+        Self { extents }
+    }
+
+    pub fn leaf_capacity(block_size: u32) -> usize {
+        (block_size as usize - LEAF_HEADER_SIZE) / EXTENT_SIZE
+    }
+
+    // index of the first extent ending after `logical`
+    fn position(&self, logical: u64) -> usize {
+        self.extents.partition_point(|e| e.end() <= logical)
+    }
+
+    // Resolves a file block to its data block and how many blocks after it are mapped the same
+    // way. Unmapped blocks resolve to 0 with the length of the hole, which is unbounded past the
+    // last extent
+    pub fn lookup(&self, logical: u64) -> (u64, u64) {
+        match self.extents.get(self.position(logical)) {
+            Some(e) if e.logical <= logical => {
+                let offset = logical - e.logical;
+                (e.start + offset, e.len - offset)
+            }
+            Some(e) => (0, e.logical - logical),
+            None => (0, u64::MAX - logical),
+        }
+    }
+
+    // Maps a run of unmapped file blocks, merging with the neighbours when the data is contiguous
+    pub fn insert(&mut self, extent: Extent) {
+        let index = self.position(extent.logical);
+
+        if index > 0 {
+            let prev = &mut self.extents[index - 1];
+
+            if prev.end() == extent.logical && prev.start + prev.len == extent.start {
+                prev.len += extent.len;
+
+                if let Some(next) = self.extents.get(index).copied() {
+                    let prev = self.extents[index - 1];
+
+                    if prev.end() == next.logical && prev.start + prev.len == next.start {
+                        self.extents[index - 1].len += next.len;
+                        self.extents.remove(index);
+                    }
+                }
+
+                return;
+            }
+        }
+
+        if let Some(next) = self.extents.get_mut(index) {
+            if extent.end() == next.logical && extent.start + extent.len == next.start {
+                next.logical = extent.logical;
+                next.start = extent.start;
+                next.len += extent.len;
+                return;
+            }
+        }
+
+        self.extents.insert(index, extent);
+    }
+
+    // Unmaps the file blocks in [from, to) and returns the data runs that were released
+    pub fn remove_range(&mut self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let mut released = vec![];
+        let mut kept = Vec::with_capacity(self.extents.len() + 1);
+
+        for e in self.extents.drain(..) {
+            let (start, end) = (e.logical, e.end());
+
+            if end <= from || start >= to {
+                kept.push(e);
+                continue;
+            }
+
+            if start < from {
+                kept.push(Extent::new(e.logical, e.start, from - start));
+            }
+
+            let cut_start = start.max(from);
+            let cut_end = end.min(to);
+            released.push((e.start + (cut_start - start), cut_end - cut_start));
+
+            if end > to {
+                let offset = to - start;
+                kept.push(Extent::new(to, e.start + offset, e.len - offset));
+            }
+        }
+
+        self.extents = kept;
+        released
+    }
+
+    // Unmaps [from, to) and moves everything after it down to close the gap
+    pub fn collapse_range(&mut self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let released = self.remove_range(from, to);
+        let shift = to - from;
+        let mut collapsed: Vec<Extent> = Vec::with_capacity(self.extents.len());
+
+        for mut e in self.extents.drain(..) {
+            if e.logical >= to {
+                e.logical -= shift;
+            }
+
+            match collapsed.last_mut() {
+                Some(prev) if prev.end() == e.logical && prev.start + prev.len == e.start => {
+                    prev.len += e.len;
+                }
+                _ => collapsed.push(e),
+            }
+        }
+
+        self.extents = collapsed;
+        released
+    }
+
+    pub fn encode_leaf(extents: &[Extent], block_size: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(block_size as usize);
+        buf.extend_from_slice(&pointer_to_bytes(extents.len() as u32));
+
+        for extent in extents {
+            for pointer in extent.to_pointers() {
+                buf.extend_from_slice(&u64_to_bytes(pointer));
+            }
+        }
+
+        buf.resize(block_size as usize, 0);
+        buf
+    }
+
+    pub fn decode_leaf(buf: &[u8]) -> Vec<Extent> {
+        let count = bytes_to_pointer(&buf[..LEAF_HEADER_SIZE]) as usize;
+
+        buf[LEAF_HEADER_SIZE..]
+            .chunks_exact(EXTENT_SIZE)
+            .take(count)
+            .map(|chunk| {
+                let pointers: Vec<u64> = chunk
+                    .chunks_exact(8)
+                    .map(|pointer| bytes_to_u64(pointer.try_into().unwrap()))
+                    .collect();
+                Extent::from_pointers(&pointers)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(extents: &[(u64, u64, u64)]) -> ExtentMap {
+        ExtentMap::new(
+            extents
+                .iter()
+                .map(|&(logical, start, len)| Extent::new(logical, start, len))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn insert_merges_contiguous_runs() {
+        let mut extents = ExtentMap::default();
+        extents.insert(Extent::new(0, 100, 2));
+        extents.insert(Extent::new(4, 104, 2));
+        assert_eq!(extents, map(&[(0, 100, 2), (4, 104, 2)]));
+
+        // fills the gap and joins both neighbours
+        extents.insert(Extent::new(2, 102, 2));
+        assert_eq!(extents, map(&[(0, 100, 6)]));
+
+        // contiguous in the file but not on disk
+        extents.insert(Extent::new(6, 200, 1));
+        extents.insert(Extent::new(10, 300, 1));
+        assert_eq!(extents, map(&[(0, 100, 6), (6, 200, 1), (10, 300, 1)]));
+
+        assert_eq!(extents.lookup(3), (103, 3));
+        assert_eq!(extents.lookup(7), (0, 3));
+        assert_eq!(extents.lookup(11), (0, u64::MAX - 11));
+    }
+
+    #[test]
+    fn remove_range_splits_extents() {
+        let mut extents = map(&[(0, 100, 10), (20, 200, 5)]);
+
+        assert_eq!(extents.remove_range(3, 5), [(103, 2)]);
+        assert_eq!(extents, map(&[(0, 100, 3), (5, 105, 5), (20, 200, 5)]));
+
+        assert_eq!(extents.remove_range(8, 22), [(108, 2), (200, 2)]);
+        assert_eq!(extents, map(&[(0, 100, 3), (5, 105, 3), (22, 202, 3)]));
+    }
+
+    #[test]
+    fn collapse_range_closes_the_gap() {
+        let mut extents = map(&[(0, 100, 10), (10, 50, 4)]);
+
+        assert_eq!(extents.collapse_range(2, 4), [(102, 2)]);
+        assert_eq!(extents, map(&[(0, 100, 2), (2, 104, 6), (8, 50, 4)]));
+
+        // what's left on either side of the gap is contiguous on disk again
+        let mut extents = map(&[(0, 100, 2), (5, 102, 3)]);
+        assert!(extents.collapse_range(2, 5).is_empty());
+        assert_eq!(extents, map(&[(0, 100, 5)]));
+    }
+
+    #[test]
+    fn leaves_round_trip() {
+        let extents = map(&[(0, 100, 10), (20, 200, 5), (u64::MAX - 1, 1, 1)]).extents;
+        let buf = ExtentMap::encode_leaf(&extents, 1024);
+
+        assert_eq!(buf.len(), 1024);
+        assert_eq!(ExtentMap::decode_leaf(&buf), extents);
+        assert_eq!(ExtentMap::leaf_capacity(1024), 42);
+    }
+}
@@ -1,16 +1,34 @@
-use std::{io::Read, io::Write};
+use std::mem::size_of;
 
 use anyhow::{anyhow, Result};
-use crc32fast::Hasher;
 use fuser::{FileAttr, FileType};
 use libc::{gid_t, mode_t, uid_t};
-use serde::{Deserialize, Serialize};
 
-use crate::utils::{current_timestamp, timestamp_to_system_time};
+use crate::utils::{bytes_to_u64, current_timestamp, timestamp_to_system_time, u64_to_bytes};
 
-use super::super_block::SuperBlock;
+use super::{
+    encoding::{Decoder, Encoder},
+    extent::{Extent, INLINE_EXTENTS},
+    super_block::SuperBlock,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+pub const DIRECT_POINTERS: usize = 12;
+// symlink targets up to this length are stored directly in the pointers space
+pub const INLINE_DATA_SIZE: usize = size_of::<[u64; DIRECT_POINTERS]>();
+// the file blocks are mapped by extents instead of the pointer tree, same bit as ext4
+pub const EXTENTS_FLAG: u32 = 0x80000;
+// every inode takes a slot of this size in the inode table, the space after the last field is
+// zeroed
+pub const INODE_SIZE: usize = 256;
+
+// On disk, INODE_SIZE bytes in little-endian:
+//   0 id u64, 8 size u64, 16 creation_time u64, 24 last_accessed u64, 32 last_modified u64,
+//  40 last_metadata_changed u64 (seconds since the epoch), 48 block_count u64, 56 mode u32,
+//  60 hard_links u32, 64 uid u32, 68 gid u32, 72 rdev u32, 76 flags u32, 80 kind u32 (see
+//  file_type_to_code), 84 direct_pointers 12 * u64, 180 indirect_pointer u64,
+// 188 double_indirect_pointer u64, 196 triple_indirect_pointer u64, 204 xattr_pointer u64,
+// 212 reserved, 252 CRC32 of the bytes before it
+#[derive(Debug, Clone)]
 pub struct Inode {
     pub id: u64,
     pub size: u64,
@@ -26,9 +44,11 @@ pub struct Inode {
     pub block_count: u64,
     pub rdev: u32,
     pub flags: u32,
-    pub direct_pointers: [u32; 12],
-    pub indirect_pointer: u32,
-    pub checksum: u32,
+    pub direct_pointers: [u64; DIRECT_POINTERS],
+    pub indirect_pointer: u64,
+    pub double_indirect_pointer: u64,
+    pub triple_indirect_pointer: u64,
+    pub xattr_pointer: u64,
 }
 
 impl Inode {
@@ -48,17 +68,25 @@ impl Inode {
             hard_links: 1,
             block_count: 0,
             rdev: 0,
-            direct_pointers: [0; 12],
+            direct_pointers: [0; DIRECT_POINTERS],
             indirect_pointer: 0,
-            checksum: 0,
+            double_indirect_pointer: 0,
+            triple_indirect_pointer: 0,
+            xattr_pointer: 0,
         }
     }
 
+    // st_blocks counts 512 byte units, holes don't take any space
+    pub fn allocated_sectors(&self, block_size: u32) -> u64 {
+        let xattr_blocks = (self.xattr_pointer != 0) as u64;
+        (self.block_count + xattr_blocks) * (block_size as u64 / 512)
+    }
+
     pub fn to_file_attr(&self, super_block: &SuperBlock) -> FileAttr {
         FileAttr {
             ino: self.id,
             size: self.size,
-            blocks: self.block_count,
+            blocks: self.allocated_sectors(super_block.block_size),
             atime: timestamp_to_system_time(self.last_accessed),
             mtime: timestamp_to_system_time(self.last_modified),
             ctime: timestamp_to_system_time(self.last_metadata_changed),
@@ -74,51 +102,150 @@ impl Inode {
         }
     }
 
-    pub fn serialize_into<W>(&mut self, w: W) -> Result<()>
-    where
-        W: Write,
-    {
-        self.checksum();
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.u64(self.id);
+        e.u64(self.size);
+        e.u64(self.creation_time);
+        e.u64(self.last_accessed);
+        e.u64(self.last_modified);
+        e.u64(self.last_metadata_changed);
+        e.u64(self.block_count);
+        e.u32(self.mode);
+        e.u32(self.hard_links);
+        e.u32(self.uid);
+        e.u32(self.gid);
+        e.u32(self.rdev);
+        e.u32(self.flags);
+        e.u32(file_type_to_code(self.kind));
+
+        for pointer in self.direct_pointers {
+            e.u64(pointer);
+        }
+
+        e.u64(self.indirect_pointer);
+        e.u64(self.double_indirect_pointer);
+        e.u64(self.triple_indirect_pointer);
+        e.u64(self.xattr_pointer);
+        e.pad_to(INODE_SIZE - 4);
+        e.checksum();
+        e.finish()
     }
 
-    pub fn deserialize_from<R>(r: R) -> Result<Self>
-    where
-        R: Read,
-    {
-        let mut inode: Self = bincode::deserialize_from(r)?;
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let buf = buf.get(..INODE_SIZE).ok_or(anyhow!("Truncated inode"))?;
 
-        if !inode.verify_checksum() {
-            Err(anyhow!("Invalid superblock checksum"))
-        } else {
-            Ok(inode)
+        if !Decoder::verify_checksum(buf) {
+            return Err(anyhow!("Invalid inode checksum"));
         }
+
+        let mut d = Decoder::new(buf);
+        let id = d.u64()?;
+        let size = d.u64()?;
+        let creation_time = d.u64()?;
+        let last_accessed = d.u64()?;
+        let last_modified = d.u64()?;
+        let last_metadata_changed = d.u64()?;
+        let block_count = d.u64()?;
+        let mode = d.u32()?;
+        let hard_links = d.u32()?;
+        let uid = d.u32()?;
+        let gid = d.u32()?;
+        let rdev = d.u32()?;
+        let flags = d.u32()?;
+        let kind = file_type_from_code(d.u32()?)?;
+        let mut direct_pointers = [0; DIRECT_POINTERS];
+
+        for pointer in direct_pointers.iter_mut() {
+            *pointer = d.u64()?;
+        }
+
+        Ok(Self {
+            id,
+            size,
+            creation_time,
+            last_accessed,
+            last_modified,
+            last_metadata_changed,
+            kind,
+            mode,
+            hard_links,
+            uid,
+            gid,
+            block_count,
+            rdev,
+            flags,
+            direct_pointers,
+            indirect_pointer: d.u64()?,
+            double_indirect_pointer: d.u64()?,
+            triple_indirect_pointer: d.u64()?,
+            xattr_pointer: d.u64()?,
+        })
     }
 
-    pub fn checksum(&mut self) {
-        self.checksum = self.calculate_checksum();
+    // root of the pointer tree for the given indirection level (1 = indirect, 3 = triple)
+    pub fn indirect_root(&self, level: u32) -> u64 {
+        match level {
+            1 => self.indirect_pointer,
+            2 => self.double_indirect_pointer,
+            3 => self.triple_indirect_pointer,
+            _ => unreachable!(),
+        }
     }
 
-    pub fn calculate_checksum(&mut self) -> u32 {
-        self.checksum = 0;
-        let mut hasher = Hasher::new();
-        hasher.update(&bincode::serialize(&self).unwrap());
-        hasher.finalize()
+    pub fn set_indirect_root(&mut self, level: u32, pointer: u64) {
+        match level {
+            1 => self.indirect_pointer = pointer,
+            2 => self.double_indirect_pointer = pointer,
+            3 => self.triple_indirect_pointer = pointer,
+            _ => unreachable!(),
+        }
     }
 
-    pub fn verify_checksum(&mut self) -> bool {
-        let checksum = self.checksum;
-        self.checksum = 0;
-        let new_checksum = self.calculate_checksum();
-        let ok = checksum == new_checksum;
+    pub fn uses_extents(&self) -> bool {
+        self.flags & EXTENTS_FLAG != 0
+    }
 
-        if !ok {
-            println!("AHCLK");
+    pub fn inline_extents(&self) -> Vec<Extent> {
+        self.direct_pointers
+            .chunks_exact(3)
+            .map(Extent::from_pointers)
+            .filter(|e| e.len != 0)
+            .collect()
+    }
+
+    pub fn set_inline_extents(&mut self, extents: &[Extent]) {
+        assert!(extents.len() <= INLINE_EXTENTS);
+        self.direct_pointers = [0; DIRECT_POINTERS];
+
+        for (chunk, extent) in self.direct_pointers.chunks_exact_mut(3).zip(extents) {
+            chunk.copy_from_slice(&extent.to_pointers());
+        }
+    }
+
+    pub fn is_fast_symlink(&self) -> bool {
+        self.kind == FileType::Symlink && self.block_count == 0
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        assert!(data.len() <= INLINE_DATA_SIZE);
+        let mut buf = [0u8; INLINE_DATA_SIZE];
+        buf[..data.len()].copy_from_slice(data);
+
+        for (pointer, chunk) in self.direct_pointers.iter_mut().zip(buf.chunks_exact(8)) {
+            *pointer = bytes_to_u64(chunk.try_into().unwrap());
         }
+    }
+
+    pub fn inline_data(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(INLINE_DATA_SIZE);
 
-        self.checksum = checksum;
+        for pointer in self.direct_pointers {
+            buf.extend_from_slice(&u64_to_bytes(pointer));
+        }
 
-        ok
+        buf.truncate(self.size as usize);
+        buf
     }
 
     pub fn clear_suid_sgid(&mut self) {
@@ -129,3 +256,29 @@ impl Inode {
         }
     }
 }
+
+// the same codes ext4 uses for its directory entries
+fn file_type_to_code(kind: FileType) -> u32 {
+    match kind {
+        FileType::RegularFile => 1,
+        FileType::Directory => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::NamedPipe => 5,
+        FileType::Socket => 6,
+        FileType::Symlink => 7,
+    }
+}
+
+fn file_type_from_code(code: u32) -> Result<FileType> {
+    match code {
+        1 => Ok(FileType::RegularFile),
+        2 => Ok(FileType::Directory),
+        3 => Ok(FileType::CharDevice),
+        4 => Ok(FileType::BlockDevice),
+        5 => Ok(FileType::NamedPipe),
+        6 => Ok(FileType::Socket),
+        7 => Ok(FileType::Symlink),
+        _ => Err(anyhow!("Invalid file type {}", code)),
+    }
+}
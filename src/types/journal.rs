@@ -0,0 +1,106 @@
+use std::mem::size_of;
+
+use crc32fast::Hasher;
+
+use crate::utils::{bytes_to_pointer, bytes_to_u64, pointer_to_bytes, u64_to_bytes};
+
+const JOURNAL_MAGIC: u32 = 0x4D534A4C;
+const HEADER_BLOCK: u32 = 1;
+const DESCRIPTOR_BLOCK: u32 = 2;
+const COMMIT_BLOCK: u32 = 3;
+// magic, block kind and transaction sequence
+const RECORD_HEADER_SIZE: usize = 2 * size_of::<u32>() + size_of::<u64>();
+
+// The journal is a run of blocks reserved by mkfs. Block 0 holds the header with the sequence of
+// the next transaction, a transaction follows as descriptor blocks listing where each logged block
+// goes, each one followed by the block images it lists, and ends with a commit block. A
+// transaction is only replayed when its commit block is there and its checksum matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JournalRecord {
+    Header { sequence: u64 },
+    Descriptor { sequence: u64, targets: Vec<u64> },
+    Commit { sequence: u64, checksum: u32 },
+}
+
+impl JournalRecord {
+    // how many logged blocks a single descriptor block can list
+    pub fn descriptor_capacity(block_size: u32) -> usize {
+        (block_size as usize - RECORD_HEADER_SIZE - size_of::<u32>()) / size_of::<u64>()
+    }
+
+    pub fn encode(&self, block_size: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(block_size as usize);
+        buf.extend_from_slice(&pointer_to_bytes(JOURNAL_MAGIC));
+
+        match self {
+            JournalRecord::Header { sequence } => {
+                buf.extend_from_slice(&pointer_to_bytes(HEADER_BLOCK));
+                buf.extend_from_slice(&u64_to_bytes(*sequence));
+            }
+            JournalRecord::Descriptor { sequence, targets } => {
+                buf.extend_from_slice(&pointer_to_bytes(DESCRIPTOR_BLOCK));
+                buf.extend_from_slice(&u64_to_bytes(*sequence));
+                buf.extend_from_slice(&pointer_to_bytes(targets.len() as u32));
+
+                for target in targets {
+                    buf.extend_from_slice(&u64_to_bytes(*target));
+                }
+            }
+            JournalRecord::Commit { sequence, checksum } => {
+                buf.extend_from_slice(&pointer_to_bytes(COMMIT_BLOCK));
+                buf.extend_from_slice(&u64_to_bytes(*sequence));
+                buf.extend_from_slice(&pointer_to_bytes(*checksum));
+            }
+        }
+
+        buf.resize(block_size as usize, 0);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < RECORD_HEADER_SIZE || bytes_to_pointer(&buf[..4]) != JOURNAL_MAGIC {
+            return None;
+        }
+
+        let sequence = bytes_to_u64(buf[8..16].try_into().unwrap());
+        let payload = &buf[RECORD_HEADER_SIZE..];
+
+        match bytes_to_pointer(&buf[4..8]) {
+            HEADER_BLOCK => Some(JournalRecord::Header { sequence }),
+            DESCRIPTOR_BLOCK => {
+                let count = bytes_to_pointer(&payload[..4]) as usize;
+                let targets: Vec<u64> = payload[4..]
+                    .chunks_exact(size_of::<u64>())
+                    .take(count)
+                    .map(|chunk| bytes_to_u64(chunk.try_into().unwrap()))
+                    .collect();
+
+                if targets.len() != count {
+                    return None;
+                }
+
+                Some(JournalRecord::Descriptor { sequence, targets })
+            }
+            COMMIT_BLOCK => Some(JournalRecord::Commit {
+                sequence,
+                checksum: bytes_to_pointer(&payload[..4]),
+            }),
+            _ => None,
+        }
+    }
+
+    // checksum over every logged block and where it goes, stored in the commit block
+    pub fn transaction_checksum<'a, I>(blocks: I) -> u32
+    where
+        I: IntoIterator<Item = (u64, &'a [u8])>,
+    {
+        let mut hasher = Hasher::new();
+
+        for (target, image) in blocks {
+            hasher.update(&u64_to_bytes(target));
+            hasher.update(image);
+        }
+
+        hasher.finalize()
+    }
+}
@@ -1,4 +1,9 @@
+pub mod acl;
 pub mod block_group;
 pub mod directory_entry;
+pub mod encoding;
+pub mod extended_attributes;
+pub mod extent;
 pub mod inode;
+pub mod journal;
 pub mod super_block;
@@ -1,22 +1,90 @@
-use std::{
-    io::{Read, Write},
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
-use crc32fast::Hasher;
+use clap::ValueEnum;
 use libc::{gid_t, uid_t};
-use serde::{Deserialize, Serialize};
+
+use crate::{
+    device::BlockDevice,
+    types::inode::INODE_SIZE,
+    utils::{get_block_group_size, get_inode_table_size, max_inodes_per_group},
+};
+
+use super::encoding::{Decoder, Encoder};
 
 const MAGIC_NUMBER: u32 = 0x4D534653;
+// bumped whenever the layout of anything on disk changes
+pub const FORMAT_VERSION: u32 = 3;
+// the super block copies fit the smallest block size, the space after the last field is zeroed
+pub const SUPER_BLOCK_SIZE: usize = 512;
+
+// Features work like ext4's. An unknown compat feature is safe to ignore, an unknown ro_compat
+// one only allows reading the image and an unknown incompat one doesn't allow opening it at all
+pub const FEATURE_COMPAT_JOURNAL: u32 = 0x1;
+pub const FEATURE_COMPAT_XATTR: u32 = 0x2;
+pub const FEATURE_INCOMPAT_EXTENTS: u32 = 0x1;
+// blocks shared between files are reference counted, writing without knowing it frees them early
+pub const FEATURE_RO_COMPAT_SHARED_BLOCKS: u32 = 0x1;
+const SUPPORTED_FEATURES_INCOMPAT: u32 = FEATURE_INCOMPAT_EXTENTS;
+const SUPPORTED_FEATURES_RO_COMPAT: u32 = FEATURE_RO_COMPAT_SHARED_BLOCKS;
+pub const DEFAULT_MAX_MOUNT_COUNT: u32 = 20;
+pub const LABEL_SIZE: usize = 16;
+// default_mount_options bits, used when a mount doesn't ask for something else
+pub const DEFAULT_MOUNT_WRITEBACK: u32 = 0x1;
+// block sizes tried when looking for a backup without a readable primary to tell the real one
+const BACKUP_BLOCK_SIZES: [u32; 8] = [512, 1024, 2048, 4096, 8192, 16384, 32768, 65536];
+// groups searched for a backup with every block size when the primary gives no hint
+const BACKUP_PROBE_GROUPS: u64 = 4;
+
+// What happens when a filesystem error is found while mounted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ErrorBehavior {
+    // record it and carry on
+    #[default]
+    Continue,
+    // refuse every change from then on
+    RemountRo,
+    // stop serving the filesystem at all
+    Panic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsState {
+    // unmounted cleanly or checked since
+    Clean,
+    // mounted right now, or the last mount never got to unmount
+    Dirty,
+}
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+// On disk, SUPER_BLOCK_SIZE bytes in little-endian:
+//   0 magic u32, 4 format_version u32, 8 block_size u32, 12 state u32 (0 clean, 1 dirty),
+//  16 created_at, 28 modified_at, 40 last_mounted_at, 52 last_checked_at, 64 first_error_at,
+//  76 last_error_at (u64 seconds and u32 nanoseconds each), 88 mount_count u32,
+//  92 max_mount_count u32, 96 error_count u32, 100 uid u32, 104 gid u32,
+// 108 journal_blocks u32, 112 journal_start u64, 120 block_count u64, 128 inode_count u64,
+// 136 free_blocks u64, 144 free_inodes u64, 152 block_group_count u64,
+// 160 data_blocks_per_group u64, 168 shared_blocks_inode u64, 176 feature_compat u32,
+// 180 feature_incompat u32, 184 feature_ro_compat u32, 188 inodes_per_group u64,
+// 196 reserved_blocks u64, 204 uuid, 220 label (zero padded), 236 default_mount_options u32,
+// 240 errors u32 (0 continue, 1 remount read-only, 2 panic), 244 reserved, 508 CRC32 of the
+// bytes before it
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SuperBlock {
     pub magic: u32,
+    pub format_version: u32,
     pub block_size: u32,
     pub created_at: SystemTime,
     pub modified_at: SystemTime,
     pub last_mounted_at: SystemTime,
+    pub last_checked_at: SystemTime,
+    pub state: FsState,
+    pub mount_count: u32,
+    // mounts before a check is due, 0 never asks for one
+    pub max_mount_count: u32,
+    // filesystem errors seen since the last check
+    pub error_count: u32,
+    pub first_error_at: SystemTime,
+    pub last_error_at: SystemTime,
     pub block_count: u64,
     pub inode_count: u64,
     pub free_blocks: u64,
@@ -25,7 +93,22 @@ pub struct SuperBlock {
     pub data_blocks_per_group: u64,
     pub uid: uid_t,
     pub gid: gid_t,
-    pub checksum: u32,
+    // inode holding the reference counts of shared data blocks, 0 when nothing is shared
+    pub shared_blocks_inode: u64,
+    // data blocks reserved for the metadata journal, none when journal_blocks is 0
+    pub journal_start: u64,
+    pub journal_blocks: u32,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    // size of every group's inode table, and so of the groups themselves
+    pub inodes_per_group: u64,
+    // free blocks only root can allocate
+    pub reserved_blocks: u64,
+    pub uuid: [u8; 16],
+    pub label: String,
+    pub default_mount_options: u32,
+    pub errors: ErrorBehavior,
 }
 
 impl SuperBlock {
@@ -33,71 +116,367 @@ impl SuperBlock {
         block_size: u32,
         block_group_count: u64,
         data_blocks_per_group: u64,
+        inodes_per_group: u64,
         uid: uid_t,
         gid: gid_t,
     ) -> Self {
         let block_count = block_size as u64 * 8 * block_group_count;
+        let inode_count = inodes_per_group * block_group_count;
 
         Self {
             magic: MAGIC_NUMBER,
+            format_version: FORMAT_VERSION,
             block_size,
             created_at: SystemTime::now(),
             modified_at: UNIX_EPOCH,
             last_mounted_at: UNIX_EPOCH,
+            last_checked_at: UNIX_EPOCH,
+            state: FsState::Clean,
+            mount_count: 0,
+            max_mount_count: DEFAULT_MAX_MOUNT_COUNT,
+            error_count: 0,
+            first_error_at: UNIX_EPOCH,
+            last_error_at: UNIX_EPOCH,
             block_count,
-            inode_count: block_count,
+            inode_count,
             free_blocks: block_count,
-            free_inodes: block_count,
+            free_inodes: inode_count,
             block_group_count,
             data_blocks_per_group,
             uid,
             gid,
-            checksum: 0,
+            shared_blocks_inode: 0,
+            journal_start: 0,
+            journal_blocks: 0,
+            feature_compat: FEATURE_COMPAT_XATTR,
+            feature_incompat: FEATURE_INCOMPAT_EXTENTS,
+            feature_ro_compat: 0,
+            inodes_per_group,
+            reserved_blocks: 0,
+            uuid: [0; 16],
+            label: String::new(),
+            default_mount_options: 0,
+            errors: ErrorBehavior::Continue,
         }
     }
 
-    pub fn serialize_into<W>(&mut self, w: W) -> Result<()>
-    where
-        W: Write,
-    {
-        self.checksum();
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+    pub fn group_size(&self) -> u64 {
+        get_block_group_size(self.block_size, self.inodes_per_group)
+    }
+
+    pub fn inode_table_size(&self) -> u64 {
+        get_inode_table_size(self.block_size, self.inodes_per_group)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::new();
+        e.u32(self.magic);
+        e.u32(self.format_version);
+        e.u32(self.block_size);
+        e.u32(match self.state {
+            FsState::Clean => 0,
+            FsState::Dirty => 1,
+        });
+        e.time(self.created_at);
+        e.time(self.modified_at);
+        e.time(self.last_mounted_at);
+        e.time(self.last_checked_at);
+        e.time(self.first_error_at);
+        e.time(self.last_error_at);
+        e.u32(self.mount_count);
+        e.u32(self.max_mount_count);
+        e.u32(self.error_count);
+        e.u32(self.uid);
+        e.u32(self.gid);
+        e.u32(self.journal_blocks);
+        e.u64(self.journal_start);
+        e.u64(self.block_count);
+        e.u64(self.inode_count);
+        e.u64(self.free_blocks);
+        e.u64(self.free_inodes);
+        e.u64(self.block_group_count);
+        e.u64(self.data_blocks_per_group);
+        e.u64(self.shared_blocks_inode);
+        e.u32(self.feature_compat);
+        e.u32(self.feature_incompat);
+        e.u32(self.feature_ro_compat);
+        e.u64(self.inodes_per_group);
+        e.u64(self.reserved_blocks);
+        e.bytes(&self.uuid);
+        let mut label = [0; LABEL_SIZE];
+        label[..self.label.len()].copy_from_slice(self.label.as_bytes());
+        e.bytes(&label);
+        e.u32(self.default_mount_options);
+        e.u32(match self.errors {
+            ErrorBehavior::Continue => 0,
+            ErrorBehavior::RemountRo => 1,
+            ErrorBehavior::Panic => 2,
+        });
+        e.pad_to(SUPER_BLOCK_SIZE - 4);
+        e.checksum();
+        e.finish()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        Self::decode_checked(buf, true)
     }
 
-    pub fn deserialize_from<R>(r: R) -> Result<Self>
-    where
-        R: Read,
-    {
-        let mut sb: Self = bincode::deserialize_from(r)?;
+    fn decode_checked(buf: &[u8], verify: bool) -> Result<Self> {
+        let buf = buf
+            .get(..SUPER_BLOCK_SIZE)
+            .ok_or(anyhow!("Truncated superblock"))?;
+        let mut d = Decoder::new(buf);
+
+        if d.u32()? != MAGIC_NUMBER {
+            return Err(anyhow!("Not a mfsr superblock"));
+        }
+
+        if verify && !Decoder::verify_checksum(buf) {
+            return Err(anyhow!("Invalid superblock checksum"));
+        }
+
+        let format_version = d.u32()?;
+
+        if format_version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "Unsupported format version {}, expected {}",
+                format_version,
+                FORMAT_VERSION
+            ));
+        }
+
+        let sb = Self {
+            magic: MAGIC_NUMBER,
+            format_version,
+            block_size: d.u32()?,
+            state: match d.u32()? {
+                0 => FsState::Clean,
+                1 => FsState::Dirty,
+                state => return Err(anyhow!("Invalid filesystem state {}", state)),
+            },
+            created_at: d.time()?,
+            modified_at: d.time()?,
+            last_mounted_at: d.time()?,
+            last_checked_at: d.time()?,
+            first_error_at: d.time()?,
+            last_error_at: d.time()?,
+            mount_count: d.u32()?,
+            max_mount_count: d.u32()?,
+            error_count: d.u32()?,
+            uid: d.u32()?,
+            gid: d.u32()?,
+            journal_blocks: d.u32()?,
+            journal_start: d.u64()?,
+            block_count: d.u64()?,
+            inode_count: d.u64()?,
+            free_blocks: d.u64()?,
+            free_inodes: d.u64()?,
+            block_group_count: d.u64()?,
+            data_blocks_per_group: d.u64()?,
+            shared_blocks_inode: d.u64()?,
+            feature_compat: d.u32()?,
+            feature_incompat: d.u32()?,
+            feature_ro_compat: d.u32()?,
+            inodes_per_group: d.u64()?,
+            reserved_blocks: d.u64()?,
+            uuid: d.bytes(16)?.try_into()?,
+            label: {
+                let label = d.bytes(LABEL_SIZE)?;
+                let len = label.iter().position(|&b| b == 0).unwrap_or(LABEL_SIZE);
+                String::from_utf8(label[..len].to_vec())?
+            },
+            default_mount_options: d.u32()?,
+            errors: match d.u32()? {
+                0 => ErrorBehavior::Continue,
+                1 => ErrorBehavior::RemountRo,
+                2 => ErrorBehavior::Panic,
+                errors => return Err(anyhow!("Invalid error behavior {}", errors)),
+            },
+        };
 
-        if !sb.verify_checksum() {
-            Err(anyhow!("Invalid superblock checksum"))
-        } else {
-            Ok(sb)
+        // everything else about the layout follows from these
+        if !sb.block_size.is_power_of_two()
+            || sb.block_size < BACKUP_BLOCK_SIZES[0]
+            || sb.inodes_per_group == 0
+            || sb.inodes_per_group > max_inodes_per_group(sb.block_size)
+        {
+            return Err(anyhow!(
+                "Invalid layout, {} inodes per group of {} byte blocks",
+                sb.inodes_per_group,
+                sb.block_size
+            ));
         }
+
+        Ok(sb)
     }
 
-    pub fn checksum(&mut self) {
-        self.checksum = self.calculate_checksum();
+    // Reads the copy kept at the start of a block group. Where the group starts depends on the
+    // block size and the size of the inode table, which are only known once a copy has been
+    // read, so every usual block size is tried with every table size it allows
+    pub fn read_from_group(device: &dyn BlockDevice, group: u64) -> Result<Self> {
+        let mut buf = [0; SUPER_BLOCK_SIZE];
+
+        if group == 0 {
+            device.read_at(0, &mut buf)?;
+            return Self::decode(&buf);
+        }
+
+        for block_size in BACKUP_BLOCK_SIZES {
+            let inodes_per_block = block_size as u64 / INODE_SIZE as u64;
+            let max_table_blocks = max_inodes_per_group(block_size) / inodes_per_block;
+
+            for table_blocks in 1..=max_table_blocks {
+                let group_size = get_block_group_size(block_size, table_blocks * inodes_per_block);
+                let read = match group_size.checked_mul(group) {
+                    Some(offset) if offset < device.len() => device.read_at(offset, &mut buf),
+                    _ => break,
+                };
+
+                match read.and_then(|_| Self::decode(&buf)) {
+                    Ok(sb)
+                        if sb.block_size == block_size
+                            && sb.group_size() == group_size
+                            && group < sb.block_group_count =>
+                    {
+                        return Ok(sb)
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        Err(anyhow!("No valid superblock in block group {}", group))
     }
 
-    pub fn calculate_checksum(&mut self) -> u32 {
-        self.checksum = 0;
-        let mut hasher = Hasher::new();
-        hasher.update(&bincode::serialize(&self).unwrap());
-        hasher.finalize()
+    // Reads the primary copy, or the first valid backup when it's damaged. Also returns the group
+    // the copy came from
+    pub fn read_with_fallback(device: &dyn BlockDevice) -> Result<(Self, u64)> {
+        let primary_error = match Self::read_from_group(device, 0) {
+            Ok(sb) => return Ok((sb, 0)),
+            Err(e) => e,
+        };
+
+        // a primary that only fails its checksum still tells where the backups are
+        let mut buf = [0; SUPER_BLOCK_SIZE];
+        let damaged = device
+            .read_at(0, &mut buf)
+            .and_then(|_| Self::decode_checked(&buf, false));
+
+        if let Ok(damaged) = damaged {
+            let group_size = damaged.group_size();
+            let groups = damaged.block_group_count.min(device.len() / group_size);
+
+            for group in 1..groups {
+                device.read_at(group * group_size, &mut buf)?;
+
+                match Self::decode(&buf) {
+                    Ok(sb)
+                        if sb.block_size == damaged.block_size && sb.group_size() == group_size =>
+                    {
+                        return Ok((sb, group))
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        // otherwise every geometry is tried, but only on the first few groups. Scanning the whole
+        // device that way takes ages on a large image that isn't mfsr at all
+        for group in 1..=BACKUP_PROBE_GROUPS {
+            if let Ok(sb) = Self::read_from_group(device, group) {
+                return Ok((sb, group));
+            }
+        }
+
+        Err(anyhow!("{} and no valid backup was found", primary_error))
     }
 
-    pub fn verify_checksum(&mut self) -> bool {
-        let checksum = self.checksum;
-        self.checksum = 0;
-        let ok = checksum == self.calculate_checksum();
-        self.checksum = checksum;
+    pub fn unknown_features_incompat(&self) -> u32 {
+        self.feature_incompat & !SUPPORTED_FEATURES_INCOMPAT
+    }
 
-        ok
+    pub fn unknown_features_ro_compat(&self) -> u32 {
+        self.feature_ro_compat & !SUPPORTED_FEATURES_RO_COMPAT
     }
 
     pub fn update_last_mounted(&mut self) {
         self.last_mounted_at = SystemTime::now();
     }
+
+    pub fn needs_check(&self) -> bool {
+        self.max_mount_count != 0 && self.mount_count >= self.max_mount_count
+    }
+
+    pub fn record_error(&mut self) {
+        let now = SystemTime::now();
+
+        if self.error_count == 0 {
+            self.first_error_at = now;
+        }
+
+        self.error_count = self.error_count.saturating_add(1);
+        self.last_error_at = now;
+    }
+
+    // A successful fsck starts the counters over
+    pub fn record_check(&mut self) {
+        self.state = FsState::Clean;
+        self.mount_count = 0;
+        self.error_count = 0;
+        self.first_error_at = UNIX_EPOCH;
+        self.last_error_at = UNIX_EPOCH;
+        self.last_checked_at = SystemTime::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::{format, FormatOptions},
+        device::MemoryDevice,
+    };
+
+    fn image(groups: u64) -> MemoryDevice {
+        let options = FormatOptions {
+            block_size: 1024,
+            bytes_per_inode: 1024,
+            ..Default::default()
+        };
+        let size = get_block_group_size(1024, 8192) * groups;
+        let mut device = MemoryDevice::new(size).unwrap();
+        format(&mut device, size, &options).unwrap();
+
+        device
+    }
+
+    #[test]
+    fn fallback_uses_the_damaged_primary_geometry() {
+        let mut device = image(3);
+        // the mount count, only the checksum notices
+        device.write_at(88, &[0xff]).unwrap();
+        assert!(SuperBlock::read_from_group(&device, 0).is_err());
+
+        let (sb, group) = SuperBlock::read_with_fallback(&device).unwrap();
+        assert_eq!(group, 1);
+        assert_eq!(sb.block_group_count, 3);
+    }
+
+    #[test]
+    fn fallback_probes_without_a_primary() {
+        let mut device = image(3);
+        device.write_at(0, &[0; SUPER_BLOCK_SIZE]).unwrap();
+
+        let (sb, group) = SuperBlock::read_with_fallback(&device).unwrap();
+        assert_eq!(group, 1);
+        assert_eq!(sb.block_size, 1024);
+    }
+
+    #[test]
+    fn fallback_gives_up_on_garbage() {
+        let device = MemoryDevice::new(256 << 20).unwrap();
+        let error = SuperBlock::read_with_fallback(&device).unwrap_err();
+
+        assert!(error.to_string().contains("no valid backup"), "{}", error);
+    }
 }
@@ -1,11 +1,8 @@
-use std::{
-    mem::size_of,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use fuser::TimeOrNow;
 
-use crate::types::inode::Inode;
+use crate::types::inode::INODE_SIZE;
 
 #[inline(always)]
 pub fn timestamp_to_system_time(timestamp: u64) -> SystemTime {
@@ -34,17 +31,30 @@ pub fn time_or_now_to_timestamp(time_or_now: TimeOrNow) -> u64 {
 }
 
 #[inline(always)]
-pub fn get_block_group_size(block_size: u32) -> u64 {
+pub fn get_block_group_size(block_size: u32, inodes_per_group: u64) -> u64 {
     block_size as u64 // super block
     + block_size as u64 // data bitmap
     + block_size as u64 // inode bitmap
-    + get_inode_table_size(block_size)
+    + get_inode_table_size(block_size, inodes_per_group)
     + get_data_block_size(block_size)
 }
 
+// every byte of the image has to be reachable with an off_t
 #[inline(always)]
-pub fn get_inode_table_size(block_size: u32) -> u64 {
-    block_size as u64 * 8 * size_of::<Inode>() as u64
+pub fn max_block_group_count(block_size: u32, inodes_per_group: u64) -> u64 {
+    i64::MAX as u64 / get_block_group_size(block_size, inodes_per_group)
+}
+
+// the table takes whole blocks
+#[inline(always)]
+pub fn get_inode_table_size(block_size: u32, inodes_per_group: u64) -> u64 {
+    (inodes_per_group * INODE_SIZE as u64).next_multiple_of(block_size as u64)
+}
+
+// as many as the inode bitmap has bits
+#[inline(always)]
+pub fn max_inodes_per_group(block_size: u32) -> u64 {
+    block_size as u64 * 8
 }
 
 #[inline(always)]
@@ -94,3 +104,17 @@ pub fn u64_to_bytes(value: u64) -> [u8; 8] {
 
     result
 }
+
+// 8-4-4-4-12 hex digits
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}